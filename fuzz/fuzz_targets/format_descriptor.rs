@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qtstream::coremedia::format_desc::FormatDescriptor;
+use qtstream::qt_pkt::QTPacket;
+
+// fdsc content, the boxes following the fdsc header
+fuzz_target!(|data: &[u8]| {
+    let mut pkt = QTPacket::new();
+    let _ = pkt.write(data);
+    pkt.borrow_mut().set_position(4);
+
+    if let Ok(fd) = FormatDescriptor::from_qt_packet(&mut pkt) {
+        let _ = fd.as_qt_packet();
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qtstream::qt_pkt::QTPacket;
+
+// whole packets as they come out of the pool, sub packets included
+fuzz_target!(|data: &[u8]| {
+    let mut pkt = match QTPacket::from_bytes(data) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    while let Ok((_, _)) = pkt.read_qt_packet_with_magic() {}
+});
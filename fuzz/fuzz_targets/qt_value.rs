@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qtstream::qt_pkt::QTPacket;
+use qtstream::qt_value::QTValue;
+
+fuzz_target!(|data: &[u8]| {
+    let mut pkt = QTPacket::new();
+    let _ = pkt.write(data);
+    // skip the length placeholder QTPacket::new starts with
+    pkt.borrow_mut().set_position(4);
+
+    if let Ok(value) = QTValue::from_qt_packet(&mut pkt) {
+        // serializing back must not panic either
+        let _ = value.as_qt_packet();
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qtstream::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use qtstream::qt_pkt::QTPacket;
+
+// FEED payload after the asyn header, starting at the sbuf box
+fuzz_target!(|data: &[u8]| {
+    let mut pkt = QTPacket::new();
+    let _ = pkt.write(data);
+    pkt.borrow_mut().set_position(4);
+
+    if let Ok(sample_buffer) = SampleBuffer::from_qt_packet(&mut pkt, MEDIA_TYPE_VIDEO) {
+        let _ = sample_buffer.validate_num_samples();
+    }
+});
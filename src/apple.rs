@@ -1,9 +1,121 @@
+use crate::retry::RetryPolicy;
 use rusb::{
     Context, Device, DeviceDescriptor, DeviceHandle, Direction, Error, Recipient, RequestType,
     TransferType, UsbContext,
 };
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+pub const APPLE_VENDOR_ID: u16 = 0x05AC;
+
+// a busy bus sporadically fails single transfers with Interrupted or Busy,
+// those are retried a few times. NoDevice, Pipe and the rest are real
+pub const DEFAULT_TRANSFER_RETRY: RetryPolicy = RetryPolicy::fixed(4, Duration::from_millis(5));
+
+// re-enumeration after the enable request usually takes a few hundred ms,
+// polled every 50ms for up to 10s
+pub const DEFAULT_ENABLE_RETRY: RetryPolicy = RetryPolicy::fixed(200, Duration::from_millis(50));
+
+// a blocked read is what the loop waits in, a short timeout lets it see term
+// (ctrl-c) within a second. an idle device just yields Timeout, the caller
+// treats that as no data
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn is_transient(e: Error) -> bool {
+    matches!(e, Error::Interrupted | Error::Busy)
+}
+
+// runs transfer again while it fails with a transient error and the policy
+// has attempts left, what names the transfer in the log
+fn retry_transient<F: FnMut() -> Result<usize, Error>>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut transfer: F,
+) -> Result<usize, Error> {
+    let mut attempt = 0;
+
+    loop {
+        match transfer() {
+            Ok(e) => return Ok(e),
+            Err(e) if is_transient(e) && policy.should_retry(attempt + 1) => {
+                attempt += 1;
+                log::warn!(
+                    "{} {}, retry {}/{}",
+                    what,
+                    e,
+                    attempt,
+                    policy.max_attempts - 1
+                );
+                sleep(policy.delay(attempt));
+            }
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+// raw bulk transfer counters, below any packet parsing
+#[derive(Clone, Copy, Debug)]
+pub struct TransferStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub reads: u64,
+    pub writes: u64,
+    // time since the device was opened
+    pub elapsed: Duration,
+}
+
+impl TransferStats {
+    // average read rate in bytes per second
+    pub fn read_rate(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            s if s > 0f64 => self.bytes_read as f64 / s,
+            _ => 0f64,
+        }
+    }
+
+    pub fn write_rate(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            s if s > 0f64 => self.bytes_written as f64 / s,
+            _ => 0f64,
+        }
+    }
+}
+
+// what set_qt_enabled did. only Enabled went through the re-enumeration
+// wait, Disabled just sent the control request
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QTModeChange {
+    // enable asked, the QuickTime interface was already there
+    AlreadyEnabled,
+    Enabled,
+    Disabled,
+    // disable asked, the device wasn't in QuickTime mode
+    NoChange,
+}
+
+impl QTModeChange {
+    // whether the device was actually switched
+    pub fn toggled(&self) -> bool {
+        match self {
+            QTModeChange::Enabled | QTModeChange::Disabled => true,
+            _ => false,
+        }
+    }
+}
+
+// one interface with the QuickTime class (0xFF) and subclass (0x2A), config
+// is the configuration value, not its index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QTInterface {
+    pub config: u8,
+    pub interface: u8,
+    pub setting: u8,
+}
+
+fn is_qt_interface(class_code: u8, sub_class_code: u8) -> bool {
+    class_code == 0xFF && sub_class_code == 0x2A
+}
 
 pub struct AppleDevice {
     device: Device<Context>,
@@ -11,11 +123,23 @@ pub struct AppleDevice {
     index_config: u8,
     index_interface: u8,
     index_setting: u8,
+    // which of qt_interfaces() claim_interface takes
+    interface_choice: usize,
     in_max_packet_size: u16,
     out_max_packet_size: u16,
     in_endpoint_address: u8,
     out_endpoint_address: u8,
+    in_endpoint_override: Option<u8>,
+    out_endpoint_override: Option<u8>,
+    read_timeout: Duration,
+    transfer_retry: RetryPolicy,
+    enable_retry: RetryPolicy,
     handle: DeviceHandle<Context>,
+    opened: Instant,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
 }
 
 impl AppleDevice {
@@ -30,14 +154,35 @@ impl AppleDevice {
             index_config: 0,
             index_interface: 0,
             index_setting: 0,
+            interface_choice: 0,
             in_max_packet_size: 0,
             out_max_packet_size: 0,
             in_endpoint_address: 0,
             out_endpoint_address: 0,
+            in_endpoint_override: None,
+            out_endpoint_override: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            transfer_retry: DEFAULT_TRANSFER_RETRY,
+            enable_retry: DEFAULT_ENABLE_RETRY,
             handle,
+            opened: Instant::now(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
         };
     }
 
+    pub fn transfer_stats(&self) -> TransferStats {
+        TransferStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            elapsed: self.opened.elapsed(),
+        }
+    }
+
     pub fn is_qt_enabled(&self) -> Result<bool, Error> {
         let num_configuration = self.descriptor.num_configurations();
         for config_idx in 0..num_configuration {
@@ -48,8 +193,7 @@ impl AppleDevice {
 
             for interface in desc.interfaces() {
                 for interface_desc in interface.descriptors() {
-                    if interface_desc.class_code() == 0xFF
-                        && interface_desc.sub_class_code() == 0x2A
+                    if is_qt_interface(interface_desc.class_code(), interface_desc.sub_class_code())
                     {
                         return Ok(true);
                     }
@@ -59,44 +203,88 @@ impl AppleDevice {
         Ok(false)
     }
 
-    pub fn claim_interface(&mut self) -> Option<Error> {
+    // every QuickTime interface over all configurations and alternate
+    // settings, in descriptor order
+    pub fn qt_interfaces(&self) -> Result<Vec<QTInterface>, Error> {
+        let mut interfaces: Vec<QTInterface> = Vec::new();
+
         let num_configuration = self.descriptor.num_configurations();
         for config_idx in 0..num_configuration {
             let desc = match self.device.config_descriptor(config_idx) {
                 Ok(e) => e,
-                Err(e) => return Some(e),
+                Err(e) => return Err(e),
             };
 
             for interface in desc.interfaces() {
                 for interface_desc in interface.descriptors() {
-                    if interface_desc.class_code() == 0xFF
-                        && interface_desc.sub_class_code() == 0x2A
+                    if is_qt_interface(interface_desc.class_code(), interface_desc.sub_class_code())
                     {
-                        self.index_config = desc.number();
-                        self.index_interface = interface_desc.interface_number();
-                        self.index_setting = interface_desc.setting_number();
-
-                        if match self.handle.active_configuration() {
-                            Err(e) => return Some(e),
-                            Ok(cfg) => cfg,
-                        } != self.index_config
-                        {
-                            match self.handle.set_active_configuration(self.index_config) {
-                                Err(e) => return Some(e),
-                                _ => {}
-                            };
-                        }
-
-                        match self.handle.claim_interface(self.index_interface) {
-                            Err(e) => return Some(e),
-                            _ => {}
-                        };
-                        return None;
+                        interfaces.push(QTInterface {
+                            config: desc.number(),
+                            interface: interface_desc.interface_number(),
+                            setting: interface_desc.setting_number(),
+                        });
                     }
                 }
             }
         }
-        Some(Error::NotFound)
+
+        Ok(interfaces)
+    }
+
+    // claim the n-th entry of qt_interfaces() instead of the first, for
+    // devices exposing the capture interface in more than one configuration
+    pub fn set_interface_choice(&mut self, choice: usize) {
+        self.interface_choice = choice;
+    }
+
+    pub fn claim_interface(&mut self) -> Option<Error> {
+        let interfaces = match self.qt_interfaces() {
+            Ok(e) => e,
+            Err(e) => return Some(e),
+        };
+
+        for (i, qt_interface) in interfaces.iter().enumerate() {
+            log::debug!("quicktime interface {}: {:?}", i, qt_interface);
+        }
+
+        let qt_interface = match interfaces.get(self.interface_choice) {
+            Some(e) => *e,
+            None => return Some(Error::NotFound),
+        };
+
+        self.index_config = qt_interface.config;
+        self.index_interface = qt_interface.interface;
+        self.index_setting = qt_interface.setting;
+
+        if match self.handle.active_configuration() {
+            Err(e) => return Some(e),
+            Ok(cfg) => cfg,
+        } != self.index_config
+        {
+            match self.handle.set_active_configuration(self.index_config) {
+                Err(e) => return Some(e),
+                _ => {}
+            };
+        }
+
+        match self.handle.claim_interface(self.index_interface) {
+            Err(e) => return Some(e),
+            _ => {}
+        };
+
+        // setting 0 is active after the claim already
+        if self.index_setting != 0 {
+            match self
+                .handle
+                .set_alternate_setting(self.index_interface, self.index_setting)
+            {
+                Err(e) => return Some(e),
+                _ => {}
+            };
+        }
+
+        None
     }
 
     pub fn init_bulk_endpoint(&mut self) -> Option<Error> {
@@ -107,25 +295,66 @@ impl AppleDevice {
                 Err(e) => return Some(e),
             };
 
+            // the interface claim_interface picked
+            if desc.number() != self.index_config {
+                continue;
+            }
+
             for interface in desc.interfaces() {
                 for interface_desc in interface.descriptors() {
-                    if interface_desc.class_code() == 0xFF
-                        && interface_desc.sub_class_code() == 0x2A
+                    if is_qt_interface(interface_desc.class_code(), interface_desc.sub_class_code())
+                        && interface_desc.interface_number() == self.index_interface
+                        && interface_desc.setting_number() == self.index_setting
                     {
+                        let mut found_in = false;
+                        let mut found_out = false;
+
+                        // without an override the last bulk endpoint of each
+                        // direction wins
                         for endpoint_desc in interface_desc.endpoint_descriptors() {
+                            log::debug!(
+                                "endpoint {:#04x} {:?} {:?} max packet {}",
+                                endpoint_desc.address(),
+                                endpoint_desc.direction(),
+                                endpoint_desc.transfer_type(),
+                                endpoint_desc.max_packet_size()
+                            );
+
+                            if endpoint_desc.transfer_type() != TransferType::Bulk {
+                                continue;
+                            }
+
                             if endpoint_desc.direction() == Direction::In
-                                && endpoint_desc.transfer_type() == TransferType::Bulk
+                                && self
+                                    .in_endpoint_override
+                                    .is_none_or(|a| a == endpoint_desc.address())
                             {
                                 self.in_max_packet_size = endpoint_desc.max_packet_size();
                                 self.in_endpoint_address = endpoint_desc.address();
+                                found_in = true;
                             } else if endpoint_desc.direction() == Direction::Out
-                                && endpoint_desc.transfer_type() == TransferType::Bulk
+                                && self
+                                    .out_endpoint_override
+                                    .is_none_or(|a| a == endpoint_desc.address())
                             {
                                 self.out_max_packet_size = endpoint_desc.max_packet_size();
                                 self.out_endpoint_address = endpoint_desc.address();
+                                found_out = true;
                             }
                         }
 
+                        // a direction without bulk endpoint, or an override
+                        // naming none of them
+                        if !found_in || !found_out {
+                            return Some(Error::InvalidParam);
+                        }
+
+                        log::debug!(
+                            "bulk endpoints in {:#04x} out {:#04x}",
+                            self.in_endpoint_address,
+                            self.out_endpoint_address
+                        );
+
                         return None;
                     }
                 }
@@ -134,14 +363,17 @@ impl AppleDevice {
         Some(Error::NotFound)
     }
 
-    pub fn set_qt_enabled(&mut self, enabled: bool) -> Result<bool, Error> {
+    pub fn set_qt_enabled(&mut self, enabled: bool) -> Result<QTModeChange, Error> {
         let is_enabled = match self.is_qt_enabled() {
             Ok(is_enabled) => is_enabled == enabled,
             Err(e) => return Err(e),
         };
 
         if is_enabled {
-            return Ok(true);
+            return Ok(match enabled {
+                true => QTModeChange::AlreadyEnabled,
+                false => QTModeChange::NoChange,
+            });
         }
 
         let index = match enabled {
@@ -164,41 +396,73 @@ impl AppleDevice {
         };
 
         if enabled {
-            sleep(Duration::from_secs(1));
+            // the device drops off the bus and comes back with the extra
+            // interface, poll for it instead of waiting a fixed time. the
+            // old handle may still open while it is going away
+            return match self.reopen_when(|d| match d.is_qt_enabled() {
+                Ok(enabled) => enabled,
+                // config descriptors can't be read mid re-enumeration
+                Err(_) => false,
+            }) {
+                Ok(_) => Ok(QTModeChange::Enabled),
+                Err(e) => Err(e),
+            };
+        }
 
-            let context = match Context::new() {
-                Ok(ctx) => ctx,
-                Err(e) => return Err(e),
+        Ok(QTModeChange::Disabled)
+    }
+
+    // poll with enable_retry until the device is back on the bus under the
+    // same vid/pid and ready says so, the handle is replaced each time
+    fn reopen_when<F: Fn(&AppleDevice) -> bool>(&mut self, ready: F) -> Result<(), Error> {
+        let context = match Context::new() {
+            Ok(ctx) => ctx,
+            Err(e) => return Err(e),
+        };
+
+        let mut attempt = 0;
+
+        loop {
+            if !self.enable_retry.should_retry(attempt) {
+                return Err(Error::Timeout);
+            }
+
+            attempt += 1;
+            sleep(self.enable_retry.delay(attempt));
+
+            let handle = match context
+                .open_device_with_vid_pid(self.descriptor.vendor_id(), self.descriptor.product_id())
+            {
+                Some(e) => e,
+                None => continue,
             };
 
-            loop {
-                self.handle = match context.open_device_with_vid_pid(
-                    self.descriptor.vendor_id(),
-                    self.descriptor.product_id(),
-                ) {
-                    Some(e) => e,
-                    None => return Err(Error::NotFound),
-                };
-
-                self.device = self.handle.device();
-                self.descriptor = match self.device.device_descriptor() {
-                    Ok(d) => d,
-                    Err(e) => return Err(e),
-                };
-
-                if match self.is_qt_enabled() {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                } == enabled
-                {
-                    break;
-                }
+            let device = handle.device();
+            let descriptor = match device.device_descriptor() {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            self.handle = handle;
+            self.device = device;
+            self.descriptor = descriptor;
 
-                sleep(Duration::from_millis(500));
+            if ready(self) {
+                return Ok(());
             }
         }
+    }
 
-        return Ok(true);
+    // usb port reset, clears a device left wedged by a crashed capture
+    // without a replug. when the device re-enumerates the handle is reopened
+    // the way set_qt_enabled does it. claimed interfaces are gone afterwards
+    pub fn reset(&mut self) -> Result<(), Error> {
+        match self.handle.reset() {
+            Ok(_) => Ok(()),
+            // libusb: the device has to re-enumerate, the handle is stale
+            Err(Error::NotFound) => self.reopen_when(|d| d.is_qt_enabled().is_ok()),
+            Err(e) => Err(e),
+        }
     }
 
     pub fn clear_feature(&self) -> Option<Error> {
@@ -231,6 +495,38 @@ impl AppleDevice {
         None
     }
 
+    // pick bulk endpoints by address instead of the last one found, applied
+    // by the next init_bulk_endpoint. None keeps the default per direction
+    pub fn set_endpoint_override(&mut self, in_address: Option<u8>, out_address: Option<u8>) {
+        self.in_endpoint_override = in_address;
+        self.out_endpoint_override = out_address;
+    }
+
+    // how long read_bulk waits for data before returning Timeout
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    // retries of a bulk transfer failing with Interrupted or Busy
+    pub fn set_transfer_retry(&mut self, policy: RetryPolicy) {
+        self.transfer_retry = policy;
+    }
+
+    // polls for the device coming back after the QuickTime enable request,
+    // Timeout once they're used up
+    pub fn set_enable_retry(&mut self, policy: RetryPolicy) {
+        self.enable_retry = policy;
+    }
+
+    // endpoints chosen by init_bulk_endpoint, 0 before it ran
+    pub fn in_endpoint_address(&self) -> u8 {
+        self.in_endpoint_address
+    }
+
+    pub fn out_endpoint_address(&self) -> u8 {
+        self.out_endpoint_address
+    }
+
     pub fn max_read_packet_size(&self) -> u16 {
         self.in_max_packet_size
     }
@@ -240,15 +536,123 @@ impl AppleDevice {
     }
 
     pub fn read_bulk(&self, buf: &mut [u8]) -> Result<usize, Error> {
-        return self
-            .handle
-            .read_bulk(self.in_endpoint_address, buf, Duration::from_secs(10));
+        let n = match retry_transient(&self.transfer_retry, "read bulk", || {
+            self.handle
+                .read_bulk(self.in_endpoint_address, buf, self.read_timeout)
+        }) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+
+        Ok(n)
     }
 
     pub fn write_bulk(&self, buf: &[u8]) -> Result<usize, Error> {
-        return self
-            .handle
-            .write_bulk(self.out_endpoint_address, buf, Duration::from_secs(10));
+        let n = match retry_transient(&self.transfer_retry, "write bulk", || {
+            self.handle
+                .write_bulk(self.out_endpoint_address, buf, Duration::from_secs(10))
+        }) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+
+        Ok(n)
+    }
+}
+
+// the usb side of a QuickTime session. AppleDevice on real hardware, the
+// session code only talks to the device through this
+pub trait UsbTransport: Send {
+    fn transfer_stats(&self) -> TransferStats;
+    fn is_qt_enabled(&self) -> Result<bool, Error>;
+    fn set_qt_enabled(&mut self, enabled: bool) -> Result<QTModeChange, Error>;
+    fn set_interface_choice(&mut self, choice: usize);
+    fn claim_interface(&mut self) -> Option<Error>;
+    fn init_bulk_endpoint(&mut self) -> Option<Error>;
+    fn clear_feature(&self) -> Option<Error>;
+    fn reset(&mut self) -> Result<(), Error>;
+    fn set_read_timeout(&mut self, timeout: Duration);
+    fn set_transfer_retry(&mut self, policy: RetryPolicy);
+    fn set_enable_retry(&mut self, policy: RetryPolicy);
+    fn max_read_packet_size(&self) -> u16;
+    fn read_bulk(&self, buf: &mut [u8]) -> Result<usize, Error>;
+    fn write_bulk(&self, buf: &[u8]) -> Result<usize, Error>;
+}
+
+impl UsbTransport for AppleDevice {
+    fn transfer_stats(&self) -> TransferStats {
+        AppleDevice::transfer_stats(self)
+    }
+
+    fn is_qt_enabled(&self) -> Result<bool, Error> {
+        AppleDevice::is_qt_enabled(self)
+    }
+
+    fn set_qt_enabled(&mut self, enabled: bool) -> Result<QTModeChange, Error> {
+        AppleDevice::set_qt_enabled(self, enabled)
+    }
+
+    fn set_interface_choice(&mut self, choice: usize) {
+        AppleDevice::set_interface_choice(self, choice)
+    }
+
+    fn claim_interface(&mut self) -> Option<Error> {
+        AppleDevice::claim_interface(self)
+    }
+
+    fn init_bulk_endpoint(&mut self) -> Option<Error> {
+        AppleDevice::init_bulk_endpoint(self)
+    }
+
+    fn clear_feature(&self) -> Option<Error> {
+        AppleDevice::clear_feature(self)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        AppleDevice::reset(self)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) {
+        AppleDevice::set_read_timeout(self, timeout)
+    }
+
+    fn set_transfer_retry(&mut self, policy: RetryPolicy) {
+        AppleDevice::set_transfer_retry(self, policy)
+    }
+
+    fn set_enable_retry(&mut self, policy: RetryPolicy) {
+        AppleDevice::set_enable_retry(self, policy)
+    }
+
+    fn max_read_packet_size(&self) -> u16 {
+        AppleDevice::max_read_packet_size(self)
+    }
+
+    fn read_bulk(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        AppleDevice::read_bulk(self, buf)
+    }
+
+    fn write_bulk(&self, buf: &[u8]) -> Result<usize, Error> {
+        AppleDevice::write_bulk(self, buf)
+    }
+}
+
+// what to do about an error from get_usb_device/open_by_vid_pid, the usual
+// first run failure on linux is missing permissions on the usb device node
+pub fn open_error_hint(e: Error) -> Option<&'static str> {
+    match e {
+        Error::Access => Some(
+            "permission denied opening the usb device, install a udev rule like \
+             SUBSYSTEM==\"usb\", ATTR{idVendor}==\"05ac\", MODE=\"0666\" \
+             (then replug the device) or run as root",
+        ),
+        _ => None,
     }
 }
 
@@ -266,12 +670,18 @@ pub fn get_usb_device(sn: &str) -> Result<AppleDevice, Error> {
     let duration = Duration::from_secs(1);
 
     for device in devices.iter() {
-        let handle = match device.open() {
+        let descriptor = match device.device_descriptor() {
             Ok(d) => d,
             Err(e) => return Err(e),
         };
 
-        let descriptor = match device.device_descriptor() {
+        // other devices on the bus are often not openable without root,
+        // they must not fail the lookup
+        if descriptor.vendor_id() != APPLE_VENDOR_ID {
+            continue;
+        }
+
+        let handle = match device.open() {
             Ok(d) => d,
             Err(e) => return Err(e),
         };
@@ -296,3 +706,148 @@ pub fn get_usb_device(sn: &str) -> Result<AppleDevice, Error> {
 
     Err(Error::NotFound)
 }
+
+// open the first device matching vid/pid without reading its serial, for
+// rigs with a single device. get_usb_device is the way to pick among several
+pub fn open_by_vid_pid(vid: u16, pid: u16) -> Result<AppleDevice, Error> {
+    let usb_context = match Context::new() {
+        Ok(usb_context) => usb_context,
+        Err(e) => return Err(e),
+    };
+
+    // open_device_with_vid_pid hides why opening failed, go through the
+    // device list to keep the error
+    let devices = match usb_context.devices() {
+        Ok(d) => d,
+        Err(e) => return Err(e),
+    };
+
+    let device = match devices.iter().find(|d| match d.device_descriptor() {
+        Ok(desc) => desc.vendor_id() == vid && desc.product_id() == pid,
+        Err(_) => false,
+    }) {
+        Some(d) => d,
+        None => return Err(Error::NotFound),
+    };
+
+    let handle = match device.open() {
+        Ok(d) => d,
+        Err(e) => return Err(e),
+    };
+
+    let device = handle.device();
+
+    let descriptor = match device.device_descriptor() {
+        Ok(d) => d,
+        Err(e) => return Err(e),
+    };
+
+    Ok(AppleDevice::new(device, descriptor, handle))
+}
+
+// flip the device back out of QuickTime mode without a QuickTime session,
+// opens its own handle so it also works when the session is gone or stuck
+pub fn disable_quicktime(sn: &str) -> Result<QTModeChange, Error> {
+    let mut device = match get_usb_device(sn) {
+        Ok(d) => d,
+        Err(e) => return Err(e),
+    };
+
+    device.set_qt_enabled(false)
+}
+
+// disables QuickTime mode when dropped while unwinding from a panic, so the
+// device isn't left needing a replug. on a normal exit QuickTime's own Drop
+// has done that already and the device is re-enumerating, reopening it then
+// would only fail
+pub struct QuickTimeGuard {
+    sn: String,
+}
+
+impl QuickTimeGuard {
+    pub fn new(sn: &str) -> QuickTimeGuard {
+        QuickTimeGuard {
+            sn: String::from(sn),
+        }
+    }
+}
+
+impl Drop for QuickTimeGuard {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+
+        match disable_quicktime(&self.sn) {
+            Err(e) => log::error!("disable quicktime failed {}", e),
+            _ => {}
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fails with the given errors in order, then reports n bytes
+    fn transfer(errors: &[Error], n: usize) -> impl FnMut() -> Result<usize, Error> + '_ {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            match errors.get(calls - 1) {
+                Some(e) => Err(*e),
+                None => Ok(n),
+            }
+        }
+    }
+
+    #[test]
+    fn only_interrupted_and_busy_are_transient() {
+        assert!(is_transient(Error::Interrupted));
+        assert!(is_transient(Error::Busy));
+        assert!(!is_transient(Error::NoDevice));
+        assert!(!is_transient(Error::Pipe));
+        assert!(!is_transient(Error::Timeout));
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let policy = RetryPolicy::fixed(3, Duration::ZERO);
+
+        let errors = [Error::Busy, Error::Interrupted];
+        assert_eq!(
+            retry_transient(&policy, "test", transfer(&errors, 8)),
+            Ok(8)
+        );
+
+        // out of attempts, the last error comes through
+        let errors = [Error::Busy, Error::Busy, Error::Interrupted];
+        assert_eq!(
+            retry_transient(&policy, "test", transfer(&errors, 8)),
+            Err(Error::Interrupted)
+        );
+
+        let errors = [Error::Busy];
+        assert_eq!(
+            retry_transient(&RetryPolicy::no_retry(), "test", transfer(&errors, 8)),
+            Err(Error::Busy)
+        );
+    }
+
+    #[test]
+    fn real_errors_are_not_retried() {
+        let policy = RetryPolicy::fixed(3, Duration::ZERO);
+
+        let errors = [Error::NoDevice, Error::Busy];
+        assert_eq!(
+            retry_transient(&policy, "test", transfer(&errors, 8)),
+            Err(Error::NoDevice)
+        );
+
+        let errors = [Error::Busy, Error::Pipe];
+        assert_eq!(
+            retry_transient(&policy, "test", transfer(&errors, 8)),
+            Err(Error::Pipe)
+        );
+    }
+}
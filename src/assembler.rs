@@ -0,0 +1,333 @@
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use crate::coremedia::time::{PtsNormalizer, Time};
+use crate::h264;
+use crate::sink::SampleSink;
+use std::io::Error;
+use std::sync::mpsc::Receiver;
+
+// annex-b data, pts and whether it's a keyframe
+pub type Frame = (Vec<u8>, Time, bool);
+
+// turns the raw sample channel into decoder ready video frames: annex-b data
+// with sps/pps in front whenever they change, the pts and the keyframe flag
+pub struct Assembler {
+    rx: Receiver<Result<SampleBuffer, Error>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    strip_sei: bool,
+    keyframes_only: bool,
+    audio_sink: Option<Box<dyn SampleSink>>,
+    reorder_window: usize,
+    // assembled frames waiting for reorder, keyed on dts (pts if no dts)
+    pending: Vec<(f64, Frame)>,
+    // sentinel seen, only pending frames are left
+    ended: bool,
+    normalizer: Option<PtsNormalizer>,
+    drop_stale: bool,
+    // last timestamp let through per track, video and audio
+    last_video: Option<f64>,
+    last_audio: Option<f64>,
+    stale_samples: u64,
+}
+
+// frames held back by --reorder, a few frames cover any b-frame style
+// reordering
+pub const DEFAULT_REORDER_WINDOW: usize = 4;
+
+impl Assembler {
+    pub fn new(rx: Receiver<Result<SampleBuffer, Error>>) -> Assembler {
+        Assembler {
+            rx,
+            sps: None,
+            pps: None,
+            strip_sei: false,
+            keyframes_only: false,
+            audio_sink: None,
+            reorder_window: 0,
+            pending: Vec::new(),
+            ended: false,
+            normalizer: None,
+            drop_stale: false,
+            last_video: None,
+            last_audio: None,
+            stale_samples: 0,
+        }
+    }
+
+    // drop sei units (picture timing, orientation, ...) some decoders choke on
+    pub fn set_strip_sei(&mut self, strip_sei: bool) {
+        self.strip_sei = strip_sei;
+    }
+
+    // emit only sync samples, each with sps/pps in front so every frame
+    // decodes on its own. delta frames are dropped before any parsing
+    pub fn set_keyframes_only(&mut self, keyframes_only: bool) {
+        self.keyframes_only = keyframes_only;
+    }
+
+    // hold back up to window frames and emit them in decode order, 0 passes
+    // frames through as they arrive. a frame later than everything already
+    // emitted can't be fixed and still goes out late
+    pub fn set_reorder_window(&mut self, window: usize) {
+        self.reorder_window = window;
+    }
+
+    // frame pts relative to the first sample, audio or video, instead of the
+    // device's absolute clock
+    pub fn set_zero_based_pts(&mut self, zero_based_pts: bool) {
+        self.normalizer = match zero_based_pts {
+            true => Some(PtsNormalizer::new()),
+            false => None,
+        };
+    }
+
+    // drop samples whose timestamp isn't past the previous one of the same
+    // track, resent frames and clocks jumping back after a reconnect. video
+    // is checked on dts when the device sends one so reordered pts pass
+    pub fn set_drop_stale(&mut self, drop_stale: bool) {
+        self.drop_stale = drop_stale;
+    }
+
+    // samples dropped by set_drop_stale so far
+    pub fn stale_samples(&self) -> u64 {
+        self.stale_samples
+    }
+
+    fn is_stale(&mut self, sample_buffer: &SampleBuffer) -> bool {
+        let time = match sample_buffer.decode_time_stamp() {
+            Some(dts) => dts,
+            None => match sample_buffer.presentation_time_stamp() {
+                Some(pts) => pts,
+                None => return false,
+            },
+        }
+        .as_secs_f64();
+
+        let last = match sample_buffer.media_type() {
+            MEDIA_TYPE_VIDEO => &mut self.last_video,
+            _ => &mut self.last_audio,
+        };
+
+        match last {
+            Some(l) if time <= *l => true,
+            _ => {
+                *last = Some(time);
+                false
+            }
+        }
+    }
+
+    fn pop_earliest(&mut self) -> Option<Frame> {
+        let mut earliest: Option<usize> = None;
+
+        for (i, (key, _)) in self.pending.iter().enumerate() {
+            match earliest {
+                Some(e) if self.pending[e].0 <= *key => {}
+                _ => earliest = Some(i),
+            }
+        }
+
+        Some(self.pending.remove(earliest?).1)
+    }
+
+    // non video samples go here instead of being dropped, finalized once the
+    // stream ends
+    pub fn set_audio_sink(&mut self, audio_sink: Option<Box<dyn SampleSink>>) {
+        self.audio_sink = audio_sink;
+    }
+
+    fn finish_audio(&mut self) {
+        match self.audio_sink.take() {
+            Some(mut sink) => match sink.finalize() {
+                Err(e) => log::warn!("audio sink finalize: {}", e),
+                _ => {}
+            },
+            None => {}
+        };
+    }
+
+    fn assemble(&mut self, sample_buffer: &SampleBuffer) -> Option<Frame> {
+        let mut data: Vec<u8> = Vec::new();
+        let mut params_changed = false;
+
+        match sample_buffer.format_description().and_then(|fd| fd.avc1()) {
+            Some(avc1) => {
+                let sps = avc1.sps();
+                let pps = avc1.pps();
+
+                if self.sps.as_deref() != Some(sps) || self.pps.as_deref() != Some(pps) {
+                    self.sps = Some(Vec::from(sps));
+                    self.pps = Some(Vec::from(pps));
+                    params_changed = true;
+
+                    log::info!(
+                        "h264 {} profile, level {}",
+                        avc1.profile_string(),
+                        avc1.level_string()
+                    );
+                }
+            }
+            None => {}
+        };
+
+        if params_changed || self.keyframes_only {
+            for params in [&self.sps, &self.pps].iter().copied().flatten() {
+                h264::write_annexb(&mut data, params);
+            }
+        }
+
+        let nalus = match h264::split_nalus(sample_buffer.sample_data()?) {
+            Ok(e) => e,
+            Err(e) => {
+                log::warn!("assembler drop frame: {}", e);
+                return None;
+            }
+        };
+
+        for nalu in nalus {
+            if self.strip_sei && h264::is_sei(nalu) {
+                continue;
+            }
+            h264::write_annexb(&mut data, nalu);
+        }
+
+        let pts = match &mut self.normalizer {
+            Some(normalizer) => normalizer.normalize(&sample_buffer.presentation_time_stamp()?),
+            None => sample_buffer.presentation_time_stamp()?,
+        };
+
+        Some((data, pts, sample_buffer.is_sync_sample()))
+    }
+}
+
+impl Assembler {
+    fn next_frame(&mut self) -> Option<(f64, Frame)> {
+        loop {
+            let sample_buffer = match self.rx.recv() {
+                Ok(Ok(e)) => e,
+                // closed sentinel or sender gone
+                _ => {
+                    self.finish_audio();
+                    return None;
+                }
+            };
+
+            if self.drop_stale && self.is_stale(&sample_buffer) {
+                self.stale_samples += 1;
+                log::debug!("drop stale {} sample", sample_buffer.media_type_str());
+                continue;
+            }
+
+            // audio can come first, it sets the shared origin as well
+            match (
+                &mut self.normalizer,
+                sample_buffer.presentation_time_stamp(),
+            ) {
+                (Some(normalizer), Some(pts)) if normalizer.origin().is_none() => {
+                    normalizer.normalize(&pts);
+                }
+                _ => {}
+            };
+
+            if sample_buffer.media_type() != MEDIA_TYPE_VIDEO {
+                match &mut self.audio_sink {
+                    Some(sink) => match sink.write(&sample_buffer) {
+                        Err(e) => {
+                            // keep the video going without audio
+                            log::warn!("audio sink write: {}", e);
+                            self.audio_sink = None;
+                        }
+                        _ => {}
+                    },
+                    None => {}
+                };
+                continue;
+            }
+
+            if self.keyframes_only && !sample_buffer.is_sync_sample() {
+                continue;
+            }
+
+            let dts = match sample_buffer.decode_time_stamp() {
+                Some(e) => Some(e.as_secs_f64()),
+                None => None,
+            };
+
+            match self.assemble(&sample_buffer) {
+                Some(frame) => return Some((dts.unwrap_or(frame.1.as_secs_f64()), frame)),
+                None => continue,
+            }
+        }
+    }
+}
+
+impl Iterator for Assembler {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.ended && self.pending.len() <= self.reorder_window {
+            match self.next_frame() {
+                Some(frame) => self.pending.push(frame),
+                None => self.ended = true,
+            }
+        }
+
+        self.pop_earliest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    // (pts in 1/600s, tag) per frame, the tag ends up as the last data byte
+    fn assemble_all(window: usize, frames: &[(u64, u8)]) -> Vec<(u64, u8)> {
+        let (tx, rx) = sync_channel(frames.len());
+        for (pts, tag) in frames {
+            let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+            sample.set_sample_data(Some(vec![0, 0, 0, 2, 0x41, *tag]));
+            sample.set_output_presentation_time_stamp(Some(Time::new(*pts, 600, 1, 0)));
+            tx.send(Ok(sample)).unwrap();
+        }
+        drop(tx);
+
+        let mut assembler = Assembler::new(rx);
+        assembler.set_reorder_window(window);
+        assembler
+            .map(|(data, pts, _)| (pts.value(), *data.last().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn no_window_passes_frames_through() {
+        let frames = [(0, 0), (40, 1), (20, 2), (60, 3)];
+        assert_eq!(assemble_all(0, &frames), frames);
+    }
+
+    #[test]
+    fn window_emits_in_timestamp_order() {
+        let frames = [(0, 0), (40, 1), (20, 2), (80, 3), (60, 4), (100, 5)];
+        assert_eq!(
+            assemble_all(2, &frames),
+            [(0, 0), (20, 2), (40, 1), (60, 4), (80, 3), (100, 5)]
+        );
+
+        // equal timestamps keep their arrival order
+        let frames = [(20, 0), (0, 1), (20, 2), (20, 3)];
+        assert_eq!(
+            assemble_all(DEFAULT_REORDER_WINDOW, &frames),
+            [(0, 1), (20, 0), (20, 2), (20, 3)]
+        );
+    }
+
+    #[test]
+    fn frame_later_than_the_window_goes_out_late() {
+        // 0 arrives after 20 already left with a window of 1
+        let frames = [(20, 0), (40, 1), (0, 2), (60, 3)];
+        assert_eq!(
+            assemble_all(1, &frames),
+            [(20, 0), (0, 2), (40, 1), (60, 3)]
+        );
+    }
+}
@@ -2,6 +2,7 @@ use crate::qt_pkt::QTPacket;
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::io::Error;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct AudioStreamDescription {
     sample_rate: f64,
     format_id: u32,
@@ -16,6 +17,13 @@ pub struct AudioStreamDescription {
 
 pub const AUDIO_FORMAT_ID_LPCM: u32 = 0x6C70636D;
 
+// lpcm format flags (kAudioFormatFlag*), the default 12 is packed signed
+// little endian integer
+pub const AUDIO_FORMAT_FLAG_IS_FLOAT: u32 = 1 << 0;
+pub const AUDIO_FORMAT_FLAG_IS_BIG_ENDIAN: u32 = 1 << 1;
+pub const AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER: u32 = 1 << 2;
+pub const AUDIO_FORMAT_FLAG_IS_PACKED: u32 = 1 << 3;
+
 impl AudioStreamDescription {
     pub fn new(
         sample_rate: f64,
@@ -52,6 +60,43 @@ impl AudioStreamDescription {
         self.format_flags
     }
 
+    pub fn is_float(&self) -> bool {
+        self.format_flags & AUDIO_FORMAT_FLAG_IS_FLOAT != 0
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.format_flags & AUDIO_FORMAT_FLAG_IS_BIG_ENDIAN != 0
+    }
+
+    pub fn is_signed_integer(&self) -> bool {
+        self.format_flags & AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER != 0
+    }
+
+    // sample format in ffmpeg naming (s16le, f32be, ...), None for
+    // non lpcm or odd sample sizes
+    pub fn sample_format(&self) -> Option<String> {
+        if self.format_id != AUDIO_FORMAT_ID_LPCM {
+            return None;
+        }
+
+        let kind = match (self.is_float(), self.is_signed_integer()) {
+            (true, _) => "f",
+            (false, true) => "s",
+            (false, false) => "u",
+        };
+
+        let order = match self.is_big_endian() {
+            true => "be",
+            false => "le",
+        };
+
+        match self.bits_per_channel {
+            8 => Some(format!("{}8", kind)),
+            16 | 24 | 32 | 64 => Some(format!("{}{}{}", kind, self.bits_per_channel, order)),
+            _ => None,
+        }
+    }
+
     pub fn bytes_per_packet(&self) -> u32 {
         self.bytes_per_packet
     }
@@ -145,6 +190,7 @@ impl AudioStreamDescription {
         }
     }
 
+    // plain AudioStreamBasicDescription, what from_qt_packet reads
     pub fn as_buffer(&self) -> Result<Vec<u8>, Error> {
         let mut buffer: Vec<u8> = Vec::new();
 
@@ -184,6 +230,18 @@ impl AudioStreamDescription {
             Err(e) => return Err(e),
             _ => {}
         };
+
+        Ok(buffer)
+    }
+
+    // the 40 byte asbd followed by a sample rate range (min, max), the
+    // layout of an entry in the HPA1 "formats" list
+    pub fn as_format_buffer(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer = match self.as_buffer() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
         match buffer.write_f64::<LittleEndian>(self.sample_rate) {
             Err(e) => return Err(e),
             _ => {}
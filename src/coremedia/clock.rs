@@ -1,5 +1,7 @@
 use crate::coremedia::time::Time;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 const NANO_SECOND_SCALE: u32 = 1_000_000_000;
 
@@ -13,11 +15,57 @@ const KCM_TIME_FLAGS_IMPLIED_VALUE_FLAGS_MASK: u32 =
 
 const TIME_LENGTH_IN_BYTES: i32 = 24;
 
+// where clocks read the current time from, only differences between two
+// readings matter so the origin is up to the implementation
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+// wall clock of the host
+pub struct HostTimeSource;
+
+impl TimeSource for HostTimeSource {
+    fn now(&self) -> Duration {
+        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(e) => e,
+            Err(_) => Duration::ZERO,
+        }
+    }
+}
+
+// time only moves when told to, for reproducing clock and skew values
+pub struct ManualTimeSource {
+    nanos: AtomicU64,
+}
+
+impl ManualTimeSource {
+    pub fn new(start: Duration) -> ManualTimeSource {
+        ManualTimeSource {
+            nanos: AtomicU64::new(start.as_nanos() as u64),
+        }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.nanos.store(now.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.nanos.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl TimeSource for ManualTimeSource {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
+
 pub struct Clock {
     id: u64,
     time_scale: u32,
     factor: f64,
-    t: SystemTime,
+    source: Arc<dyn TimeSource>,
+    t: Duration,
 }
 
 impl Clone for Clock {
@@ -26,6 +74,7 @@ impl Clone for Clock {
             id: self.id,
             time_scale: self.time_scale,
             factor: self.factor,
+            source: Arc::clone(&self.source),
             t: self.t,
         };
     }
@@ -33,23 +82,33 @@ impl Clone for Clock {
 
 impl Clock {
     pub fn new_with_host_time(id: u64) -> Clock {
-        Clock {
-            id,
-            time_scale: NANO_SECOND_SCALE,
-            factor: 1f64,
-            t: SystemTime::now(),
-        }
+        Clock::new_with_time_source(id, Arc::new(HostTimeSource))
     }
 
     pub fn new_with_host_time_and_scale(id: u64, ts: u32) -> Clock {
+        Clock::new_with_time_source_and_scale(id, ts, Arc::new(HostTimeSource))
+    }
+
+    pub fn new_with_time_source(id: u64, source: Arc<dyn TimeSource>) -> Clock {
+        Clock::new_with_time_source_and_scale(id, NANO_SECOND_SCALE, source)
+    }
+
+    pub fn new_with_time_source_and_scale(id: u64, ts: u32, source: Arc<dyn TimeSource>) -> Clock {
+        let t = source.now();
+
         Clock {
             id,
             time_scale: ts,
             factor: ts as f64 / NANO_SECOND_SCALE as f64,
-            t: SystemTime::now(),
+            source,
+            t,
         }
     }
 
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     pub fn calculate_skew(st1: &Time, et1: &Time, st2: &Time, et2: &Time) -> f64 {
         let diff_clock1 = et1.value() - st1.value();
         let diff_clock2 = et2.value() - st2.value();
@@ -61,9 +120,7 @@ impl Clock {
     }
 
     pub fn get_time(&self) -> Time {
-        let since = SystemTime::now()
-            .duration_since(self.t)
-            .expect("get time duration since");
+        let since = self.source.now().saturating_sub(self.t);
 
         Time::new(
             self.calc_value(since.as_nanos() as u64),
@@ -1,32 +1,84 @@
 use crate::coremedia::audio_desc::AudioStreamDescription;
 use crate::coremedia::sample::{
-    MAGIC_AUDIO_STREAM_DESCRIPTION, MAGIC_CODEC, MAGIC_EXTENSION, MAGIC_MEDIA_TYPE,
-    MAGIC_VIDEO_DIMENSION, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO,
+    CODEC_AVC1, MAGIC_AUDIO_STREAM_DESCRIPTION, MAGIC_CODEC, MAGIC_EXTENSION,
+    MAGIC_FORMAT_DESCRIPTOR, MAGIC_MEDIA_TYPE, MAGIC_VIDEO_DIMENSION, MEDIA_TYPE_SOUND,
+    MEDIA_TYPE_VIDEO,
 };
 use crate::qt_pkt::QTPacket;
-use crate::qt_value::QTValue;
+use crate::qt_value::{QTKeyValuePair, QTValue};
 use byteorder::{BigEndian, ReadBytesExt};
 use std::fmt::{Debug, Formatter};
 use std::io;
 use std::io::{Cursor, Error, ErrorKind, Read};
 
+#[derive(Clone)]
 pub struct AVC1 {
     version: u8,
     avc_profile: u8,
     avc_compatibility: u8,
     avc_level: u8,
     nalu_len: u8,
-    sps: Option<Vec<u8>>,
-    pps: Option<Vec<u8>>,
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    avcc: Vec<u8>,
 }
 
 impl AVC1 {
     pub fn sps(&self) -> &[u8] {
-        self.sps.as_ref().expect("sps None").as_slice()
+        self.sps.as_slice()
     }
 
     pub fn pps(&self) -> &[u8] {
-        self.pps.as_ref().expect("pps None").as_slice()
+        self.pps.as_slice()
+    }
+
+    // raw AVCDecoderConfigurationRecord as sent by the device
+    pub fn avcc(&self) -> &[u8] {
+        self.avcc.as_slice()
+    }
+
+    pub fn profile(&self) -> u8 {
+        self.avc_profile
+    }
+
+    // constraint_set flags byte
+    pub fn compatibility(&self) -> u8 {
+        self.avc_compatibility
+    }
+
+    // level_idc, ten times the level number
+    pub fn level(&self) -> u8 {
+        self.avc_level
+    }
+
+    pub fn profile_string(&self) -> String {
+        let name = match self.avc_profile {
+            // constraint_set1 marks constrained baseline
+            66 if self.avc_compatibility & 0x40 != 0 => "Constrained Baseline",
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            _ => return format!("Unknown({})", self.avc_profile),
+        };
+        String::from(name)
+    }
+
+    pub fn level_string(&self) -> String {
+        match self.avc_level {
+            // level 1b is signalled with constraint_set3 on baseline, main
+            // and extended only, other profiles use level_idc 9
+            11 if self.avc_compatibility & 0x10 != 0
+                && matches!(self.avc_profile, 66 | 77 | 88) =>
+            {
+                String::from("1b")
+            }
+            9 => String::from("1b"),
+            l => format!("{}.{}", l / 10, l % 10),
+        }
     }
 
     fn from_vec(data: &Vec<u8>) -> Result<AVC1, Error> {
@@ -35,6 +87,12 @@ impl AVC1 {
             Ok(e) => e,
             Err(e) => return Err(e),
         };
+        if version != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("avcC version {}", version),
+            ));
+        }
         let avc_profile = match cur.read_u8() {
             Ok(e) => e,
             Err(e) => return Err(e),
@@ -47,52 +105,53 @@ impl AVC1 {
             Ok(e) => e,
             Err(e) => return Err(e),
         };
+        // 6 reserved bits set, then length size minus one where 2 is not
+        // allowed
         let nalu_len = match cur.read_u8() {
-            Ok(e) => (e & 0x3) + 1,
+            Ok(e) if e & 0xFC == 0xFC && e & 0x3 != 2 => (e & 0x3) + 1,
+            Ok(e) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("avcC length size byte {:#x}", e),
+                ))
+            }
             Err(e) => return Err(e),
         };
+        // 3 reserved bits set, then the sps count
         let sps_size = match cur.read_u8() {
-            Ok(e) => e & 0x1F,
+            Ok(e) if e & 0xE0 == 0xE0 && e & 0x1F > 0 => e & 0x1F,
+            Ok(e) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("avcC sps count byte {:#x}", e),
+                ))
+            }
             Err(e) => return Err(e),
         };
 
-        let mut sps: Option<Vec<u8>> = None;
+        // both counts are at least 1, the accessors rely on it
+        let mut sps: Vec<u8> = Vec::new();
 
         for _ in 0..sps_size {
-            let sps_len = match cur.read_u16::<BigEndian>() {
+            sps = match read_parameter_set(&mut cur) {
                 Ok(e) => e,
                 Err(e) => return Err(e),
             };
-
-            let mut sps_buffer: Vec<u8> = vec![0; sps_len as usize];
-            match cur.read_exact(&mut sps_buffer) {
-                Err(e) => return Err(e),
-                _ => {}
-            };
-
-            sps = Some(sps_buffer)
         }
 
-        let mut pps: Option<Vec<u8>> = None;
-
         let pps_size = match cur.read_u8() {
-            Ok(e) => e & 0x1F,
+            Ok(0) => return Err(Error::new(ErrorKind::InvalidData, "avcC without pps")),
+            Ok(e) => e,
             Err(e) => return Err(e),
         };
 
+        let mut pps: Vec<u8> = Vec::new();
+
         for _ in 0..pps_size {
-            let pps_len = match cur.read_u16::<BigEndian>() {
+            pps = match read_parameter_set(&mut cur) {
                 Ok(e) => e,
                 Err(e) => return Err(e),
             };
-
-            let mut pps_buffer: Vec<u8> = vec![0; pps_len as usize];
-            match cur.read_exact(&mut pps_buffer) {
-                Err(e) => return Err(e),
-                _ => {}
-            };
-
-            pps = Some(pps_buffer)
         }
 
         Ok(AVC1 {
@@ -103,10 +162,101 @@ impl AVC1 {
             nalu_len,
             sps,
             pps,
+            avcc: data.clone(),
         })
     }
 }
 
+// 16 bit length and the parameter set, the length is checked against what is
+// left before anything is allocated
+fn read_parameter_set(cur: &mut Cursor<&Vec<u8>>) -> Result<Vec<u8>, Error> {
+    let len = match cur.read_u16::<BigEndian>() {
+        Ok(e) => e as usize,
+        Err(e) => return Err(e),
+    };
+
+    let remaining = cur.get_ref().len().saturating_sub(cur.position() as usize);
+
+    if len == 0 || len > remaining {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "avcC parameter set length {} with {} bytes left",
+                len, remaining
+            ),
+        ));
+    }
+
+    let mut buffer: Vec<u8> = vec![0; len];
+    match cur.read_exact(&mut buffer) {
+        Err(e) => return Err(e),
+        _ => {}
+    };
+
+    Ok(buffer)
+}
+
+// extension 49 holds the sample description atoms as an object of idx/data
+// pairs, data of the first one if it has the wanted idx
+fn sample_description_atom(value: &QTValue, idx: u16) -> Option<&Vec<u8>> {
+    let kv = value.as_vec()?.first()?.as_pair()?;
+
+    if kv.key().as_idx()? != idx {
+        return None;
+    }
+
+    kv.value().as_data()
+}
+
+// format description extension idx keys meant for the CoreVideo color tags
+// (primaries, transfer function, YCbCr matrix), whose values are strings like
+// ITU_R_709_2 / ITU_R_2020 / ITU_R_2100_HLG. UNCONFIRMED: these idx numbers
+// were not taken from a device capture and have no source to cite, they are
+// inferred. a device using other keys just gets no ColorInfo. check them
+// against the extensions() of a real FEED (Debug or to_json) before relying
+// on them, and note the device and iOS version here once confirmed
+const EXTENSION_IDX_COLOR_PRIMARIES: u16 = 52;
+const EXTENSION_IDX_TRANSFER_FUNCTION: u16 = 53;
+const EXTENSION_IDX_YCBCR_MATRIX: u16 = 54;
+
+pub struct ColorInfo {
+    primaries: Option<String>,
+    transfer: Option<String>,
+    matrix: Option<String>,
+}
+
+impl ColorInfo {
+    pub fn primaries(&self) -> Option<&str> {
+        self.primaries.as_deref()
+    }
+
+    pub fn transfer(&self) -> Option<&str> {
+        self.transfer.as_deref()
+    }
+
+    pub fn matrix(&self) -> Option<&str> {
+        self.matrix.as_deref()
+    }
+}
+
+impl Debug for ColorInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "primaries: {:?}\ntransfer: {:?}\nmatrix: {:?}\n",
+            self.primaries, self.transfer, self.matrix,
+        ))
+    }
+}
+
+// the stream carries no explicit rotation flag, a rotated device sends a new
+// format descriptor with width and height swapped
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+#[derive(Clone)]
 pub struct FormatDescriptor {
     media_type: u32,
     video_dimension_width: u32,
@@ -118,6 +268,46 @@ pub struct FormatDescriptor {
 }
 
 impl FormatDescriptor {
+    // sound format from a bare asbd, e.g. the one AFMT announces
+    pub fn new_audio(asbd: AudioStreamDescription) -> FormatDescriptor {
+        FormatDescriptor {
+            media_type: MEDIA_TYPE_SOUND,
+            video_dimension_width: 0,
+            video_dimension_height: 0,
+            codec: 0,
+            extensions: None,
+            avc1: None,
+            audio_stream_basic_description: Some(asbd),
+        }
+    }
+
+    // h264 format from an avcC record, the record goes into extension 49 the
+    // way the device sends it so write_boxes reproduces it
+    pub fn new_video(width: u32, height: u32, avcc: &[u8]) -> Result<FormatDescriptor, Error> {
+        let avc1 = match AVC1::from_vec(&Vec::from(avcc)) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let atoms = QTValue::Object(vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(105),
+            QTValue::Data(Vec::from(avcc)),
+        ))]);
+
+        Ok(FormatDescriptor {
+            media_type: MEDIA_TYPE_VIDEO,
+            video_dimension_width: width,
+            video_dimension_height: height,
+            codec: CODEC_AVC1,
+            extensions: Some(vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+                QTValue::IdxKey(49),
+                atoms,
+            ))]),
+            avc1: Some(avc1),
+            audio_stream_basic_description: None,
+        })
+    }
+
     pub fn video_dimension_width(&self) -> u32 {
         self.video_dimension_width
     }
@@ -126,17 +316,88 @@ impl FormatDescriptor {
         self.video_dimension_height
     }
 
+    pub fn media_type(&self) -> u32 {
+        self.media_type
+    }
+
+    // codec fourcc, 0 for audio
+    pub fn codec(&self) -> u32 {
+        self.codec
+    }
+
+    // derived from the video dimensions, None for audio
+    pub fn orientation(&self) -> Option<Orientation> {
+        if self.media_type != MEDIA_TYPE_VIDEO || self.video_dimension_width == 0 {
+            return None;
+        }
+
+        match self.video_dimension_height > self.video_dimension_width {
+            true => Some(Orientation::Portrait),
+            false => Some(Orientation::Landscape),
+        }
+    }
+
     pub fn audio_stream_description(&self) -> &AudioStreamDescription {
         self.audio_stream_basic_description
             .as_ref()
             .expect("audio stream description")
     }
 
-    pub fn avc1(&self) -> &AVC1 {
-        self.avc1.as_ref().expect("avc1")
+    // None for audio and for video formats without an avcC atom
+    pub fn avc1(&self) -> Option<&AVC1> {
+        self.avc1.as_ref()
+    }
+
+    pub fn extensions(&self) -> Option<&[QTValue]> {
+        match &self.extensions {
+            Some(e) => Some(e.as_slice()),
+            None => None,
+        }
+    }
+
+    // lookup extension value by idx key
+    pub fn extension(&self, idx: u16) -> Option<&QTValue> {
+        for extension in self.extensions()? {
+            match extension.as_pair() {
+                Some(kv) if kv.key().as_idx() == Some(idx) => return Some(kv.value()),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    pub fn color_info(&self) -> Option<ColorInfo> {
+        let color_info = ColorInfo {
+            primaries: self
+                .extension(EXTENSION_IDX_COLOR_PRIMARIES)
+                .and_then(|v| v.as_string()),
+            transfer: self
+                .extension(EXTENSION_IDX_TRANSFER_FUNCTION)
+                .and_then(|v| v.as_string()),
+            matrix: self
+                .extension(EXTENSION_IDX_YCBCR_MATRIX)
+                .and_then(|v| v.as_string()),
+        };
+
+        if color_info.primaries.is_none()
+            && color_info.transfer.is_none()
+            && color_info.matrix.is_none()
+        {
+            return None;
+        }
+
+        Some(color_info)
     }
 
     pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<FormatDescriptor, Error> {
+        FormatDescriptor::from_qt_packet_nested(pkt, 0)
+    }
+
+    // depth of the enclosing QTValue, extensions may hold values again
+    pub(crate) fn from_qt_packet_nested(
+        pkt: &mut QTPacket,
+        depth: u32,
+    ) -> Result<FormatDescriptor, Error> {
         let (mut mdia_pkt, _) = match QTPacket::from_qt_packet_with_magic(pkt, MAGIC_MEDIA_TYPE) {
             Ok(e) => e,
             Err(e) => return Err(e),
@@ -162,15 +423,7 @@ impl FormatDescriptor {
                     Err(e) => return Err(e),
                 };
 
-                Ok(FormatDescriptor {
-                    media_type: MEDIA_TYPE_SOUND,
-                    video_dimension_width: 0,
-                    video_dimension_height: 0,
-                    codec: 0,
-                    extensions: None,
-                    avc1: None,
-                    audio_stream_basic_description: Some(asd),
-                })
+                Ok(FormatDescriptor::new_audio(asd))
             }
             MEDIA_TYPE_VIDEO => {
                 let (mut video_dimension, _) =
@@ -211,38 +464,28 @@ impl FormatDescriptor {
                 let mut avc1: Option<AVC1> = None;
 
                 loop {
-                    let extension = match QTValue::from_qt_packet(&mut extension_pkt) {
-                        Ok(e) => e,
-                        Err(e) => match e.kind() {
-                            ErrorKind::UnexpectedEof => break,
-                            _ => return Err(e),
-                        },
-                    };
+                    let extension =
+                        match QTValue::from_qt_packet_nested(&mut extension_pkt, depth + 1) {
+                            Ok(e) => e,
+                            Err(e) => match e.kind() {
+                                ErrorKind::UnexpectedEof => break,
+                                _ => return Err(e),
+                            },
+                        };
 
                     match extension.as_pair() {
                         Some(kv) => match kv.key().as_idx() {
                             Some(idx) => match idx {
-                                49 => {
-                                    let obj = kv.value().as_vec().expect("idx 49 is not object");
-                                    if obj.len() > 0 {
-                                        let obj_kv =
-                                            obj[0].as_pair().expect("obj[0] is not kv pair");
-                                        let obj_k =
-                                            obj_kv.key().as_idx().expect("obj[0].key is not idx");
-                                        if obj_k == 105 {
-                                            // AVCC format in iOS 15.6
-                                            let obj_data = obj_kv
-                                                .value()
-                                                .as_data()
-                                                .expect("obj[0].value is not data");
-
-                                            avc1 = Some(match AVC1::from_vec(obj_data) {
-                                                Ok(e) => e,
-                                                Err(e) => return Err(e),
-                                            });
-                                        }
+                                49 => match sample_description_atom(kv.value(), 105) {
+                                    // AVCC format in iOS 15.6
+                                    Some(obj_data) => {
+                                        avc1 = Some(match AVC1::from_vec(obj_data) {
+                                            Ok(e) => e,
+                                            Err(e) => return Err(e),
+                                        })
                                     }
-                                }
+                                    None => {}
+                                },
                                 _ => {}
                             },
                             _ => {}
@@ -267,44 +510,58 @@ impl FormatDescriptor {
         }
     }
 
+    // full fdsc box, the inverse of from_qt_packet after its fdsc header
     pub fn as_qt_packet(&self) -> Result<QTPacket, io::Error> {
-        let mut mdia_pkt = QTPacket::new();
-        match mdia_pkt.write_u32(MAGIC_MEDIA_TYPE) {
+        let mut pkt = QTPacket::new_with_magic(MAGIC_FORMAT_DESCRIPTOR);
+
+        match self.write_boxes(&mut pkt) {
             Err(e) => return Err(e),
             _ => {}
         };
 
+        Ok(pkt)
+    }
+
+    // fdsc content: mdia followed by asbd for sound or vdim, codc and extn
+    // for video. siblings, the same order from_qt_packet reads them in
+    pub fn write_boxes(&self, pkt: &mut QTPacket) -> Result<(), io::Error> {
+        let mut mdia_pkt = QTPacket::new_with_magic(MAGIC_MEDIA_TYPE);
+
         match mdia_pkt.write_u32(self.media_type) {
             Err(e) => return Err(e),
             _ => {}
         };
 
+        match write_box(pkt, &mut mdia_pkt) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
         match self.media_type {
             MEDIA_TYPE_SOUND => {
-                let mut asdb = QTPacket::new_with_magic(MAGIC_AUDIO_STREAM_DESCRIPTION);
+                let asbd = match &self.audio_stream_basic_description {
+                    Some(e) => e,
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "sound format without audio stream description",
+                        ))
+                    }
+                };
 
-                let buffer = match self
-                    .audio_stream_basic_description
-                    .as_ref()
-                    .unwrap()
-                    .as_buffer()
-                {
+                let buffer = match asbd.as_buffer() {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 };
 
-                let asdb_buffer = match asdb.write(buffer.as_slice()) {
-                    Err(e) => return Err(e),
-                    Ok(_) => match asdb.as_bytes() {
-                        Ok(e) => e,
-                        Err(e) => return Err(e),
-                    },
-                };
+                let mut asbd_pkt = QTPacket::new_with_magic(MAGIC_AUDIO_STREAM_DESCRIPTION);
 
-                match mdia_pkt.write(asdb_buffer) {
+                match asbd_pkt.write(buffer.as_slice()) {
                     Err(e) => return Err(e),
                     _ => {}
-                }
+                };
+
+                write_box(pkt, &mut asbd_pkt)
             }
             MEDIA_TYPE_VIDEO => {
                 let mut vd_pkt = QTPacket::new_with_magic(MAGIC_VIDEO_DIMENSION);
@@ -319,73 +576,213 @@ impl FormatDescriptor {
                     _ => {}
                 };
 
-                let mut codec_pkt = QTPacket::new_with_magic(MAGIC_CODEC);
-
-                match codec_pkt.write_u32(self.codec) {
+                match write_box(pkt, &mut vd_pkt) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
 
-                let codec_buffer = match codec_pkt.as_bytes() {
-                    Ok(e) => e,
+                let mut codec_pkt = QTPacket::new_with_magic(MAGIC_CODEC);
+
+                match codec_pkt.write_u32(self.codec) {
                     Err(e) => return Err(e),
+                    _ => {}
                 };
 
-                match vd_pkt.write(codec_buffer) {
+                match write_box(pkt, &mut codec_pkt) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
 
+                // from_qt_packet requires extn, an empty one stands for none
                 let mut extension_pkt = QTPacket::new_with_magic(MAGIC_EXTENSION);
 
-                if self.extensions.is_some() {
-                    for extension in self.extensions.as_ref().unwrap() {
-                        let mut ext_val_pkt = match extension.as_qt_packet() {
-                            Ok(e) => e,
-                            Err(e) => return Err(e),
-                        };
-
-                        let extensions_buffer = match ext_val_pkt.as_bytes() {
-                            Ok(e) => e,
-                            Err(e) => return Err(e),
-                        };
-
-                        match extension_pkt.write(extensions_buffer) {
-                            Err(e) => return Err(e),
-                            _ => {}
-                        };
-                    }
-
-                    let extension_buffer = match extension_pkt.as_bytes() {
-                        Err(e) => return Err(e),
+                for extension in self.extensions().unwrap_or(&[]) {
+                    let mut ext_val_pkt = match extension.as_qt_packet() {
                         Ok(e) => e,
+                        Err(e) => return Err(e),
                     };
 
-                    match vd_pkt.write(extension_buffer) {
+                    match write_box(&mut extension_pkt, &mut ext_val_pkt) {
                         Err(e) => return Err(e),
                         _ => {}
                     };
                 }
 
-                let vd_buffer = match vd_pkt.as_bytes() {
-                    Err(e) => return Err(e),
-                    Ok(e) => e,
-                };
-
-                match mdia_pkt.write(vd_buffer) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                };
+                write_box(pkt, &mut extension_pkt)
             }
-            _ => return Err(Error::new(ErrorKind::InvalidData, "media type invalid")),
-        };
-
-        Ok(mdia_pkt)
+            _ => Err(Error::new(ErrorKind::InvalidData, "media type invalid")),
+        }
     }
 }
 
+// append child with its final length to parent
+fn write_box(parent: &mut QTPacket, child: &mut QTPacket) -> Result<(), io::Error> {
+    let buffer = match child.as_bytes() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    match parent.write(buffer) {
+        Err(e) => return Err(e),
+        _ => {}
+    };
+
+    Ok(())
+}
+
 impl Debug for FormatDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("Format Descriptor")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // avcC with one sps and one pps
+    fn avcc(profile: u8, compatibility: u8, level: u8) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![1, profile, compatibility, level, 0xFF, 0xE1];
+        data.extend_from_slice(&[0, 4, 0x67, profile, compatibility, level]);
+        data.extend_from_slice(&[1, 0, 2, 0x68, 0xCE]);
+        data
+    }
+
+    #[test]
+    fn level_1b() {
+        for profile in [66, 77, 88] {
+            let avc1 = AVC1::from_vec(&avcc(profile, 0x10, 11)).unwrap();
+            assert_eq!(avc1.level_string(), "1b");
+        }
+
+        // constraint_set3 means something else for the high profiles
+        for profile in [100, 110, 122, 244] {
+            let avc1 = AVC1::from_vec(&avcc(profile, 0x10, 11)).unwrap();
+            assert_eq!(avc1.level_string(), "1.1");
+        }
+
+        let avc1 = AVC1::from_vec(&avcc(100, 0, 9)).unwrap();
+        assert_eq!(avc1.level_string(), "1b");
+
+        let avc1 = AVC1::from_vec(&avcc(77, 0, 11)).unwrap();
+        assert_eq!(avc1.level_string(), "1.1");
+    }
+
+    #[test]
+    fn truncated_avcc() {
+        let data = avcc(100, 0, 31);
+        assert!(AVC1::from_vec(&data).is_ok());
+
+        for len in 0..data.len() {
+            let truncated = Vec::from(&data[..len]);
+            assert!(AVC1::from_vec(&truncated).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn avcc_without_parameter_sets() {
+        let mut data = avcc(100, 0, 31);
+        data[5] = 0xE0;
+        data.drain(6..12);
+        let err = AVC1::from_vec(&data).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut data = avcc(100, 0, 31);
+        data.truncate(12);
+        data.push(0);
+        let err = AVC1::from_vec(&data).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    // length, magic and payload appended to parent
+    fn put_box(parent: &mut QTPacket, magic: u32, payload: &[u8]) {
+        let mut child = QTPacket::new_with_magic(magic);
+        child.write(payload).unwrap();
+        parent.write(child.as_bytes().unwrap()).unwrap();
+    }
+
+    // parse the content of an fdsc box given as bytes
+    fn parse_fdsc(data: &[u8]) -> FormatDescriptor {
+        let mut pkt = QTPacket::from_bytes(data).unwrap();
+        assert_eq!(pkt.read_u32().unwrap(), MAGIC_FORMAT_DESCRIPTOR);
+        FormatDescriptor::from_qt_packet(&mut pkt).unwrap()
+    }
+
+    fn fdsc_bytes(fd: &FormatDescriptor) -> Vec<u8> {
+        fd.as_qt_packet().unwrap().as_bytes().unwrap().to_vec()
+    }
+
+    #[test]
+    fn video_round_trip() {
+        let avcc = avcc(100, 0, 31);
+
+        let atoms = QTValue::Object(vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(105),
+            QTValue::Data(avcc.clone()),
+        ))]);
+        let extensions = [
+            QTValue::KeyValuePair(QTKeyValuePair::new(QTValue::IdxKey(49), atoms)),
+            QTValue::KeyValuePair(QTKeyValuePair::new(
+                QTValue::IdxKey(EXTENSION_IDX_COLOR_PRIMARIES),
+                QTValue::StringValue(String::from("ITU_R_709_2")),
+            )),
+        ];
+
+        let mut extn = QTPacket::new_with_magic(MAGIC_EXTENSION);
+        for extension in &extensions {
+            extn.write(extension.as_qt_packet().unwrap().as_bytes().unwrap())
+                .unwrap();
+        }
+
+        let mut vdim: Vec<u8> = Vec::new();
+        vdim.extend_from_slice(&1170u32.to_le_bytes());
+        vdim.extend_from_slice(&2532u32.to_le_bytes());
+
+        let mut fdsc = QTPacket::new_with_magic(MAGIC_FORMAT_DESCRIPTOR);
+        put_box(&mut fdsc, MAGIC_MEDIA_TYPE, &MEDIA_TYPE_VIDEO.to_le_bytes());
+        put_box(&mut fdsc, MAGIC_VIDEO_DIMENSION, &vdim);
+        put_box(&mut fdsc, MAGIC_CODEC, &CODEC_AVC1.to_le_bytes());
+        fdsc.write(extn.as_bytes().unwrap()).unwrap();
+        let original = fdsc.as_bytes().unwrap().to_vec();
+
+        let fd = parse_fdsc(&original);
+        let written = fdsc_bytes(&fd);
+        assert_eq!(written, original);
+
+        let again = parse_fdsc(&written);
+        assert_eq!(again.media_type(), MEDIA_TYPE_VIDEO);
+        assert_eq!(again.video_dimension_width(), 1170);
+        assert_eq!(again.video_dimension_height(), 2532);
+        assert_eq!(again.codec(), CODEC_AVC1);
+        assert_eq!(again.avc1().unwrap().avcc(), avcc.as_slice());
+        assert_eq!(again.avc1().unwrap().sps(), fd.avc1().unwrap().sps());
+        assert_eq!(again.avc1().unwrap().pps(), fd.avc1().unwrap().pps());
+        assert_eq!(again.extensions().unwrap().len(), extensions.len());
+        assert_eq!(again.color_info().unwrap().primaries(), Some("ITU_R_709_2"));
+        assert_eq!(fdsc_bytes(&again), original);
+    }
+
+    #[test]
+    fn audio_round_trip() {
+        let asbd = AudioStreamDescription::default();
+
+        let mut fdsc = QTPacket::new_with_magic(MAGIC_FORMAT_DESCRIPTOR);
+        put_box(&mut fdsc, MAGIC_MEDIA_TYPE, &MEDIA_TYPE_SOUND.to_le_bytes());
+        put_box(
+            &mut fdsc,
+            MAGIC_AUDIO_STREAM_DESCRIPTION,
+            &asbd.as_buffer().unwrap(),
+        );
+        let original = fdsc.as_bytes().unwrap().to_vec();
+
+        let fd = parse_fdsc(&original);
+        let written = fdsc_bytes(&fd);
+        assert_eq!(written, original);
+
+        let again = parse_fdsc(&written);
+        assert_eq!(again.media_type(), MEDIA_TYPE_SOUND);
+        assert_eq!(*again.audio_stream_description(), asbd);
+        assert!(again.avc1().is_none());
+        assert_eq!(fdsc_bytes(&again), original);
+    }
+}
@@ -1,9 +1,12 @@
+use crate::coremedia::audio_desc::AudioStreamDescription;
 use crate::coremedia::format_desc::FormatDescriptor;
 use crate::coremedia::time::Time;
 use crate::qt_pkt::QTPacket;
-use crate::qt_value::QTValue;
+use crate::qt_value::{QTKeyValuePair, QTValue};
 use std::fmt::{Debug, Formatter};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 pub const MAGIC_AUDIO_STREAM_DESCRIPTION: u32 = 0x61736264;
 pub const MAGIC_FORMAT_DESCRIPTOR: u32 = 0x66647363;
@@ -14,6 +17,24 @@ pub const MAGIC_CODEC: u32 = 0x636F6463;
 pub const MEDIA_TYPE_VIDEO: u32 = 0x76696465;
 pub const MEDIA_TYPE_SOUND: u32 = 0x736F756E;
 pub const CODEC_AVC1: u32 = 0x61766331;
+pub const CODEC_HVC1: u32 = 0x68766331;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MediaType {
+    Video,
+    Sound,
+    Other(u32),
+}
+
+impl From<u32> for MediaType {
+    fn from(media_type: u32) -> Self {
+        match media_type {
+            MEDIA_TYPE_VIDEO => MediaType::Video,
+            MEDIA_TYPE_SOUND => MediaType::Sound,
+            e => MediaType::Other(e),
+        }
+    }
+}
 
 pub struct SampleTimingInfo {
     duration: Time,
@@ -22,12 +43,37 @@ pub struct SampleTimingInfo {
 }
 
 impl SampleTimingInfo {
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> SampleTimingInfo {
-        SampleTimingInfo {
-            duration: Time::from_qt_packet(pkt),
-            presentation_time_stamp: Time::from_qt_packet(pkt),
-            decode_time_stamp: Time::from_qt_packet(pkt),
-        }
+    pub fn duration(&self) -> &Time {
+        &self.duration
+    }
+
+    pub fn presentation_time_stamp(&self) -> &Time {
+        &self.presentation_time_stamp
+    }
+
+    pub fn decode_time_stamp(&self) -> &Time {
+        &self.decode_time_stamp
+    }
+
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<SampleTimingInfo, Error> {
+        let duration = match Time::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let presentation_time_stamp = match Time::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let decode_time_stamp = match Time::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(SampleTimingInfo {
+            duration,
+            presentation_time_stamp,
+            decode_time_stamp,
+        })
     }
 }
 
@@ -48,9 +94,123 @@ impl Debug for SampleTimingInfo {
     }
 }
 
+// satt idx keys for the CoreMedia sample attachments
+const SATT_IDX_NOT_SYNC: u16 = 4;
+const SATT_IDX_PARTIAL_SYNC: u16 = 5;
+const SATT_IDX_DEPENDS_ON_OTHERS: u16 = 26;
+const SATT_IDX_IS_DEPENDED_ON_BY_OTHERS: u16 = 27;
+const SATT_IDX_EARLIER_DISPLAY_TIMES_ALLOWED: u16 = 28;
+const SATT_IDX_DISPLAY_IMMEDIATELY: u16 = 29;
+const SATT_IDX_DO_NOT_DISPLAY: u16 = 30;
+
+#[derive(Default)]
+pub struct SampleAttachments {
+    not_sync: Option<bool>,
+    partial_sync: Option<bool>,
+    depends_on_others: Option<bool>,
+    is_depended_on_by_others: Option<bool>,
+    earlier_display_times_allowed: Option<bool>,
+    display_immediately: Option<bool>,
+    do_not_display: Option<bool>,
+}
+
+impl SampleAttachments {
+    fn from_values(values: &Vec<QTValue>) -> SampleAttachments {
+        let mut attachments = SampleAttachments::default();
+
+        for value in values {
+            let pairs: Vec<&QTValue> = match value.as_vec() {
+                Some(arr) => arr.iter().collect(),
+                None => vec![value],
+            };
+
+            for pair in pairs {
+                let kv = match pair.as_pair() {
+                    Some(e) => e,
+                    None => continue,
+                };
+
+                let idx = match kv.key().as_idx() {
+                    Some(e) => e,
+                    None => continue,
+                };
+
+                let flag = match kv.value() {
+                    QTValue::Boolean(b) => *b,
+                    QTValue::UInt32(n) => *n != 0,
+                    QTValue::UInt64(n) => *n != 0,
+                    _ => continue,
+                };
+
+                match idx {
+                    SATT_IDX_NOT_SYNC => attachments.not_sync = Some(flag),
+                    SATT_IDX_PARTIAL_SYNC => attachments.partial_sync = Some(flag),
+                    SATT_IDX_DEPENDS_ON_OTHERS => attachments.depends_on_others = Some(flag),
+                    SATT_IDX_IS_DEPENDED_ON_BY_OTHERS => {
+                        attachments.is_depended_on_by_others = Some(flag)
+                    }
+                    SATT_IDX_EARLIER_DISPLAY_TIMES_ALLOWED => {
+                        attachments.earlier_display_times_allowed = Some(flag)
+                    }
+                    SATT_IDX_DISPLAY_IMMEDIATELY => attachments.display_immediately = Some(flag),
+                    SATT_IDX_DO_NOT_DISPLAY => attachments.do_not_display = Some(flag),
+                    _ => {}
+                }
+            }
+        }
+
+        attachments
+    }
+
+    pub fn not_sync(&self) -> Option<bool> {
+        self.not_sync
+    }
+
+    pub fn partial_sync(&self) -> Option<bool> {
+        self.partial_sync
+    }
+
+    pub fn depends_on_others(&self) -> Option<bool> {
+        self.depends_on_others
+    }
+
+    pub fn is_depended_on_by_others(&self) -> Option<bool> {
+        self.is_depended_on_by_others
+    }
+
+    pub fn earlier_display_times_allowed(&self) -> Option<bool> {
+        self.earlier_display_times_allowed
+    }
+
+    pub fn display_immediately(&self) -> Option<bool> {
+        self.display_immediately
+    }
+
+    pub fn do_not_display(&self) -> Option<bool> {
+        self.do_not_display
+    }
+}
+
+impl Debug for SampleAttachments {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "not_sync: {:?}\npartial_sync: {:?}\ndepends_on_others: {:?}\nis_depended_on_by_others: {:?}\nearlier_display_times_allowed: {:?}\ndisplay_immediately: {:?}\ndo_not_display: {:?}\n",
+            self.not_sync,
+            self.partial_sync,
+            self.depends_on_others,
+            self.is_depended_on_by_others,
+            self.earlier_display_times_allowed,
+            self.display_immediately,
+            self.do_not_display,
+        ))
+    }
+}
+
 pub struct SampleBuffer {
     output_presentation_time_stamp: Option<Time>,
     format_description: Option<FormatDescriptor>,
+    // format_description came in this sbuf's own fdsc, not attached later
+    inline_format: bool,
     num_samples: u32,                                        //nsmp
     sample_timing_info_array: Option<Vec<SampleTimingInfo>>, //stia
     sample_data: Option<Vec<u8>>,
@@ -58,6 +218,7 @@ pub struct SampleBuffer {
     attachments: Option<Vec<QTValue>>, //satt
     sary: Option<Vec<QTValue>>,        //sary
     media_type: u32,
+    host_time: Option<Duration>,
 }
 
 const SBUF: u32 = 0x73627566; //the cmsamplebuf and only content of feed asyns
@@ -70,6 +231,14 @@ const SSIZ: u32 = 0x7373697A; //samplesize in bytes, size of what is contained i
 const NSMP: u32 = 0x6E736D70; //numsample so you know how many things are in the arrays
 const FREE: u32 = 0x66726565;
 
+// a FEED holds one frame, an EAT a few thousand lpcm frames at most. far
+// above that nsmp is corrupt
+pub const MAX_NUM_SAMPLES: u32 = 1 << 20;
+
+const UNKNOWN_SBUF_BOX_LOG_INTERVAL: u64 = 1000;
+
+static UNKNOWN_SBUF_BOXES: AtomicU64 = AtomicU64::new(0);
+
 impl SampleBuffer {
     pub fn new(media_type: u32) -> SampleBuffer {
         SampleBuffer {
@@ -81,10 +250,51 @@ impl SampleBuffer {
             sample_timing_info_array: None,
             num_samples: 0,
             format_description: None,
+            inline_format: false,
             output_presentation_time_stamp: None,
+            host_time: None,
         }
     }
 
+    pub fn num_samples(&self) -> u32 {
+        self.num_samples
+    }
+
+    pub fn sample_sizes(&self) -> Option<&[u32]> {
+        match &self.sample_sizes {
+            Some(e) => Some(e.as_slice()),
+            None => None,
+        }
+    }
+
+    // stia and ssiz hold either one entry shared by all samples or one per
+    // sample, any other length means the sbuf boxes were misparsed
+    pub fn validate_num_samples(&self) -> Result<(), Error> {
+        let n = self.num_samples as usize;
+
+        match &self.sample_timing_info_array {
+            Some(arr) if arr.len() != 1 && arr.len() != n => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("nsmp {} but {} stia entries", n, arr.len()),
+                ))
+            }
+            _ => {}
+        };
+
+        match &self.sample_sizes {
+            Some(arr) if arr.len() != 1 && arr.len() != n => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("nsmp {} but {} ssiz entries", n, arr.len()),
+                ))
+            }
+            _ => {}
+        };
+
+        Ok(())
+    }
+
     pub fn sary(&self) -> &Vec<QTValue> {
         self.sary.as_ref().expect("take sary")
     }
@@ -96,6 +306,40 @@ impl SampleBuffer {
         }
     }
 
+    pub fn set_sample_data(&mut self, sample_data: Option<Vec<u8>>) {
+        self.sample_data = sample_data;
+    }
+
+    // lpcm bytes of an EAT sample, None for video so it can't end up in an
+    // audio path by accident
+    pub fn audio_samples(&self) -> Option<&[u8]> {
+        if self.media_type != MEDIA_TYPE_SOUND {
+            return None;
+        }
+
+        self.sample_data()
+    }
+
+    // when the host received the sample, read from QuickTime's time source.
+    // the same source for audio and video so both tracks line up on it
+    pub fn host_time(&self) -> Option<Duration> {
+        self.host_time
+    }
+
+    pub fn set_host_time(&mut self, host_time: Option<Duration>) {
+        self.host_time = host_time;
+    }
+
+    pub fn set_format_description(&mut self, format_description: Option<FormatDescriptor>) {
+        self.format_description = format_description;
+    }
+
+    // the device sent a format with this sample, a set_format_description
+    // from a cache doesn't count. decoder config only needs updating here
+    pub fn has_inline_format(&self) -> bool {
+        self.inline_format
+    }
+
     pub fn format_description(&self) -> Option<&FormatDescriptor> {
         match &self.format_description {
             Some(e) => Some(e),
@@ -103,21 +347,128 @@ impl SampleBuffer {
         }
     }
 
+    // how to read audio_samples, None for video or without a format
+    pub fn audio_stream_description(&self) -> Option<&AudioStreamDescription> {
+        if self.media_type != MEDIA_TYPE_SOUND {
+            return None;
+        }
+
+        match self.format_description() {
+            Some(fd) if fd.media_type() == MEDIA_TYPE_SOUND => Some(fd.audio_stream_description()),
+            _ => None,
+        }
+    }
+
+    pub fn sample_timing_info_array(&self) -> Option<&[SampleTimingInfo]> {
+        match &self.sample_timing_info_array {
+            Some(e) => Some(e.as_slice()),
+            None => None,
+        }
+    }
+
+    pub fn sample_attachments(&self) -> Option<SampleAttachments> {
+        match &self.attachments {
+            Some(e) => Some(SampleAttachments::from_values(e)),
+            None => None,
+        }
+    }
+
+    // samples without a NotSync attachment are sync samples (keyframes)
+    pub fn is_sync_sample(&self) -> bool {
+        match self.sample_attachments() {
+            Some(attachments) => attachments.not_sync() != Some(true),
+            None => true,
+        }
+    }
+
+    // replaces the attachments with a lone NotSync, the way the device marks
+    // frames that aren't keyframes
+    pub fn set_sync_sample(&mut self, sync: bool) {
+        self.attachments = Some(vec![QTValue::Object(vec![QTValue::KeyValuePair(
+            QTKeyValuePair::new(QTValue::IdxKey(SATT_IDX_NOT_SYNC), QTValue::Boolean(!sync)),
+        )])]);
+    }
+
     pub fn media_type(&self) -> u32 {
         self.media_type
     }
 
+    pub fn media_kind(&self) -> MediaType {
+        MediaType::from(self.media_type)
+    }
+
+    // fourcc of the media type, e.g. vide / soun
+    pub fn media_type_str(&self) -> String {
+        String::from_utf8_lossy(&self.media_type.to_be_bytes()).into_owned()
+    }
+
+    pub fn set_output_presentation_time_stamp(&mut self, t: Option<Time>) {
+        self.output_presentation_time_stamp = t;
+    }
+
     pub fn output_presentation_time_stamp(&self) -> Option<Time> {
         self.output_presentation_time_stamp.clone()
     }
 
+    // presentation time of the first sample, falls back to opts
+    pub fn presentation_time_stamp(&self) -> Option<Time> {
+        match &self.sample_timing_info_array {
+            Some(arr) if arr.len() > 0 => Some(arr[0].presentation_time_stamp.clone()),
+            _ => self.output_presentation_time_stamp(),
+        }
+    }
+
+    // decode time of the first sample, None when the device left it invalid
+    pub fn decode_time_stamp(&self) -> Option<Time> {
+        match &self.sample_timing_info_array {
+            Some(arr) if arr.len() > 0 && arr[0].decode_time_stamp.is_valid() => {
+                Some(arr[0].decode_time_stamp.clone())
+            }
+            _ => None,
+        }
+    }
+
     pub fn from_qt_packet(pkt: &mut QTPacket, media_type: u32) -> Result<SampleBuffer, Error> {
         let mut sample = Self::new(media_type);
 
-        let (mut sbuf, _) =
-            QTPacket::from_qt_packet_with_magic(pkt, SBUF).expect("read sbuf packet");
+        let (mut sbuf, _) = match QTPacket::from_qt_packet_with_magic(pkt, SBUF) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        while sbuf.remaining() > 0 {
+            let (box_len, magic) = match sbuf.peek_header() {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            match magic {
+                OPTS | STIA | SDAT | NSMP | SSIZ | MAGIC_FORMAT_DESCRIPTOR | SATT | SARY | FREE => {
+                }
+                _ => {
+                    // new iOS versions add boxes we don't know yet, step over
+                    // exactly the declared length so the next box stays aligned
+                    if box_len < 8 {
+                        return Err(Error::new(ErrorKind::InvalidData, "sbuf box length"));
+                    }
+
+                    match sbuf.skip(box_len as u64) {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
+
+                    let count = UNKNOWN_SBUF_BOXES.fetch_add(1, Ordering::Relaxed);
+                    if count % UNKNOWN_SBUF_BOX_LOG_INTERVAL == 0 {
+                        log::debug!(
+                            "sbuf unknown magic {:#x} ({} unknown boxes so far)",
+                            magic,
+                            count + 1
+                        );
+                    }
+                    continue;
+                }
+            };
 
-        while sbuf.pos() < sbuf.len().expect("sbuf length") {
             let (mut inner, magic) = match sbuf.read_qt_packet_with_magic() {
                 Ok(e) => e,
                 Err(e) => return Err(e),
@@ -125,61 +476,106 @@ impl SampleBuffer {
 
             match magic {
                 OPTS => {
-                    sample.output_presentation_time_stamp = Some(Time::from_qt_packet(&mut inner))
+                    sample.output_presentation_time_stamp = match Time::from_qt_packet(&mut inner) {
+                        Ok(e) => Some(e),
+                        Err(e) => return Err(e),
+                    }
                 }
                 STIA => {
                     let mut arr: Vec<SampleTimingInfo> = Vec::new();
-                    while inner.pos() < inner.len().expect("sita length") {
-                        arr.push(SampleTimingInfo::from_qt_packet(&mut inner))
+                    while inner.remaining() > 0 {
+                        match SampleTimingInfo::from_qt_packet(&mut inner) {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.sample_timing_info_array = Some(arr);
                 }
                 SDAT => {
-                    let inner_len = inner.len().expect("inner length");
-                    let mut sample_data: Vec<u8> = vec![0; inner_len as usize - 8];
-                    inner.read(&mut sample_data).expect("sdat read sample data");
+                    let mut sample_data: Vec<u8> = vec![0; inner.remaining() as usize];
+                    match inner.read_exact(&mut sample_data) {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
                     sample.sample_data = Some(sample_data);
                 }
-                NSMP => sample.num_samples = inner.read_u32().expect("nsmp read sample length"),
+                NSMP => {
+                    sample.num_samples = match inner.read_u32() {
+                        Ok(e) if e > MAX_NUM_SAMPLES => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                format!("nsmp {} above {}", e, MAX_NUM_SAMPLES),
+                            ))
+                        }
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    }
+                }
                 SSIZ => {
                     let mut arr: Vec<u32> = Vec::new();
-                    while inner.pos() < inner.len().expect("ssiz length") {
-                        arr.push(inner.read_u32().expect("read ssiz"))
+                    while inner.remaining() > 0 {
+                        match inner.read_u32() {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.sample_sizes = Some(arr);
                 }
                 MAGIC_FORMAT_DESCRIPTOR => {
-                    sample.format_description = Some(
-                        FormatDescriptor::from_qt_packet(&mut inner)
-                            .expect("read format descriptor"),
-                    )
+                    sample.format_description = match FormatDescriptor::from_qt_packet(&mut inner) {
+                        Ok(e) => Some(e),
+                        Err(e) => return Err(e),
+                    };
+                    sample.inline_format = true;
                 }
                 SATT => {
                     let mut arr: Vec<QTValue> = Vec::new();
-                    while inner.pos() < inner.len().expect("satt length") {
-                        arr.push(QTValue::from_qt_packet(&mut inner).expect("read satt"))
+                    while inner.remaining() > 0 {
+                        match QTValue::from_qt_packet(&mut inner) {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.attachments = Some(arr);
                 }
                 SARY => {
                     let mut arr: Vec<QTValue> = Vec::new();
-                    while inner.pos() < inner.len().expect("sary length") {
-                        arr.push(QTValue::from_qt_packet(&mut inner).expect("read sary"))
+                    while inner.remaining() > 0 {
+                        match QTValue::from_qt_packet(&mut inner) {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.sary = Some(arr);
                 }
                 FREE => {
                     // free box
                 }
-                _ => {
-                    println!(
-                        "invalid data {}",
-                        format!("sbuf invalid magic {:#x}", magic)
-                    );
-                }
+                _ => {}
             };
         }
 
+        // every sample takes at least a byte of sdat, empty media markers
+        // come without any
+        match &sample.sample_data {
+            Some(data) if !data.is_empty() && sample.num_samples as usize > data.len() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "nsmp {} with {} bytes of sample data",
+                        sample.num_samples,
+                        data.len()
+                    ),
+                ))
+            }
+            _ => {}
+        };
+
+        match sample.validate_num_samples() {
+            Err(e) => log::warn!("sbuf {}: {}", sample.media_type_str(), e),
+            _ => {}
+        };
+
         Ok(sample)
     }
 }
@@ -228,3 +624,38 @@ impl Debug for SampleBuffer {
         f.write_str("-----")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_box(parent: &mut QTPacket, magic: u32, payload: &[u8]) {
+        let mut child = QTPacket::new_with_magic(magic);
+        child.write(payload).unwrap();
+        parent.write(child.as_bytes().unwrap()).unwrap();
+    }
+
+    // feed payload with the sbuf box at the read position
+    fn feed(sbuf: &mut QTPacket) -> QTPacket {
+        let mut outer = QTPacket::new();
+        outer.write(sbuf.as_bytes().unwrap()).unwrap();
+        QTPacket::from_bytes(outer.as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn unknown_box_is_skipped() {
+        let opts = Time::new(90000, 600, 1, 0);
+
+        let mut sbuf = QTPacket::new_with_magic(SBUF);
+        put_box(&mut sbuf, OPTS, &opts.as_bytes().unwrap());
+        put_box(&mut sbuf, 0x7A7A7A7A, &[0xAA; 13]);
+        put_box(&mut sbuf, SDAT, &[0, 0, 0, 2, 0x65, 0x88]);
+
+        let sample = SampleBuffer::from_qt_packet(&mut feed(&mut sbuf), MEDIA_TYPE_VIDEO).unwrap();
+
+        let t = sample.output_presentation_time_stamp().unwrap();
+        assert_eq!(t.value(), 90000);
+        assert_eq!(t.scale(), 600);
+        assert_eq!(sample.sample_data(), Some(&[0, 0, 0, 2, 0x65, 0x88][..]));
+    }
+}
@@ -61,6 +61,11 @@ impl Time {
         self.value as f64 * scaling_factor
     }
 
+    // kCMTimeFlags_Valid, invalid times carry no usable value
+    pub fn is_valid(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
     pub fn seconds(&self) -> u64 {
         match self.value {
             0 => 0,
@@ -68,18 +73,37 @@ impl Time {
         }
     }
 
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> Time {
-        let value = pkt.read_u64().expect("time read value");
-        let scale = pkt.read_u32().expect("time read scale");
-        let flags = pkt.read_u32().expect("time read flags");
-        let epoch = pkt.read_u64().expect("time read epoch");
+    pub fn as_secs_f64(&self) -> f64 {
+        match self.scale {
+            0 => 0f64,
+            s => self.value as f64 / s as f64,
+        }
+    }
 
-        Time {
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<Time, Error> {
+        let value = match pkt.read_u64() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let scale = match pkt.read_u32() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let flags = match pkt.read_u32() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let epoch = match pkt.read_u64() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(Time {
             value,
             scale,
             flags,
             epoch,
-        }
+        })
     }
 
     pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
@@ -108,3 +132,44 @@ impl Time {
         Ok(buffer)
     }
 }
+
+// moves timestamps so the stream starts at zero. the origin is the first
+// time seen, whatever track it came from, so audio and video fed through one
+// normalizer keep their offset to each other. times before the origin end
+// up at zero
+pub struct PtsNormalizer {
+    origin: Option<f64>,
+}
+
+impl PtsNormalizer {
+    pub fn new() -> PtsNormalizer {
+        PtsNormalizer { origin: None }
+    }
+
+    pub fn origin(&self) -> Option<f64> {
+        self.origin
+    }
+
+    // seconds since the origin, the first call sets it
+    pub fn normalize_secs(&mut self, secs: f64) -> f64 {
+        let origin = *self.origin.get_or_insert(secs);
+        (secs - origin).max(0f64)
+    }
+
+    // same as normalize_secs keeping scale, flags and epoch of t
+    pub fn normalize(&mut self, t: &Time) -> Time {
+        let secs = self.normalize_secs(t.as_secs_f64());
+        Time::new(
+            (secs * t.scale as f64).round() as u64,
+            t.scale,
+            t.flags,
+            t.epoch,
+        )
+    }
+}
+
+impl Default for PtsNormalizer {
+    fn default() -> PtsNormalizer {
+        PtsNormalizer::new()
+    }
+}
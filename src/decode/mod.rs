@@ -0,0 +1,20 @@
+use crate::interop::AccessUnit;
+use std::io::Error;
+
+#[cfg(feature = "openh264")]
+pub mod openh264;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+
+// decoded picture, packed 8 bit rgb rows
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+// decoding is left to an external codec, the crate only hands it access units
+// in decode order. None means the unit produced no picture (yet)
+pub trait Decoder {
+    fn decode(&mut self, access_unit: &AccessUnit) -> Result<Option<Frame>, Error>;
+}
@@ -0,0 +1,34 @@
+use crate::decode::{Decoder, Frame};
+use crate::interop::AccessUnit;
+use ::openh264::formats::YUVSource;
+use std::io::{Error, ErrorKind};
+
+// software decoding through cisco's openh264
+pub struct OpenH264Decoder {
+    inner: ::openh264::decoder::Decoder,
+}
+
+impl OpenH264Decoder {
+    pub fn new() -> Result<OpenH264Decoder, Error> {
+        match ::openh264::decoder::Decoder::new() {
+            Ok(inner) => Ok(OpenH264Decoder { inner }),
+            Err(e) => Err(Error::new(ErrorKind::Other, e.to_string())),
+        }
+    }
+}
+
+impl Decoder for OpenH264Decoder {
+    fn decode(&mut self, access_unit: &AccessUnit) -> Result<Option<Frame>, Error> {
+        let yuv = match self.inner.decode(&access_unit.data) {
+            Ok(Some(e)) => e,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+        };
+
+        let (width, height) = yuv.dimensions();
+        let mut rgb: Vec<u8> = vec![0; width * height * 3];
+        yuv.write_rgb8(&mut rgb);
+
+        Ok(Some(Frame { width, height, rgb }))
+    }
+}
@@ -0,0 +1,65 @@
+use crate::coremedia::sample::SampleBuffer;
+use crate::decode::{Decoder, Frame};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+
+// decode the stream from the first keyframe on and save the first picture
+// as png, a screenshot of the live device
+pub fn snapshot<D: Decoder + ?Sized>(
+    decoder: &mut D,
+    rx: &Receiver<Result<SampleBuffer, Error>>,
+    path: &Path,
+) -> Result<(), Error> {
+    let mut started = false;
+
+    loop {
+        let sample_buffer = match rx.recv() {
+            Ok(Ok(e)) => e,
+            _ => return Err(Error::new(ErrorKind::UnexpectedEof, "stream ended")),
+        };
+
+        let access_unit = match sample_buffer.to_access_unit() {
+            Some(e) => e,
+            None => continue,
+        };
+
+        started |= access_unit.keyframe;
+
+        if !started {
+            continue;
+        }
+
+        match decoder.decode(&access_unit) {
+            Ok(Some(frame)) => return write_png(&frame, path),
+            Ok(None) => {}
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+fn write_png(frame: &Frame, path: &Path) -> Result<(), Error> {
+    let file = match File::create(path) {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        frame.width as u32,
+        frame.height as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = match encoder.write_header() {
+        Ok(e) => e,
+        Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string())),
+    };
+
+    match writer.write_image_data(&frame.rgb) {
+        Err(e) => Err(Error::new(ErrorKind::Other, e.to_string())),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{Error, ErrorKind};
+
+pub const NALU_START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+pub const NALU_TYPE_SLICE: u8 = 1;
+pub const NALU_TYPE_IDR: u8 = 5;
+pub const NALU_TYPE_SEI: u8 = 6;
+pub const NALU_TYPE_SPS: u8 = 7;
+pub const NALU_TYPE_PPS: u8 = 8;
+pub const NALU_TYPE_AUD: u8 = 9;
+
+// split sample data into nal units. the device sends AVCC (4 byte big endian
+// length prefix), annex-b start codes are taken as well. a 256..511 byte unit
+// has a length prefix that looks like a 3 byte start code, so the length
+// prefixes win whenever they add up exactly
+pub fn split_nalus(data: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let err = match split_avcc(data) {
+        Ok(e) => return Ok(e),
+        Err(e) => e,
+    };
+
+    match split_annexb(data) {
+        Some(nalus) => Ok(nalus),
+        None => Err(err),
+    }
+}
+
+// split AVCC sample data (4 byte big endian length prefix) into nal units
+pub fn split_avcc(data: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut nalus: Vec<&[u8]> = Vec::new();
+    let mut cur = data;
+
+    while !cur.is_empty() {
+        if cur.len() < 4 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "nalu length truncated",
+            ));
+        }
+
+        let slice_len = u32::from_be_bytes([cur[0], cur[1], cur[2], cur[3]]) as usize;
+        if cur.len() - 4 < slice_len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "nalu data truncated"));
+        }
+
+        nalus.push(&cur[4..slice_len + 4]);
+        cur = &cur[slice_len + 4..];
+    }
+
+    Ok(nalus)
+}
+
+// nal_unit_type from the header byte, 0 (unspecified) for an empty unit
+pub fn nal_type(nalu: &[u8]) -> u8 {
+    match nalu.first() {
+        Some(header) => header & 0x1F,
+        None => 0,
+    }
+}
+
+pub fn is_idr(nalu: &[u8]) -> bool {
+    nal_type(nalu) == NALU_TYPE_IDR
+}
+
+pub fn is_sei(nalu: &[u8]) -> bool {
+    nal_type(nalu) == NALU_TYPE_SEI
+}
+
+// a picture split into several slices has one nal unit per slice, the first
+// one starts at macroblock 0. first_mb_in_slice is the leading ue(v) of the
+// slice header and ue(v) is 0 exactly when its first bit is set
+pub fn is_first_slice(nalu: &[u8]) -> bool {
+    match nal_type(nalu) {
+        NALU_TYPE_SLICE | NALU_TYPE_IDR => match nalu.get(1) {
+            Some(b) => b & 0x80 != 0,
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+pub fn write_annexb(out: &mut Vec<u8>, nalu: &[u8]) {
+    out.extend_from_slice(&NALU_START_CODE);
+    out.extend_from_slice(nalu);
+}
+
+// split annex-b data on 3 or 4 byte start codes, None if it doesn't start
+// with one
+pub fn split_annexb(data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push((i, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    match starts.first() {
+        // 4 byte start code is a zero byte plus the 3 byte one
+        Some((start, _)) if *start == 0 || (*start == 1 && data[0] == 0) => {}
+        _ => return None,
+    };
+
+    let mut nalus: Vec<&[u8]> = Vec::with_capacity(starts.len());
+
+    for (n, (_, begin)) in starts.iter().enumerate() {
+        let mut end = match starts.get(n + 1) {
+            Some((next, _)) => *next,
+            None => data.len(),
+        };
+
+        // trailing zero belongs to the next 4 byte start code
+        while end > *begin && data[end - 1] == 0 && n + 1 < starts.len() {
+            end -= 1;
+        }
+
+        nalus.push(&data[*begin..end]);
+    }
+
+    Some(nalus)
+}
+
+pub struct AnnexBSummary {
+    pub nalus: usize,
+    pub frames: usize,
+    pub idr_frames: usize,
+}
+
+// sanity check for a recorded annex-b stream: it begins with a start code and
+// sps/pps come before the first idr, so a decoder can start at the beginning
+pub fn verify_annexb(data: &[u8]) -> Result<AnnexBSummary, Error> {
+    let nalus = match split_annexb(data) {
+        Some(e) => e,
+        None => return Err(Error::new(ErrorKind::InvalidData, "missing start code")),
+    };
+
+    let mut summary = AnnexBSummary {
+        nalus: nalus.len(),
+        frames: 0,
+        idr_frames: 0,
+    };
+
+    let mut sps = false;
+    let mut pps = false;
+
+    for nalu in nalus {
+        match nal_type(nalu) {
+            NALU_TYPE_SPS => sps = true,
+            NALU_TYPE_PPS => pps = true,
+            NALU_TYPE_IDR => {
+                if summary.idr_frames == 0 && !(sps && pps) {
+                    return Err(Error::new(ErrorKind::InvalidData, "idr before sps/pps"));
+                }
+                if is_first_slice(nalu) {
+                    summary.idr_frames += 1;
+                    summary.frames += 1;
+                }
+            }
+            NALU_TYPE_SLICE if is_first_slice(nalu) => summary.frames += 1,
+            _ => {}
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 4] = [0x67, 0x64, 0x00, 0x1F];
+    const PPS: [u8; 2] = [0x68, 0xCE];
+
+    #[test]
+    fn verify_counts_pictures_not_slices() {
+        let mut data: Vec<u8> = Vec::new();
+        write_annexb(&mut data, &SPS);
+        write_annexb(&mut data, &PPS);
+        // idr in two slices, first_mb_in_slice 0 and 40
+        write_annexb(&mut data, &[0x65, 0x88, 0x84]);
+        write_annexb(&mut data, &[0x65, 0x02, 0x92, 0x10]);
+        // p frame in three slices
+        write_annexb(&mut data, &[0x41, 0x9A, 0x02]);
+        write_annexb(&mut data, &[0x41, 0x02, 0x92, 0x10]);
+        write_annexb(&mut data, &[0x41, 0x01, 0x48, 0x10]);
+        // single slice p frame
+        write_annexb(&mut data, &[0x41, 0x9A, 0x04]);
+
+        let summary = verify_annexb(&data).unwrap();
+        assert_eq!(summary.nalus, 8);
+        assert_eq!(summary.frames, 3);
+        assert_eq!(summary.idr_frames, 1);
+    }
+
+    #[test]
+    fn verify_idr_before_parameter_sets() {
+        let mut data: Vec<u8> = Vec::new();
+        write_annexb(&mut data, &[0x65, 0x88, 0x84]);
+        write_annexb(&mut data, &SPS);
+        write_annexb(&mut data, &PPS);
+
+        assert!(verify_annexb(&data).is_err());
+        assert!(verify_annexb(&[0x65, 0x88]).is_err());
+    }
+
+    #[test]
+    fn annexb_and_avcc_split_alike() {
+        // the 300 byte slice has a length prefix of 00 00 01 2c
+        let mut slice: Vec<u8> = vec![0x65, 0x88];
+        slice.resize(300, 0x5A);
+        let nalus: [&[u8]; 4] = [&SPS, &PPS, &[0x06, 0x05, 0x01, 0x80], &slice];
+
+        let mut avcc: Vec<u8> = Vec::new();
+        let mut annexb: Vec<u8> = Vec::new();
+        for nalu in nalus {
+            avcc.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+            avcc.extend_from_slice(nalu);
+            write_annexb(&mut annexb, nalu);
+        }
+
+        let from_avcc = split_nalus(&avcc).unwrap();
+        let from_annexb = split_nalus(&annexb).unwrap();
+
+        assert_eq!(from_avcc, nalus);
+        assert_eq!(from_annexb, from_avcc);
+    }
+}
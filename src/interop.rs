@@ -0,0 +1,155 @@
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use crate::h264;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+
+// decoder ready view of a video sample, annex-b bytes with sps/pps in front
+// when the sample carries a format description
+pub struct AccessUnit {
+    pub data: Vec<u8>,
+    pub pts: f64,
+    pub dts: f64,
+    pub keyframe: bool,
+}
+
+impl SampleBuffer {
+    // nal units of a FEED sample without their length prefix, None for audio
+    // and for sample data that doesn't split cleanly
+    pub fn video_nalus(&self) -> Option<Vec<&[u8]>> {
+        if self.media_type() != MEDIA_TYPE_VIDEO {
+            return None;
+        }
+
+        h264::split_nalus(self.sample_data()?).ok()
+    }
+
+    // AccessUnit::try_from tells why a sample can't be converted
+    pub fn to_access_unit(&self) -> Option<AccessUnit> {
+        AccessUnit::try_from(self).ok()
+    }
+}
+
+impl TryFrom<&SampleBuffer> for AccessUnit {
+    type Error = Error;
+
+    fn try_from(sample_buffer: &SampleBuffer) -> Result<Self, Self::Error> {
+        if sample_buffer.media_type() != MEDIA_TYPE_VIDEO {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "sample buffer is not a video sample",
+            ));
+        }
+
+        let sample_data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "video sample without sample data",
+                ))
+            }
+        };
+
+        let nalus = match h264::split_nalus(sample_data) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let mut data: Vec<u8> = Vec::new();
+
+        match sample_buffer.format_description() {
+            Some(fd) => match fd.avc1() {
+                Some(avc1) => {
+                    h264::write_annexb(&mut data, avc1.sps());
+                    h264::write_annexb(&mut data, avc1.pps());
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "video format description without avcC",
+                    ))
+                }
+            },
+            None => {}
+        };
+
+        let mut keyframe = false;
+
+        for nalu in nalus {
+            keyframe |= h264::is_idr(nalu);
+            h264::write_annexb(&mut data, nalu);
+        }
+
+        let (pts, dts) = match sample_buffer.sample_timing_info_array() {
+            Some(arr) if !arr.is_empty() => {
+                let pts = arr[0].presentation_time_stamp().as_secs_f64();
+                // decode time stamp is left empty when frames are in order
+                match arr[0].decode_time_stamp().scale() {
+                    0 => (pts, pts),
+                    _ => (pts, arr[0].decode_time_stamp().as_secs_f64()),
+                }
+            }
+            _ => match sample_buffer.output_presentation_time_stamp() {
+                Some(t) => (t.as_secs_f64(), t.as_secs_f64()),
+                None => (0f64, 0f64),
+            },
+        };
+
+        Ok(AccessUnit {
+            data,
+            pts,
+            dts,
+            keyframe,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coremedia::sample::MEDIA_TYPE_SOUND;
+
+    #[test]
+    fn access_unit_errors() {
+        let audio = SampleBuffer::new(MEDIA_TYPE_SOUND);
+        let err = AccessUnit::try_from(&audio).err().unwrap();
+        assert_eq!(err.to_string(), "sample buffer is not a video sample");
+
+        // length prefix claims more than there is
+        let mut video = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        video.set_sample_data(Some(vec![0, 0, 0, 9, 0x65, 0x88]));
+        let err = AccessUnit::try_from(&video).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(video.to_access_unit().is_none());
+
+        video.set_sample_data(Some(vec![0, 0, 0, 2, 0x65, 0x88]));
+        let au = AccessUnit::try_from(&video).unwrap();
+        assert_eq!(au.data, [0, 0, 0, 1, 0x65, 0x88]);
+        assert!(au.keyframe);
+    }
+
+    #[test]
+    fn annexb_and_avcc_samples_alike() {
+        let nalus: [&[u8]; 2] = [&[0x06, 0x05, 0x01, 0x80], &[0x65, 0x88, 0x84, 0x00]];
+
+        let mut avcc: Vec<u8> = Vec::new();
+        let mut annexb: Vec<u8> = Vec::new();
+        for nalu in nalus {
+            avcc.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+            avcc.extend_from_slice(nalu);
+            // 3 byte start codes are accepted as well
+            annexb.extend_from_slice(&[0, 0, 1]);
+            annexb.extend_from_slice(nalu);
+        }
+
+        let mut from_avcc = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        from_avcc.set_sample_data(Some(avcc));
+        let mut from_annexb = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        from_annexb.set_sample_data(Some(annexb));
+
+        let a = AccessUnit::try_from(&from_avcc).unwrap();
+        let b = AccessUnit::try_from(&from_annexb).unwrap();
+        assert_eq!(a.data, b.data);
+        assert_eq!(a.keyframe, b.keyframe);
+    }
+}
@@ -0,0 +1,10 @@
+#![allow(dead_code)]
+
+// the parsers for what the device sends, split out so they can be fuzzed
+// (see fuzz/) without a device or the usb stack. the binary uses them from
+// here as well
+pub mod coremedia;
+pub mod h264;
+pub mod interop;
+pub mod qt_pkt;
+pub mod qt_value;
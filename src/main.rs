@@ -3,45 +3,221 @@
 extern crate core;
 
 mod apple;
-mod coremedia;
+mod assembler;
+mod decode;
+mod mux;
 mod qt;
+mod qt_caps;
 mod qt_device;
-mod qt_pkt;
-mod qt_value;
+mod qt_stats;
+mod retry;
+#[cfg(feature = "rtp")]
+mod rtp;
+mod sink;
+mod validate;
 
-use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use qtstream::{coremedia, h264, interop, qt_pkt, qt_value};
+
+use crate::assembler::Assembler;
+use crate::coremedia::sample::SampleBuffer;
 use crate::qt::QuickTime;
-use byteorder::{BigEndian, WriteBytesExt};
+use crate::qt_value::QTValue;
+#[cfg(feature = "pcm")]
+use crate::sink::pcm::RawPcmSink;
+#[cfg(feature = "wav")]
+use crate::sink::wav::WavSink;
 use rusty_libimobiledevice::error::IdeviceError;
 use rusty_libimobiledevice::idevice;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
+#[cfg(any(feature = "mp4", feature = "mkv", feature = "pcm", feature = "wav"))]
+use std::io::BufWriter;
 use std::io::Write;
 use std::sync::mpsc::{Receiver, SyncSender};
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
-fn get_apple_device() -> Result<idevice::Device, IdeviceError> {
+enum DeviceLookupError {
+    Idevice(IdeviceError),
+    // QuickTime capture runs over the usb vendor interface, there is no
+    // wireless equivalent so network paired devices can't be used
+    NetworkOnly(String),
+}
+
+impl Display for DeviceLookupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceLookupError::Idevice(e) => f.write_fmt(format_args!("{:?}", e)),
+            DeviceLookupError::NetworkOnly(udid) => f.write_fmt(format_args!(
+                "device {} is only reachable over network; QuickTime capture requires USB",
+                udid
+            )),
+        }
+    }
+}
+
+fn get_apple_device() -> Result<idevice::Device, DeviceLookupError> {
     let devices = match idevice::get_devices() {
         Ok(d) => d,
-        Err(e) => return Err(e),
+        Err(e) => return Err(DeviceLookupError::Idevice(e)),
     };
 
+    let mut network_udid: Option<String> = None;
+
     for device in devices {
         if device.get_network() {
+            network_udid = Some(device.get_udid());
             continue;
         }
 
         return Ok(device);
     }
 
-    return Err(IdeviceError::NoDevice);
+    match network_udid {
+        Some(udid) => Err(DeviceLookupError::NetworkOnly(udid)),
+        None => Err(DeviceLookupError::Idevice(IdeviceError::NoDevice)),
+    }
+}
+
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// poll until a usb device shows up, for rigs where the phone is plugged in
+// after the program starts
+fn wait_for_device(timeout: Duration) -> Result<idevice::Device, DeviceLookupError> {
+    let start = Instant::now();
+
+    loop {
+        match get_apple_device() {
+            Ok(d) => return Ok(d),
+            Err(DeviceLookupError::Idevice(IdeviceError::NoDevice))
+            | Err(DeviceLookupError::NetworkOnly(_))
+                if start.elapsed() < timeout =>
+            {
+                thread::sleep(DEVICE_POLL_INTERVAL)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// raw h264 is always there, the others depend on the enabled cargo features
+const OUTPUT_FORMATS: &[&str] = &[
+    "h264",
+    #[cfg(feature = "mp4")]
+    "mp4",
+    #[cfg(feature = "mp4")]
+    "fmp4",
+    #[cfg(feature = "mkv")]
+    "mkv",
+    #[cfg(feature = "rtp")]
+    "rtp",
+];
+
+#[cfg(feature = "rtp")]
+const DEFAULT_RTP_DEST: &str = "127.0.0.1:5004";
+
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    match args.iter().position(|a| a == name) {
+        Some(i) => args.get(i + 1).map(|v| v.as_str()),
+        None => None,
+    }
+}
+
+// every value following the flag, it may be given more than once
+fn arg_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|w| w[0] == flag)
+        .map(|w| w[1].as_str())
+        .collect()
+}
+
+// KEY=VALUE for the device info dictionaries. true/false become Boolean,
+// integers UInt32, decimals Float and anything else StringValue
+fn parse_info_override(arg: &str) -> Option<(String, QTValue)> {
+    let (key, value) = arg.split_once('=')?;
+
+    let value = match value {
+        "true" => QTValue::Boolean(true),
+        "false" => QTValue::Boolean(false),
+        v => match (v.parse::<u32>(), v.parse::<f64>()) {
+            (Ok(i), _) => QTValue::UInt32(i),
+            (_, Ok(f)) => QTValue::Float(f),
+            _ => QTValue::StringValue(String::from(v)),
+        },
+    };
+
+    Some((String::from(key), value))
+}
+
+fn info_overrides(args: &[String], flag: &str) -> Vec<(String, QTValue)> {
+    arg_values(args, flag)
+        .into_iter()
+        .map(|a| parse_info_override(a).expect("info override KEY=VALUE"))
+        .collect()
+}
+
+// a device info dictionary saved as json, see QTValue::from_json
+#[cfg(feature = "serde")]
+fn load_device_info(path: &str) -> QTValue {
+    let file = File::open(path).expect("device info json file");
+    let json: serde_json::Value =
+        serde_json::from_reader(io::BufReader::new(file)).expect("device info json");
+    QTValue::from_json(&json).expect("device info json value")
+}
+
+// endpoint address, hex with 0x prefix or decimal
+fn parse_endpoint(arg: &str) -> Option<u8> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
 }
 
 fn main() {
-    let device = match get_apple_device() {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    match arg_value(&args, "--validate") {
+        Some(path) => {
+            let file = File::open(path).expect("capture file");
+            match validate::validate(io::BufReader::new(file)) {
+                Ok(report) => print!("{:?}", report),
+                Err(e) => println!("validate failed: {}", e),
+            };
+            return;
+        }
+        None => {}
+    };
+
+    let format = arg_value(&args, "--format").unwrap_or("h264");
+    if !OUTPUT_FORMATS.contains(&format) {
+        println!(
+            "unsupported --format {}, this build has: {}",
+            format,
+            OUTPUT_FORMATS.join(", ")
+        );
+        return;
+    }
+
+    // the assembler has one audio sink, the second flag would silently win
+    if args.iter().any(|a| a == "--audio-raw") && args.iter().any(|a| a == "--audio-wav") {
+        println!("--audio-raw and --audio-wav can't be used together, pick one");
+        return;
+    }
+
+    let device = match arg_value(&args, "--wait") {
+        Some(secs) => wait_for_device(Duration::from_secs(secs.parse().expect("--wait seconds"))),
+        None => get_apple_device(),
+    };
+
+    let device = match device {
         Ok(d) => d,
         Err(e) => {
-            println!("get_apple_device: {:?}", e);
+            println!("get_apple_device: {}", e);
             return;
         }
     };
@@ -62,22 +238,79 @@ fn main() {
         }
     };
 
-    let usb_device = match apple::get_usb_device(sn.replace("-", "").as_str()) {
+    let usb_sn = sn.replace("-", "");
+
+    let mut usb_device = match apple::get_usb_device(usb_sn.as_str()) {
         Ok(d) => d,
         Err(e) => {
-            println!("libusb: {:?}", e);
+            match apple::open_error_hint(e) {
+                Some(hint) => println!("libusb: {:?}, {}", e, hint),
+                None => println!("libusb: {:?}", e),
+            };
             return;
         }
     };
 
+    match arg_value(&args, "--qt-interface") {
+        Some(n) => usb_device.set_interface_choice(n.parse().expect("--qt-interface index")),
+        None => {}
+    };
+
+    usb_device.set_endpoint_override(
+        arg_value(&args, "--in-endpoint")
+            .map(|a| parse_endpoint(a).expect("--in-endpoint address")),
+        arg_value(&args, "--out-endpoint")
+            .map(|a| parse_endpoint(a).expect("--out-endpoint address")),
+    );
+
     let (tx, rx): (
         SyncSender<Result<SampleBuffer, io::Error>>,
         Receiver<Result<SampleBuffer, io::Error>>,
     ) = mpsc::sync_channel(256);
 
+    // QuickTime's own Drop doesn't run if this thread panics while the loop
+    // thread still owns it. declared before qt so it drops after it
+    let _guard = apple::QuickTimeGuard::new(usb_sn.as_str());
+
     let mut qt = QuickTime::new(usb_device, tx);
+    qt.set_display_info_overrides(info_overrides(&args, "--display-info"));
+    qt.set_audio_info_overrides(info_overrides(&args, "--audio-info"));
+    #[cfg(feature = "serde")]
+    {
+        qt.set_display_device_info(arg_value(&args, "--display-info-json").map(load_device_info));
+        qt.set_audio_device_info(arg_value(&args, "--audio-info-json").map(load_device_info));
+    }
+    qt.set_frame_rate_hint(
+        arg_value(&args, "--fps-hint")
+            .map(|fps| fps.parse().expect("--fps-hint frames per second")),
+    );
+    match arg_value(&args, "--display-size") {
+        Some(size) => {
+            let (width, height) = size.split_once('x').expect("--display-size WxH");
+            qt.set_display_size(Some((
+                width.parse().expect("--display-size width"),
+                height.parse().expect("--display-size height"),
+            )));
+        }
+        None => {}
+    };
+    qt.set_reset_on_init(args.iter().any(|a| a == "--reset"));
+    qt.set_on_session_established(Some(Box::new(|| {
+        println!("session established, recording")
+    })));
 
     match qt.init() {
+        Err(e) if qt::is_quicktime_interface_missing(&e) => {
+            println!("init qt failed {}, replug the device and try again", e);
+            return;
+        }
+        Err(e) if qt::is_quicktime_interface_busy(&e) => {
+            println!(
+                "init qt failed {}, quit QuickTime Player or any other capture of this device, or retry with --reset",
+                e
+            );
+            return;
+        }
         Err(e) => {
             println!("init qt failed {}", e);
             return;
@@ -85,55 +318,121 @@ fn main() {
         _ => {}
     }
 
+    if args.iter().any(|a| a == "--check") {
+        match qt.self_test(SELF_TEST_TIMEOUT) {
+            Ok(report) => println!(
+                "check ok: clock after {:?}, first frame after {:?}",
+                report.clock, report.first_feed
+            ),
+            Err(e) => println!("check failed: {}", e),
+        };
+        return;
+    }
+
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&qt.term()))
         .expect("register hook failed");
 
-    let t = thread::spawn(move || {
-        match qt.run() {
-            Err(e) => {
-                println!("quick time loop exit: {}", e)
-            }
-            _ => {}
-        };
-    });
+    match format {
+        "h264" => {
+            let t = thread::spawn(move || {
+                match qt.run() {
+                    Err(e) => {
+                        println!("quick time loop exit: {}", e)
+                    }
+                    _ => {}
+                };
+            });
+
+            record_h264(&args, rx);
 
+            t.join().expect("loop thread term");
+        }
+        // the sink formats run the loop on this thread, a failed write ends
+        // it instead of leaving the loop blocked on a full channel
+        #[cfg(feature = "mp4")]
+        "mp4" => {
+            let file = File::create("record.mp4").expect("file");
+            report_sink(qt.run_with_sink(mux::mp4::Mp4Writer::new(BufWriter::new(file))));
+        }
+        #[cfg(feature = "mp4")]
+        "fmp4" => {
+            let file = File::create("record-fragmented.mp4").expect("file");
+            report_sink(
+                qt.run_with_sink(mux::fmp4::FragmentedMp4Writer::new(BufWriter::new(file))),
+            );
+        }
+        #[cfg(feature = "mkv")]
+        "mkv" => {
+            let file = File::create("record.mkv").expect("file");
+            report_sink(qt.run_with_sink(mux::mkv::MkvWriter::new(
+                BufWriter::new(file),
+                mux::mkv::MKV_CODEC_AVC,
+            )));
+        }
+        #[cfg(feature = "rtp")]
+        "rtp" => {
+            let dest = arg_value(&args, "--rtp-dest").unwrap_or(DEFAULT_RTP_DEST);
+            report_sink(qt.run_with_sink(rtp::RtpSender::new(dest).expect("rtp sender")));
+        }
+        _ => unreachable!(),
+    };
+}
+
+fn record_h264(args: &[String], rx: Receiver<Result<SampleBuffer, io::Error>>) {
     let mut file = File::create("record.h264").expect("file");
 
-    loop {
-        let message = rx.recv().expect("read packet from channel");
-        if message.is_err() {
-            break;
-        }
-
-        let sample_buffer = message.unwrap();
-
-        if sample_buffer.media_type() == MEDIA_TYPE_VIDEO {
-            match sample_buffer.format_description() {
-                Some(fd) => {
-                    file.write_u32::<BigEndian>(1).expect("write nalu magic");
-                    file.write(fd.avc1().sps()).expect("write sps");
-                    file.write_u32::<BigEndian>(1).expect("write nalu magic");
-                    file.write(fd.avc1().pps()).expect("write pps");
-                }
-                None => {}
-            };
-            match sample_buffer.sample_data() {
-                Some(buf) => {
-                    let mut cur = buf;
-                    while cur.len() > 0 {
-                        let slice_len =
-                            u32::from_be_bytes([cur[0], cur[1], cur[2], cur[3]]) as usize;
-                        file.write_u32::<BigEndian>(1).expect("write nalu magic");
-                        file.write(&cur[4..slice_len + 4]).expect("write sdat");
-                        cur = &cur[slice_len + 4..];
-                    }
-                }
-                None => {}
-            };
+    let mut assembler = Assembler::new(rx);
+    assembler.set_keyframes_only(args.iter().any(|a| a == "--keyframes-only"));
+    assembler.set_drop_stale(args.iter().any(|a| a == "--drop-stale"));
+
+    match arg_value(args, "--reorder-window") {
+        Some(window) => {
+            assembler.set_reorder_window(window.parse().expect("--reorder-window frames"))
         }
+        None if args.iter().any(|a| a == "--reorder") => {
+            assembler.set_reorder_window(assembler::DEFAULT_REORDER_WINDOW)
+        }
+        None => {}
+    };
+
+    #[cfg(feature = "pcm")]
+    if args.iter().any(|a| a == "--audio-raw") {
+        let audio = File::create("record.pcm").expect("audio file");
+        assembler.set_audio_sink(Some(Box::new(RawPcmSink::new(BufWriter::new(audio)))));
+    }
+
+    #[cfg(feature = "wav")]
+    if args.iter().any(|a| a == "--audio-wav") {
+        let audio = File::create("record.wav").expect("audio file");
+        assembler.set_audio_sink(Some(Box::new(WavSink::new(BufWriter::new(audio)))));
+    }
+
+    for (data, _pts, _keyframe) in assembler.by_ref() {
+        file.write_all(&data).expect("write frame");
+    }
+
+    if assembler.stale_samples() > 0 {
+        println!("dropped {} stale samples", assembler.stale_samples());
     }
 
     file.flush().expect("flush");
 
-    t.join().expect("loop thread term");
+    if args.iter().any(|a| a == "--verify") {
+        let data = std::fs::read("record.h264").expect("read record");
+        match h264::verify_annexb(&data) {
+            Ok(summary) => println!(
+                "verify ok: {} nal units, {} frames, {} idr",
+                summary.nalus, summary.frames, summary.idr_frames
+            ),
+            Err(e) => println!("verify failed: {}", e),
+        };
+    }
+}
+
+// run_with_sink result, a loop error (device gone) or a sink error
+fn report_sink(result: Result<(), io::Error>) {
+    match result {
+        Err(e) => println!("recording failed: {}", e),
+        _ => {}
+    };
 }
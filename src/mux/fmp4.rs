@@ -0,0 +1,383 @@
+use super::mp4::{
+    mp4_box, mp4_full_box, put_matrix, put_u16, put_u32, put_u64, Mp4Track, TrackFormat,
+    DEFAULT_VIDEO_SAMPLE_DELTA, MOVIE_TIMESCALE, VIDEO_TIMESCALE, VIDEO_TRACK_ID,
+};
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use std::io::{Error, ErrorKind, Write};
+
+// tfhd default-base-is-moof, trun offsets count from the moof start
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+// trun data offset | sample duration | sample size | sample flags
+const TRUN_FLAGS: u32 = 0x000701;
+// sample_depends_on 2, an idr needs nothing else
+const SAMPLE_FLAGS_SYNC: u32 = 0x02000000;
+// sample_depends_on 1 | sample_is_non_sync_sample
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x01010000;
+
+struct FragmentSample {
+    // decode time in VIDEO_TIMESCALE from the first sample
+    time: u64,
+    data: Vec<u8>,
+    sync: bool,
+}
+
+// fragmented mp4, video only. the init segment (ftyp + moov without samples)
+// goes out with the first decodable sample, then one moof + mdat per gop.
+// nothing is ever seeked so the output can be a pipe or a socket
+pub struct FragmentedMp4Writer<W: Write> {
+    out: W,
+    // format and first pts, the sample tables stay empty
+    video: Option<Mp4Track>,
+    pending: Vec<FragmentSample>,
+    sequence: u32,
+}
+
+impl<W: Write> FragmentedMp4Writer<W> {
+    pub fn new(out: W) -> FragmentedMp4Writer<W> {
+        FragmentedMp4Writer {
+            out,
+            video: None,
+            pending: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    // moof/mdat pairs written so far
+    pub fn fragments(&self) -> u32 {
+        self.sequence
+    }
+
+    pub fn write_sample_buffer(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        match sample_buffer.media_type() {
+            MEDIA_TYPE_VIDEO => self.write_video(sample_buffer),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_video(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let pts = match sample_buffer.presentation_time_stamp() {
+            Some(e) => e.as_secs_f64(),
+            None => return Err(Error::new(ErrorKind::InvalidData, "sample without pts")),
+        };
+
+        if self.video.is_none() {
+            let format = match sample_buffer.format_description() {
+                Some(fd) => match fd.avc1() {
+                    Some(avc1) => TrackFormat::Video {
+                        width: fd.video_dimension_width(),
+                        height: fd.video_dimension_height(),
+                        avcc: Vec::from(avc1.avcc()),
+                    },
+                    None => return Ok(()),
+                },
+                // can't decode anything before the parameter sets arrive
+                None => return Ok(()),
+            };
+
+            // a fragment has to start on a keyframe
+            if !sample_buffer.is_sync_sample() {
+                return Ok(());
+            }
+
+            let track = Mp4Track::new(format, VIDEO_TIMESCALE, pts);
+
+            match self.write_init(&track) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+
+            self.video = Some(track);
+        }
+
+        let first_pts = self.video.as_ref().unwrap().first_pts();
+
+        let time = ((pts - first_pts).max(0f64) * VIDEO_TIMESCALE as f64).round() as u64;
+        // decode times must not go backwards
+        let time = match self.pending.last() {
+            Some(last) => time.max(last.time),
+            None => time,
+        };
+
+        if sample_buffer.is_sync_sample() && !self.pending.is_empty() {
+            match self.write_fragment(Some(time)) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        self.pending.push(FragmentSample {
+            time,
+            data: Vec::from(data),
+            sync: sample_buffer.is_sync_sample(),
+        });
+
+        Ok(())
+    }
+
+    fn write_init(&mut self, track: &Mp4Track) -> Result<(), Error> {
+        let mut buf: Vec<u8> = Vec::new();
+
+        let mut brands: Vec<u8> = Vec::new();
+        brands.extend_from_slice(b"iso5");
+        put_u32(&mut brands, 0x200);
+        brands.extend_from_slice(b"iso5iso6avc1mp41");
+        mp4_box(&mut buf, b"ftyp", &brands);
+
+        let mut mvhd: Vec<u8> = Vec::new();
+        put_u32(&mut mvhd, 0);
+        put_u32(&mut mvhd, 0);
+        put_u32(&mut mvhd, MOVIE_TIMESCALE);
+        // duration comes from the fragments
+        put_u32(&mut mvhd, 0);
+        // rate 1.0, volume 1.0
+        put_u32(&mut mvhd, 0x00010000);
+        put_u16(&mut mvhd, 0x0100);
+        mvhd.extend_from_slice(&[0; 10]);
+        put_matrix(&mut mvhd);
+        mvhd.extend_from_slice(&[0; 24]);
+        put_u32(&mut mvhd, VIDEO_TRACK_ID + 1);
+
+        let mut moov: Vec<u8> = Vec::new();
+        mp4_full_box(&mut moov, b"mvhd", 0, 0, &mvhd);
+        track.write_trak(&mut moov, VIDEO_TRACK_ID, 0f64);
+
+        let mut trex: Vec<u8> = Vec::new();
+        put_u32(&mut trex, VIDEO_TRACK_ID);
+        // sample description index, every sample uses the one avc1 entry
+        put_u32(&mut trex, 1);
+        // defaults, trun carries all of them
+        put_u32(&mut trex, 0);
+        put_u32(&mut trex, 0);
+        put_u32(&mut trex, 0);
+
+        let mut mvex: Vec<u8> = Vec::new();
+        mp4_full_box(&mut mvex, b"trex", 0, 0, &trex);
+        mp4_box(&mut moov, b"mvex", &mvex);
+
+        mp4_box(&mut buf, b"moov", &moov);
+
+        self.out.write_all(&buf)
+    }
+
+    fn moof(&self, data_offset: u32, durations: &[u32]) -> Vec<u8> {
+        let mut mfhd: Vec<u8> = Vec::new();
+        put_u32(&mut mfhd, self.sequence + 1);
+
+        let mut tfhd: Vec<u8> = Vec::new();
+        put_u32(&mut tfhd, VIDEO_TRACK_ID);
+
+        let mut tfdt: Vec<u8> = Vec::new();
+        put_u64(&mut tfdt, self.pending[0].time);
+
+        let mut trun: Vec<u8> = Vec::new();
+        put_u32(&mut trun, self.pending.len() as u32);
+        put_u32(&mut trun, data_offset);
+        for (sample, duration) in self.pending.iter().zip(durations) {
+            put_u32(&mut trun, *duration);
+            put_u32(&mut trun, sample.data.len() as u32);
+            put_u32(
+                &mut trun,
+                match sample.sync {
+                    true => SAMPLE_FLAGS_SYNC,
+                    false => SAMPLE_FLAGS_NON_SYNC,
+                },
+            );
+        }
+
+        let mut traf: Vec<u8> = Vec::new();
+        mp4_full_box(&mut traf, b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, &tfhd);
+        mp4_full_box(&mut traf, b"tfdt", 1, 0, &tfdt);
+        mp4_full_box(&mut traf, b"trun", 0, TRUN_FLAGS, &trun);
+
+        let mut moof: Vec<u8> = Vec::new();
+        mp4_full_box(&mut moof, b"mfhd", 0, 0, &mfhd);
+        mp4_box(&mut moof, b"traf", &traf);
+
+        let mut buf: Vec<u8> = Vec::new();
+        mp4_box(&mut buf, b"moof", &moof);
+        buf
+    }
+
+    // next_time is the decode time of the sample after the fragment, it gives
+    // the last sample its duration. None at the end of the stream
+    fn write_fragment(&mut self, next_time: Option<u64>) -> Result<(), Error> {
+        let mut durations: Vec<u32> = Vec::with_capacity(self.pending.len());
+
+        for pair in self.pending.windows(2) {
+            durations.push((pair[1].time - pair[0].time) as u32);
+        }
+
+        let last = self.pending.last().unwrap().time;
+        durations.push(match next_time {
+            Some(next) if next > last => (next - last) as u32,
+            _ => match durations.last() {
+                Some(d) if *d > 0 => *d,
+                _ => DEFAULT_VIDEO_SAMPLE_DELTA,
+            },
+        });
+
+        // the moof size doesn't depend on the offset value, build it twice
+        let moof_len = self.moof(0, &durations).len();
+        let moof = self.moof(moof_len as u32 + 8, &durations);
+
+        let mdat_len: usize = self.pending.iter().map(|s| s.data.len()).sum();
+
+        let mut buf: Vec<u8> = Vec::with_capacity(moof.len() + 8 + mdat_len);
+        buf.extend_from_slice(&moof);
+        put_u32(&mut buf, mdat_len as u32 + 8);
+        buf.extend_from_slice(b"mdat");
+        for sample in &self.pending {
+            buf.extend_from_slice(&sample.data);
+        }
+
+        match self.out.write_all(&buf) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.pending.clear();
+        self.sequence += 1;
+
+        Ok(())
+    }
+
+    // flushes the last, possibly partial gop
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if !self.pending.is_empty() {
+            match self.write_fragment(None) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coremedia::format_desc::FormatDescriptor;
+    use crate::coremedia::time::Time;
+
+    // top level boxes as (fourcc, payload after size and type)
+    fn boxes(buf: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut found: Vec<(&[u8], &[u8])> = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let size = u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+            found.push((&buf[pos + 4..pos + 8], &buf[pos + 8..pos + size]));
+            pos += size;
+        }
+        found
+    }
+
+    fn child<'a>(found: &[(&[u8], &'a [u8])], fourcc: &[u8; 4]) -> &'a [u8] {
+        found.iter().find(|(f, _)| f == fourcc).unwrap().1
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn sample(frame: u64, fd: &FormatDescriptor, sync: bool) -> SampleBuffer {
+        let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        sample.set_sample_data(Some(vec![0, 0, 0, 2, 0x41, frame as u8]));
+        // 30fps in 1/600s, starting away from zero
+        sample.set_output_presentation_time_stamp(Some(Time::new(6000 + frame * 20, 600, 1, 0)));
+        sample.set_format_description(Some(fd.clone()));
+        sample.set_sync_sample(sync);
+        sample
+    }
+
+    #[test]
+    fn fragments_start_on_keyframes() {
+        // one sps and one pps
+        let avcc: [u8; 17] = [
+            1, 100, 0, 31, 0xFF, 0xE1, 0, 4, 0x67, 0x64, 0, 0x1F, 1, 0, 2, 0x68, 0xCE,
+        ];
+        let fd = FormatDescriptor::new_video(1170, 2532, &avcc).unwrap();
+
+        let mut writer = FragmentedMp4Writer::new(Vec::new());
+        // nothing decodable before the first keyframe
+        writer.write_sample_buffer(&sample(0, &fd, false)).unwrap();
+        assert!(writer.out.is_empty());
+
+        let frames: [(u64, bool); 5] = [(1, true), (2, false), (3, false), (4, true), (5, false)];
+        for (frame, sync) in frames {
+            writer
+                .write_sample_buffer(&sample(frame, &fd, sync))
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(writer.fragments(), 2);
+
+        let top = boxes(&writer.out);
+        let fourccs: Vec<&[u8]> = top.iter().map(|(f, _)| *f).collect();
+        assert_eq!(
+            fourccs,
+            [&b"ftyp"[..], b"moov", b"moof", b"mdat", b"moof", b"mdat"]
+        );
+
+        let moov = boxes(top[1].1);
+        let mvex = boxes(child(&moov, b"mvex"));
+        let trex = child(&mvex, b"trex");
+        assert_eq!(u32_at(trex, 4), VIDEO_TRACK_ID);
+        let trak = boxes(child(&moov, b"trak"));
+        let mdia = boxes(child(&trak, b"mdia"));
+        let minf = boxes(child(&mdia, b"minf"));
+        let stbl = boxes(child(&minf, b"stbl"));
+        // no samples in the init segment
+        assert_eq!(u32_at(child(&stbl, b"stsz"), 8), 0);
+
+        // (tfdt, samples as (duration, sync))
+        let expected: [(u64, &[(u32, bool)]); 2] = [
+            (0, &[(3000, true), (3000, false), (3000, false)]),
+            (9000, &[(3000, true), (3000, false)]),
+        ];
+
+        for (i, (tfdt_time, samples)) in expected.iter().enumerate() {
+            let moof_buf = top[2 + i * 2].1;
+            let mdat = top[3 + i * 2].1;
+
+            let moof = boxes(moof_buf);
+            assert_eq!(u32_at(child(&moof, b"mfhd"), 4), i as u32 + 1);
+
+            let traf = boxes(child(&moof, b"traf"));
+            let tfhd = child(&traf, b"tfhd");
+            assert_eq!(u32_at(tfhd, 0), TFHD_DEFAULT_BASE_IS_MOOF);
+            assert_eq!(u32_at(tfhd, 4), VIDEO_TRACK_ID);
+
+            let tfdt = child(&traf, b"tfdt");
+            assert_eq!(tfdt[0], 1);
+            assert_eq!(&tfdt[4..12], &tfdt_time.to_be_bytes());
+
+            let trun = child(&traf, b"trun");
+            assert_eq!(u32_at(trun, 0), TRUN_FLAGS);
+            assert_eq!(u32_at(trun, 4), samples.len() as u32);
+            // data starts right after the moof and the mdat header, moof_buf
+            // is without its own header
+            let moof_len = moof_buf.len() as u32 + 8;
+            assert_eq!(u32_at(trun, 8), moof_len + 8);
+
+            for (n, (duration, sync)) in samples.iter().enumerate() {
+                let entry = 12 + n * 12;
+                assert_eq!(u32_at(trun, entry), *duration);
+                assert_eq!(u32_at(trun, entry + 4), 6);
+                let flags = match sync {
+                    true => SAMPLE_FLAGS_SYNC,
+                    false => SAMPLE_FLAGS_NON_SYNC,
+                };
+                assert_eq!(u32_at(trun, entry + 8), flags);
+            }
+
+            assert_eq!(mdat.len(), samples.len() * 6);
+            assert_eq!(mdat[5], 1 + (i * 3) as u8);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use crate::coremedia::time::Time;
+use std::io::{Error, ErrorKind, Write};
+
+pub const MKV_CODEC_AVC: &str = "V_MPEG4/ISO/AVC";
+
+const EBML_HEADER: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const EBML_DOC_TYPE: u32 = 0x4282;
+const EBML_DOC_TYPE_VERSION: u32 = 0x4287;
+const EBML_DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+const MKV_SEGMENT: u32 = 0x18538067;
+const MKV_INFO: u32 = 0x1549A966;
+const MKV_TIMECODE_SCALE: u32 = 0x2AD7B1;
+const MKV_MUXING_APP: u32 = 0x4D80;
+const MKV_WRITING_APP: u32 = 0x5741;
+const MKV_TRACKS: u32 = 0x1654AE6B;
+const MKV_TRACK_ENTRY: u32 = 0xAE;
+const MKV_TRACK_NUMBER: u32 = 0xD7;
+const MKV_TRACK_UID: u32 = 0x73C5;
+const MKV_TRACK_TYPE: u32 = 0x83;
+const MKV_CODEC_ID: u32 = 0x86;
+const MKV_CODEC_PRIVATE: u32 = 0x63A2;
+const MKV_VIDEO: u32 = 0xE0;
+const MKV_PIXEL_WIDTH: u32 = 0xB0;
+const MKV_PIXEL_HEIGHT: u32 = 0xBA;
+const MKV_CLUSTER: u32 = 0x1F43B675;
+const MKV_TIMECODE: u32 = 0xE7;
+const MKV_SIMPLE_BLOCK: u32 = 0xA3;
+
+// segment size is unknown until the capture stops
+const EBML_UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const VIDEO_TRACK_NUMBER: u8 = 1;
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+
+fn ebml_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().position(|b| *b != 0).unwrap_or(3);
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+fn ebml_size(buf: &mut Vec<u8>, size: u64) {
+    let mut len = 1;
+    while len < 8 && size >= (1u64 << (7 * len)) - 1 {
+        len += 1;
+    }
+
+    let marked = size | (1u64 << (7 * len));
+    buf.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+fn ebml_bytes(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+    ebml_id(buf, id);
+    ebml_size(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn ebml_uint(buf: &mut Vec<u8>, id: u32, n: u64) {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    ebml_bytes(buf, id, &bytes[skip..]);
+}
+
+// matroska writer for a single live video track, clusters start on keyframes
+// and are written out with their final size once the next one begins
+pub struct MkvWriter<W: Write> {
+    out: W,
+    codec_id: &'static str,
+    header_written: bool,
+    first_pts: Option<f64>,
+    cluster: Vec<u8>,
+    cluster_timecode: i64,
+}
+
+impl<W: Write> MkvWriter<W> {
+    pub fn new(out: W, codec_id: &'static str) -> MkvWriter<W> {
+        MkvWriter {
+            out,
+            codec_id,
+            header_written: false,
+            first_pts: None,
+            cluster: Vec::new(),
+            cluster_timecode: 0,
+        }
+    }
+
+    pub fn header_written(&self) -> bool {
+        self.header_written
+    }
+
+    pub fn write_header(
+        &mut self,
+        width: u32,
+        height: u32,
+        codec_private: &[u8],
+    ) -> Result<(), Error> {
+        let mut buf: Vec<u8> = Vec::new();
+
+        let mut ebml: Vec<u8> = Vec::new();
+        ebml_uint(&mut ebml, EBML_VERSION, 1);
+        ebml_uint(&mut ebml, EBML_READ_VERSION, 1);
+        ebml_uint(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+        ebml_uint(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+        ebml_bytes(&mut ebml, EBML_DOC_TYPE, b"matroska");
+        ebml_uint(&mut ebml, EBML_DOC_TYPE_VERSION, 4);
+        ebml_uint(&mut ebml, EBML_DOC_TYPE_READ_VERSION, 2);
+        ebml_bytes(&mut buf, EBML_HEADER, &ebml);
+
+        ebml_id(&mut buf, MKV_SEGMENT);
+        buf.extend_from_slice(&EBML_UNKNOWN_SIZE);
+
+        let mut info: Vec<u8> = Vec::new();
+        ebml_uint(&mut info, MKV_TIMECODE_SCALE, TIMECODE_SCALE_NS);
+        ebml_bytes(&mut info, MKV_MUXING_APP, b"qtstream");
+        ebml_bytes(&mut info, MKV_WRITING_APP, b"qtstream");
+        ebml_bytes(&mut buf, MKV_INFO, &info);
+
+        let mut video: Vec<u8> = Vec::new();
+        ebml_uint(&mut video, MKV_PIXEL_WIDTH, width as u64);
+        ebml_uint(&mut video, MKV_PIXEL_HEIGHT, height as u64);
+
+        let mut track: Vec<u8> = Vec::new();
+        ebml_uint(&mut track, MKV_TRACK_NUMBER, VIDEO_TRACK_NUMBER as u64);
+        ebml_uint(&mut track, MKV_TRACK_UID, VIDEO_TRACK_NUMBER as u64);
+        ebml_uint(&mut track, MKV_TRACK_TYPE, TRACK_TYPE_VIDEO);
+        ebml_bytes(&mut track, MKV_CODEC_ID, self.codec_id.as_bytes());
+        ebml_bytes(&mut track, MKV_CODEC_PRIVATE, codec_private);
+        ebml_bytes(&mut track, MKV_VIDEO, &video);
+
+        let mut tracks: Vec<u8> = Vec::new();
+        ebml_bytes(&mut tracks, MKV_TRACK_ENTRY, &track);
+        ebml_bytes(&mut buf, MKV_TRACKS, &tracks);
+
+        match self.out.write_all(&buf) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.header_written = true;
+
+        Ok(())
+    }
+
+    // data is length prefixed nal units as carried in sdat
+    pub fn write_sample(&mut self, data: &[u8], pts: &Time, keyframe: bool) -> Result<(), Error> {
+        if !self.header_written {
+            return Err(Error::new(ErrorKind::Other, "mkv header not written"));
+        }
+
+        let pts = pts.as_secs_f64();
+        let first_pts = *self.first_pts.get_or_insert(pts);
+        let timecode = (((pts - first_pts) * 1000f64).round() as i64).max(0);
+
+        let mut relative = timecode - self.cluster_timecode;
+
+        if self.cluster.is_empty() || keyframe || relative > i16::MAX as i64 || relative < 0 {
+            match self.flush_cluster() {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+
+            self.cluster_timecode = timecode;
+            ebml_uint(&mut self.cluster, MKV_TIMECODE, timecode as u64);
+            relative = 0;
+        }
+
+        let mut block: Vec<u8> = Vec::with_capacity(data.len() + 4);
+        block.push(0x80 | VIDEO_TRACK_NUMBER);
+        block.extend_from_slice(&(relative as i16).to_be_bytes());
+        block.push(if keyframe { 0x80 } else { 0x00 });
+        block.extend_from_slice(data);
+
+        ebml_bytes(&mut self.cluster, MKV_SIMPLE_BLOCK, &block);
+
+        Ok(())
+    }
+
+    pub fn write_sample_buffer(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        if sample_buffer.media_type() != MEDIA_TYPE_VIDEO {
+            return Ok(());
+        }
+
+        if !self.header_written {
+            let (fd, avc1) = match sample_buffer.format_description() {
+                Some(fd) => match fd.avc1() {
+                    Some(avc1) => (fd, avc1),
+                    None => return Ok(()),
+                },
+                // can't decode anything before the parameter sets arrive
+                None => return Ok(()),
+            };
+
+            match self.write_header(
+                fd.video_dimension_width(),
+                fd.video_dimension_height(),
+                avc1.avcc(),
+            ) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let pts = match sample_buffer.presentation_time_stamp() {
+            Some(e) => e,
+            None => return Err(Error::new(ErrorKind::InvalidData, "sample without pts")),
+        };
+
+        self.write_sample(data, &pts, sample_buffer.is_sync_sample())
+    }
+
+    fn flush_cluster(&mut self) -> Result<(), Error> {
+        if self.cluster.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf: Vec<u8> = Vec::with_capacity(self.cluster.len() + 12);
+        ebml_bytes(&mut buf, MKV_CLUSTER, &self.cluster);
+        self.cluster.clear();
+
+        self.out.write_all(&buf)
+    }
+
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match self.flush_cluster() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // element id with its marker bits, as the constants are written
+    fn read_id(buf: &[u8], pos: &mut usize) -> u32 {
+        let len = buf[*pos].leading_zeros() as usize + 1;
+        let id = buf[*pos..*pos + len]
+            .iter()
+            .fold(0u32, |id, b| (id << 8) | *b as u32);
+        *pos += len;
+        id
+    }
+
+    // None for the unknown size
+    fn read_size(buf: &[u8], pos: &mut usize) -> Option<usize> {
+        let len = buf[*pos].leading_zeros() as usize + 1;
+        let marked = buf[*pos..*pos + len]
+            .iter()
+            .fold(0u64, |n, b| (n << 8) | *b as u64);
+        *pos += len;
+        let size = marked & !(1u64 << (7 * len));
+        match size == (1u64 << (7 * len)) - 1 {
+            true => None,
+            false => Some(size as usize),
+        }
+    }
+
+    // children of a master element as (id, payload)
+    fn children(buf: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements: Vec<(u32, &[u8])> = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let id = read_id(buf, &mut pos);
+            let size = read_size(buf, &mut pos).unwrap_or(buf.len() - pos);
+            elements.push((id, &buf[pos..pos + size]));
+            pos += size;
+        }
+        elements
+    }
+
+    fn child<'a>(elements: &[(u32, &'a [u8])], id: u32) -> &'a [u8] {
+        elements.iter().find(|(i, _)| *i == id).unwrap().1
+    }
+
+    fn uint(data: &[u8]) -> u64 {
+        data.iter().fold(0u64, |n, b| (n << 8) | *b as u64)
+    }
+
+    #[test]
+    fn written_file_parses_back() {
+        let avcc: [u8; 7] = [1, 100, 0, 31, 0xFF, 0xE0, 0];
+        let mut writer = MkvWriter::new(Vec::new(), MKV_CODEC_AVC);
+        writer.write_header(1170, 2532, &avcc).unwrap();
+
+        // pts in 1/600s, keyframes at 0 and 100ms
+        let samples: [(u64, bool); 5] =
+            [(0, true), (20, false), (40, false), (60, true), (80, false)];
+        for (pts, keyframe) in samples {
+            let data = [0, 0, 0, 2, 0x41, pts as u8];
+            writer
+                .write_sample(&data, &Time::new(pts + 6000, 600, 1, 0), keyframe)
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let top = children(&writer.out);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, EBML_HEADER);
+        assert_eq!(top[1].0, MKV_SEGMENT);
+
+        let ebml = children(top[0].1);
+        assert_eq!(child(&ebml, EBML_DOC_TYPE), b"matroska");
+        assert_eq!(uint(child(&ebml, EBML_DOC_TYPE_VERSION)), 4);
+
+        let segment = children(top[1].1);
+        let info = children(child(&segment, MKV_INFO));
+        assert_eq!(uint(child(&info, MKV_TIMECODE_SCALE)), TIMECODE_SCALE_NS);
+
+        let tracks = children(child(&segment, MKV_TRACKS));
+        let track = children(child(&tracks, MKV_TRACK_ENTRY));
+        assert_eq!(uint(child(&track, MKV_TRACK_NUMBER)), 1);
+        assert_eq!(child(&track, MKV_CODEC_ID), MKV_CODEC_AVC.as_bytes());
+        assert_eq!(child(&track, MKV_CODEC_PRIVATE), avcc);
+        let video = children(child(&track, MKV_VIDEO));
+        assert_eq!(uint(child(&video, MKV_PIXEL_WIDTH)), 1170);
+        assert_eq!(uint(child(&video, MKV_PIXEL_HEIGHT)), 2532);
+
+        let mut timecodes: Vec<(i64, bool)> = Vec::new();
+        let mut clusters = 0;
+        for (id, payload) in &segment {
+            if *id != MKV_CLUSTER {
+                continue;
+            }
+            clusters += 1;
+
+            let cluster = children(payload);
+            let cluster_timecode = uint(child(&cluster, MKV_TIMECODE)) as i64;
+            for (id, block) in &cluster {
+                if *id != MKV_SIMPLE_BLOCK {
+                    continue;
+                }
+                assert_eq!(block[0], 0x80 | VIDEO_TRACK_NUMBER);
+                let relative = i16::from_be_bytes([block[1], block[2]]) as i64;
+                timecodes.push((cluster_timecode + relative, block[3] & 0x80 != 0));
+            }
+        }
+
+        assert_eq!(clusters, 2);
+        assert_eq!(
+            timecodes,
+            [
+                (0, true),
+                (33, false),
+                (67, false),
+                (100, true),
+                (133, false)
+            ]
+        );
+    }
+}
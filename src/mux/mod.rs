@@ -0,0 +1,6 @@
+#[cfg(feature = "mp4")]
+pub mod fmp4;
+#[cfg(feature = "mkv")]
+pub mod mkv;
+#[cfg(feature = "mp4")]
+pub mod mp4;
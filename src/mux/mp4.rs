@@ -0,0 +1,781 @@
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+
+pub(super) const MOVIE_TIMESCALE: u32 = 1000;
+pub(super) const VIDEO_TIMESCALE: u32 = 90_000;
+pub(super) const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+// used for the last video sample when there is nothing to measure against
+pub(super) const DEFAULT_VIDEO_SAMPLE_DELTA: u32 = VIDEO_TIMESCALE / 60;
+// audio and video pts come from different device clocks, a start offset this
+// large means they aren't on a common base and an edit would only hurt
+const MAX_EDIT_OFFSET_SECS: f64 = 10f64;
+
+const LANGUAGE_UND: u16 = 0x55C4;
+const MATRIX_IDENTITY: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+pub(super) fn mp4_box(buf: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    buf.extend_from_slice(fourcc);
+    buf.extend_from_slice(payload);
+}
+
+pub(super) fn mp4_full_box(
+    buf: &mut Vec<u8>,
+    fourcc: &[u8; 4],
+    version: u8,
+    flags: u32,
+    payload: &[u8],
+) {
+    let mut full: Vec<u8> = Vec::with_capacity(payload.len() + 4);
+    full.extend_from_slice(&(((version as u32) << 24) | (flags & 0xFFFFFF)).to_be_bytes());
+    full.extend_from_slice(payload);
+    mp4_box(buf, fourcc, &full);
+}
+
+pub(super) fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub(super) fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub(super) fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub(super) fn put_matrix(buf: &mut Vec<u8>) {
+    for n in MATRIX_IDENTITY {
+        put_u32(buf, n);
+    }
+}
+
+pub(super) enum TrackFormat {
+    Video {
+        width: u32,
+        height: u32,
+        avcc: Vec<u8>,
+    },
+    Audio {
+        sample_rate: u32,
+        channels: u16,
+        bits_per_channel: u16,
+        big_endian: bool,
+    },
+}
+
+pub(super) struct Mp4Track {
+    format: TrackFormat,
+    timescale: u32,
+    first_pts: f64,
+    // per sample decode time in track timescale, video only
+    times: Vec<u64>,
+    // per sample size, empty when every sample has sample_size
+    sizes: Vec<u32>,
+    sample_size: u32,
+    sample_count: u64,
+    // 1 based sample numbers of sync samples, video only
+    sync: Vec<u32>,
+    chunk_offsets: Vec<u64>,
+    chunk_samples: Vec<u32>,
+}
+
+impl Mp4Track {
+    pub(super) fn new(format: TrackFormat, timescale: u32, first_pts: f64) -> Mp4Track {
+        Mp4Track {
+            format,
+            timescale,
+            first_pts,
+            times: Vec::new(),
+            sizes: Vec::new(),
+            sample_size: 0,
+            sample_count: 0,
+            sync: Vec::new(),
+            chunk_offsets: Vec::new(),
+            chunk_samples: Vec::new(),
+        }
+    }
+
+    // pts in seconds track times are relative to
+    pub(super) fn first_pts(&self) -> f64 {
+        self.first_pts
+    }
+
+    fn is_video(&self) -> bool {
+        match self.format {
+            TrackFormat::Video { .. } => true,
+            TrackFormat::Audio { .. } => false,
+        }
+    }
+
+    // (sample count, delta) runs for stts. every lpcm frame lasts one tick
+    // of the sample rate timescale, so audio is a single run however long
+    fn stts_entries(&self) -> Vec<(u32, u32)> {
+        let mut entries: Vec<(u32, u32)> = Vec::new();
+
+        if !self.is_video() {
+            let mut remaining = self.sample_count;
+            while remaining > 0 {
+                let count = remaining.min(u32::MAX as u64);
+                entries.push((count as u32, 1));
+                remaining -= count;
+            }
+            return entries;
+        }
+
+        let last = match self.times.windows(2).last() {
+            Some(pair) if pair[1] > pair[0] => (pair[1] - pair[0]) as u32,
+            _ => DEFAULT_VIDEO_SAMPLE_DELTA,
+        };
+
+        let deltas = self
+            .times
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as u32)
+            .chain(self.times.last().map(|_| last));
+
+        for delta in deltas {
+            match entries.last_mut() {
+                Some((count, d)) if *d == delta => *count += 1,
+                _ => entries.push((1, delta)),
+            }
+        }
+
+        entries
+    }
+
+    // media duration in track timescale
+    fn duration(&self) -> u64 {
+        if !self.is_video() {
+            return self.sample_count;
+        }
+
+        self.stts_entries()
+            .iter()
+            .map(|(count, delta)| *count as u64 * *delta as u64)
+            .sum()
+    }
+
+    fn movie_duration(&self) -> u64 {
+        self.duration() * MOVIE_TIMESCALE as u64 / self.timescale as u64
+    }
+
+    pub(super) fn write_trak(&self, buf: &mut Vec<u8>, track_id: u32, start_offset: f64) {
+        let movie_duration = self.movie_duration();
+        let empty_duration = (start_offset * MOVIE_TIMESCALE as f64).round() as u64;
+
+        let mut trak: Vec<u8> = Vec::new();
+
+        let (width, height, volume) = match &self.format {
+            TrackFormat::Video { width, height, .. } => (*width, *height, 0u16),
+            TrackFormat::Audio { .. } => (0, 0, 0x0100u16),
+        };
+
+        let mut tkhd: Vec<u8> = Vec::new();
+        put_u32(&mut tkhd, 0);
+        put_u32(&mut tkhd, 0);
+        put_u32(&mut tkhd, track_id);
+        put_u32(&mut tkhd, 0);
+        put_u32(&mut tkhd, (movie_duration + empty_duration) as u32);
+        put_u64(&mut tkhd, 0);
+        put_u16(&mut tkhd, 0);
+        put_u16(&mut tkhd, 0);
+        put_u16(&mut tkhd, volume);
+        put_u16(&mut tkhd, 0);
+        put_matrix(&mut tkhd);
+        put_u32(&mut tkhd, width << 16);
+        put_u32(&mut tkhd, height << 16);
+        // enabled | in movie
+        mp4_full_box(&mut trak, b"tkhd", 0, 3, &tkhd);
+
+        // an empty edit delays the track so both start at the same wall time.
+        // a fragmented init segment has no samples yet and no edit
+        if self.sample_count > 0 {
+            let mut elst: Vec<u8> = Vec::new();
+            put_u32(&mut elst, if empty_duration > 0 { 2 } else { 1 });
+            if empty_duration > 0 {
+                put_u32(&mut elst, empty_duration as u32);
+                put_u32(&mut elst, u32::MAX);
+                put_u32(&mut elst, 0x00010000);
+            }
+            put_u32(&mut elst, movie_duration as u32);
+            put_u32(&mut elst, 0);
+            put_u32(&mut elst, 0x00010000);
+
+            let mut edts: Vec<u8> = Vec::new();
+            mp4_full_box(&mut edts, b"elst", 0, 0, &elst);
+            mp4_box(&mut trak, b"edts", &edts);
+        }
+
+        let mut mdia: Vec<u8> = Vec::new();
+
+        // version 1 carries 64 bit times, 48kHz audio outgrows 32 bit after
+        // a bit more than a day
+        let duration = self.duration();
+        let mdhd_version = match duration > u32::MAX as u64 {
+            true => 1,
+            false => 0,
+        };
+
+        let mut mdhd: Vec<u8> = Vec::new();
+        match mdhd_version {
+            1 => {
+                put_u64(&mut mdhd, 0);
+                put_u64(&mut mdhd, 0);
+                put_u32(&mut mdhd, self.timescale);
+                put_u64(&mut mdhd, duration);
+            }
+            _ => {
+                put_u32(&mut mdhd, 0);
+                put_u32(&mut mdhd, 0);
+                put_u32(&mut mdhd, self.timescale);
+                put_u32(&mut mdhd, duration as u32);
+            }
+        };
+        put_u16(&mut mdhd, LANGUAGE_UND);
+        put_u16(&mut mdhd, 0);
+        mp4_full_box(&mut mdia, b"mdhd", mdhd_version, 0, &mdhd);
+
+        let (handler, name): (&[u8; 4], &[u8]) = match self.is_video() {
+            true => (b"vide", b"VideoHandler\0"),
+            false => (b"soun", b"SoundHandler\0"),
+        };
+
+        let mut hdlr: Vec<u8> = Vec::new();
+        put_u32(&mut hdlr, 0);
+        hdlr.extend_from_slice(handler);
+        put_u32(&mut hdlr, 0);
+        put_u32(&mut hdlr, 0);
+        put_u32(&mut hdlr, 0);
+        hdlr.extend_from_slice(name);
+        mp4_full_box(&mut mdia, b"hdlr", 0, 0, &hdlr);
+
+        let mut minf: Vec<u8> = Vec::new();
+
+        match self.is_video() {
+            true => mp4_full_box(&mut minf, b"vmhd", 0, 1, &[0; 8]),
+            false => mp4_full_box(&mut minf, b"smhd", 0, 0, &[0; 4]),
+        };
+
+        let mut dref: Vec<u8> = Vec::new();
+        put_u32(&mut dref, 1);
+        // self contained
+        mp4_full_box(&mut dref, b"url ", 0, 1, &[]);
+        let mut dinf: Vec<u8> = Vec::new();
+        mp4_full_box(&mut dinf, b"dref", 0, 0, &dref);
+        mp4_box(&mut minf, b"dinf", &dinf);
+
+        let mut stbl: Vec<u8> = Vec::new();
+        self.write_stsd(&mut stbl);
+        self.write_stts(&mut stbl);
+
+        if self.is_video() {
+            let mut stss: Vec<u8> = Vec::new();
+            put_u32(&mut stss, self.sync.len() as u32);
+            for n in &self.sync {
+                put_u32(&mut stss, *n);
+            }
+            mp4_full_box(&mut stbl, b"stss", 0, 0, &stss);
+        }
+
+        self.write_stsc(&mut stbl);
+
+        let mut stsz: Vec<u8> = Vec::new();
+        put_u32(&mut stsz, self.sample_size);
+        put_u32(&mut stsz, self.sample_count as u32);
+        for size in &self.sizes {
+            put_u32(&mut stsz, *size);
+        }
+        mp4_full_box(&mut stbl, b"stsz", 0, 0, &stsz);
+
+        let mut co64: Vec<u8> = Vec::new();
+        put_u32(&mut co64, self.chunk_offsets.len() as u32);
+        for offset in &self.chunk_offsets {
+            put_u64(&mut co64, *offset);
+        }
+        mp4_full_box(&mut stbl, b"co64", 0, 0, &co64);
+
+        mp4_box(&mut minf, b"stbl", &stbl);
+        mp4_box(&mut mdia, b"minf", &minf);
+        mp4_box(&mut trak, b"mdia", &mdia);
+
+        mp4_box(buf, b"trak", &trak);
+    }
+
+    fn write_stsd(&self, buf: &mut Vec<u8>) {
+        let mut entry: Vec<u8> = Vec::new();
+        // reserved + data reference index
+        entry.extend_from_slice(&[0; 6]);
+        put_u16(&mut entry, 1);
+
+        let fourcc = match &self.format {
+            TrackFormat::Video {
+                width,
+                height,
+                avcc,
+            } => {
+                entry.extend_from_slice(&[0; 16]);
+                put_u16(&mut entry, *width as u16);
+                put_u16(&mut entry, *height as u16);
+                // 72 dpi
+                put_u32(&mut entry, 0x00480000);
+                put_u32(&mut entry, 0x00480000);
+                put_u32(&mut entry, 0);
+                // frame count
+                put_u16(&mut entry, 1);
+                // compressor name
+                entry.extend_from_slice(&[0; 32]);
+                put_u16(&mut entry, 0x0018);
+                put_u16(&mut entry, 0xFFFF);
+                mp4_box(&mut entry, b"avcC", avcc);
+                b"avc1"
+            }
+            TrackFormat::Audio {
+                sample_rate,
+                channels,
+                bits_per_channel,
+                big_endian,
+            } => {
+                // version, revision, vendor
+                entry.extend_from_slice(&[0; 8]);
+                put_u16(&mut entry, *channels);
+                put_u16(&mut entry, *bits_per_channel);
+                // compression id, packet size
+                put_u32(&mut entry, 0);
+                put_u32(&mut entry, sample_rate << 16);
+                // signed integer pcm, byte order as the device sends it
+                match big_endian {
+                    true => b"twos",
+                    false => b"sowt",
+                }
+            }
+        };
+
+        let mut stsd: Vec<u8> = Vec::new();
+        put_u32(&mut stsd, 1);
+        mp4_box(&mut stsd, fourcc, &entry);
+        mp4_full_box(buf, b"stsd", 0, 0, &stsd);
+    }
+
+    fn write_stts(&self, buf: &mut Vec<u8>) {
+        let entries = self.stts_entries();
+
+        let mut stts: Vec<u8> = Vec::new();
+        put_u32(&mut stts, entries.len() as u32);
+        for (count, delta) in entries {
+            put_u32(&mut stts, count);
+            put_u32(&mut stts, delta);
+        }
+        mp4_full_box(buf, b"stts", 0, 0, &stts);
+    }
+
+    fn write_stsc(&self, buf: &mut Vec<u8>) {
+        let mut entries: Vec<(u32, u32)> = Vec::new();
+
+        for (i, samples) in self.chunk_samples.iter().enumerate() {
+            match entries.last() {
+                Some((_, s)) if s == samples => {}
+                _ => entries.push((i as u32 + 1, *samples)),
+            }
+        }
+
+        let mut stsc: Vec<u8> = Vec::new();
+        put_u32(&mut stsc, entries.len() as u32);
+        for (first_chunk, samples) in entries {
+            put_u32(&mut stsc, first_chunk);
+            put_u32(&mut stsc, samples);
+            put_u32(&mut stsc, 1);
+        }
+        mp4_full_box(buf, b"stsc", 0, 0, &stsc);
+    }
+}
+
+// mp4 with one avc video and one lpcm audio track. samples go straight into a
+// single mdat, the moov is written by finish() once all sample tables are known
+pub struct Mp4Writer<W: Write + Seek> {
+    out: W,
+    offset: u64,
+    mdat_start: Option<u64>,
+    video: Option<Mp4Track>,
+    audio: Option<Mp4Track>,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    pub fn new(out: W) -> Mp4Writer<W> {
+        Mp4Writer {
+            out,
+            offset: 0,
+            mdat_start: None,
+            video: None,
+            audio: None,
+        }
+    }
+
+    // pts in seconds of the first sample of each track, the gap between them
+    // is written into the edit lists
+    pub fn first_video_pts(&self) -> Option<f64> {
+        self.video.as_ref().map(|t| t.first_pts)
+    }
+
+    pub fn first_audio_pts(&self) -> Option<f64> {
+        self.audio.as_ref().map(|t| t.first_pts)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.out.write_all(data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn start_mdat(&mut self) -> Result<(), Error> {
+        if self.mdat_start.is_some() {
+            return Ok(());
+        }
+
+        let mut ftyp: Vec<u8> = Vec::new();
+        let mut brands: Vec<u8> = Vec::new();
+        brands.extend_from_slice(b"isom");
+        put_u32(&mut brands, 0x200);
+        brands.extend_from_slice(b"isomiso2avc1mp41");
+        mp4_box(&mut ftyp, b"ftyp", &brands);
+
+        match self.write_all(&ftyp) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.mdat_start = Some(self.offset);
+
+        // 64 bit size, patched in finish
+        let mut mdat: Vec<u8> = Vec::new();
+        put_u32(&mut mdat, 1);
+        mdat.extend_from_slice(b"mdat");
+        put_u64(&mut mdat, 0);
+
+        self.write_all(&mdat)
+    }
+
+    pub fn write_sample_buffer(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        match sample_buffer.media_type() {
+            MEDIA_TYPE_VIDEO => self.write_video(sample_buffer),
+            MEDIA_TYPE_SOUND => self.write_audio(sample_buffer),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_video(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let pts = match sample_buffer.presentation_time_stamp() {
+            Some(e) => e.as_secs_f64(),
+            None => return Err(Error::new(ErrorKind::InvalidData, "sample without pts")),
+        };
+
+        if self.video.is_none() {
+            let format = match sample_buffer.format_description() {
+                Some(fd) => match fd.avc1() {
+                    Some(avc1) => TrackFormat::Video {
+                        width: fd.video_dimension_width(),
+                        height: fd.video_dimension_height(),
+                        avcc: Vec::from(avc1.avcc()),
+                    },
+                    None => return Ok(()),
+                },
+                // can't decode anything before the parameter sets arrive
+                None => return Ok(()),
+            };
+
+            self.video = Some(Mp4Track::new(format, VIDEO_TIMESCALE, pts));
+        }
+
+        match self.start_mdat() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let offset = self.offset;
+
+        match self.write_all(data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let track = self.video.as_mut().unwrap();
+
+        let time = ((pts - track.first_pts).max(0f64) * VIDEO_TIMESCALE as f64).round() as u64;
+        // decode times must not go backwards
+        let time = match track.times.last() {
+            Some(last) => time.max(*last),
+            None => time,
+        };
+
+        track.times.push(time);
+        track.sizes.push(data.len() as u32);
+        track.sample_count += 1;
+        if sample_buffer.is_sync_sample() {
+            track.sync.push(track.sample_count as u32);
+        }
+        track.chunk_offsets.push(offset);
+        track.chunk_samples.push(1);
+
+        Ok(())
+    }
+
+    fn write_audio(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if self.audio.is_none() {
+            let pts = match sample_buffer.output_presentation_time_stamp() {
+                Some(e) => e.as_secs_f64(),
+                None => return Err(Error::new(ErrorKind::InvalidData, "sample without pts")),
+            };
+
+            let default_asbd = AudioStreamDescription::default();
+            let asbd = match sample_buffer.format_description() {
+                Some(fd) => fd.audio_stream_description(),
+                None => &default_asbd,
+            };
+
+            // sowt/twos only carry integer samples
+            if asbd.is_float() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "float lpcm not supported",
+                ));
+            }
+
+            let format = TrackFormat::Audio {
+                sample_rate: asbd.sample_rate() as u32,
+                channels: asbd.channels_per_frame() as u16,
+                bits_per_channel: asbd.bits_per_channel() as u16,
+                big_endian: asbd.is_big_endian(),
+            };
+
+            let mut track = Mp4Track::new(format, asbd.sample_rate() as u32, pts);
+            track.sample_size = asbd.bytes_per_frame();
+
+            if track.sample_size == 0 || track.timescale == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "audio stream description",
+                ));
+            }
+
+            self.audio = Some(track);
+        }
+
+        match self.start_mdat() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let frame_size = self.audio.as_ref().unwrap().sample_size as usize;
+        // drop a trailing partial frame, lpcm samples are fixed size
+        let data = &data[..data.len() - data.len() % frame_size];
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let offset = self.offset;
+
+        match self.write_all(data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let track = self.audio.as_mut().unwrap();
+        let frames = (data.len() / frame_size) as u32;
+
+        track.sample_count += frames as u64;
+        track.chunk_offsets.push(offset);
+        track.chunk_samples.push(frames);
+
+        Ok(())
+    }
+
+    // offset of each track from the earliest track start, in seconds
+    fn start_offsets(&self) -> (f64, f64) {
+        let (video, audio) = match (&self.video, &self.audio) {
+            (Some(v), Some(a)) => (v.first_pts, a.first_pts),
+            _ => return (0f64, 0f64),
+        };
+
+        if (video - audio).abs() > MAX_EDIT_OFFSET_SECS {
+            log::debug!(
+                "mp4 audio/video start differ by {}s, not aligning",
+                video - audio
+            );
+            return (0f64, 0f64);
+        }
+
+        let origin = video.min(audio);
+
+        (video - origin, audio - origin)
+    }
+
+    pub fn finish(&mut self) -> Result<(), Error> {
+        let mdat_start = match self.mdat_start {
+            Some(e) => e,
+            // nothing was written
+            None => return self.out.flush(),
+        };
+
+        match self.out.seek(SeekFrom::Start(mdat_start + 8)) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self
+            .out
+            .write_all(&(self.offset - mdat_start).to_be_bytes())
+        {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.out.seek(SeekFrom::Start(self.offset)) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let (video_offset, audio_offset) = self.start_offsets();
+
+        let mut traks: Vec<u8> = Vec::new();
+        let mut duration: u64 = 0;
+
+        match &self.video {
+            Some(track) => {
+                track.write_trak(&mut traks, VIDEO_TRACK_ID, video_offset);
+                duration = duration
+                    .max(track.movie_duration() + (video_offset * MOVIE_TIMESCALE as f64) as u64);
+            }
+            None => {}
+        };
+
+        match &self.audio {
+            Some(track) => {
+                track.write_trak(&mut traks, AUDIO_TRACK_ID, audio_offset);
+                duration = duration
+                    .max(track.movie_duration() + (audio_offset * MOVIE_TIMESCALE as f64) as u64);
+            }
+            None => {}
+        };
+
+        let mut mvhd: Vec<u8> = Vec::new();
+        put_u32(&mut mvhd, 0);
+        put_u32(&mut mvhd, 0);
+        put_u32(&mut mvhd, MOVIE_TIMESCALE);
+        put_u32(&mut mvhd, duration as u32);
+        // rate 1.0, volume 1.0
+        put_u32(&mut mvhd, 0x00010000);
+        put_u16(&mut mvhd, 0x0100);
+        mvhd.extend_from_slice(&[0; 10]);
+        put_matrix(&mut mvhd);
+        mvhd.extend_from_slice(&[0; 24]);
+        put_u32(&mut mvhd, AUDIO_TRACK_ID + 1);
+
+        let mut moov: Vec<u8> = Vec::new();
+        mp4_full_box(&mut moov, b"mvhd", 0, 0, &mvhd);
+        moov.extend_from_slice(&traks);
+
+        let mut buf: Vec<u8> = Vec::new();
+        mp4_box(&mut buf, b"moov", &moov);
+
+        match self.write_all(&buf) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio_track(sample_count: u64) -> Mp4Track {
+        let format = TrackFormat::Audio {
+            sample_rate: 48000,
+            channels: 2,
+            bits_per_channel: 16,
+            big_endian: false,
+        };
+        let mut track = Mp4Track::new(format, 48000, 0f64);
+        track.sample_count = sample_count;
+        track.sample_size = 4;
+        track
+    }
+
+    // payload of the first box with the fourcc, after its size and type
+    fn find_box<'a>(buf: &'a [u8], fourcc: &[u8; 4]) -> &'a [u8] {
+        let pos = buf.windows(4).position(|w| w == fourcc).unwrap();
+        let size = u32::from_be_bytes([buf[pos - 4], buf[pos - 3], buf[pos - 2], buf[pos - 1]]);
+        &buf[pos + 4..pos - 4 + size as usize]
+    }
+
+    #[test]
+    fn audio_stts_is_one_run() {
+        let track = audio_track(48000 * 60);
+        assert_eq!(track.stts_entries(), [(48000 * 60, 1)]);
+        assert_eq!(track.duration(), 48000 * 60);
+
+        let mut buf: Vec<u8> = Vec::new();
+        track.write_trak(&mut buf, AUDIO_TRACK_ID, 0f64);
+
+        let mdhd = find_box(&buf, b"mdhd");
+        assert_eq!(mdhd[0], 0);
+        assert_eq!(&mdhd[16..20], &(48000u32 * 60).to_be_bytes());
+
+        let stts = find_box(&buf, b"stts");
+        assert_eq!(stts.len(), 4 + 4 + 8);
+    }
+
+    #[test]
+    fn long_audio_uses_mdhd_version_1() {
+        // 30 hours of 48kHz frames don't fit 32 bits
+        let frames = 48000u64 * 3600 * 30;
+        let track = audio_track(frames);
+
+        let entries = track.stts_entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.iter().map(|(n, _)| *n as u64).sum::<u64>(), frames);
+
+        let mut buf: Vec<u8> = Vec::new();
+        track.write_trak(&mut buf, AUDIO_TRACK_ID, 0f64);
+
+        let mdhd = find_box(&buf, b"mdhd");
+        assert_eq!(mdhd[0], 1);
+        assert_eq!(&mdhd[20..24], &48000u32.to_be_bytes());
+        assert_eq!(&mdhd[24..32], &frames.to_be_bytes());
+    }
+
+    #[test]
+    fn video_stts_runs() {
+        let format = TrackFormat::Video {
+            width: 1170,
+            height: 2532,
+            avcc: Vec::new(),
+        };
+        let mut track = Mp4Track::new(format, VIDEO_TIMESCALE, 0f64);
+        track.times = vec![0, 1500, 3000, 4500, 7500];
+        track.sample_count = track.times.len() as u64;
+
+        assert_eq!(track.stts_entries(), [(3, 1500), (2, 3000)]);
+        assert_eq!(track.duration(), 10500);
+    }
+}
@@ -1,22 +1,94 @@
-use crate::apple::AppleDevice;
-use crate::coremedia::clock::Clock;
+use crate::apple::{TransferStats, UsbTransport};
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::clock::{Clock, HostTimeSource, TimeSource};
+use crate::coremedia::format_desc::{FormatDescriptor, Orientation};
 use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
 use crate::coremedia::time::Time;
-use crate::qt_device::{qt_hpa1_device_info, qt_hpd1_device_info};
+use crate::qt_caps::DeviceCapabilities;
+use crate::qt_device::{qt_display_size, qt_hpa1_device_info, qt_hpd1_device_info};
 use crate::qt_pkt;
 use crate::qt_pkt::{
     QTPacket, QTPacketAFMT, QTPacketASYN, QTPacketCLOCK, QTPacketSKEW, QTPacketSTOP, QTPacketTIME,
 };
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{BufRead, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use crate::qt_stats::QTStats;
+use crate::qt_value::QTValue;
+use crate::retry::RetryPolicy;
+use crate::sink::SampleSink;
+use std::cell::Cell;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Cursor, Error, ErrorKind, Seek, SeekFrom, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::SyncSender;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how samples are handed to the channel once it is full
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SendMode {
+    // wait for the consumer, usb reads stall until there is room
+    Block,
+    // drop the incoming sample and count it in stats
+    DropNewest,
+}
+
+// The host answers CWPA/CVRP/CLOK with clock refs of its own. The device only
+// uses them as opaque ids to address our clocks later (TIME/SKEW/NEED), so
+// they are derived from the device ref plus an offset that keeps them unique.
+// The values come from reverse engineering (quicktime_video_hack) and are
+// known to work, changing them may stop the device from streaming.
+const CWPA_CLOCK_REF_OFFSET: u64 = 1000;
+const CVRP_CLOCK_REF_OFFSET: u64 = 0x1000AF;
+const CLOK_CLOCK_REF_OFFSET: u64 = 0x10000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClockRefOffsets {
+    // local audio clock id replied to CWPA
+    pub cwpa: u64,
+    // video clock ref replied to CVRP
+    pub cvrp: u64,
+    // host clock id replied to CLOK
+    pub clok: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClockRole {
+    // CWPA, answered with the local audio clock
+    Audio,
+    // CVRP, the clock NEED goes to
+    Video,
+    // CLOK, the host clock TIME/SKEW read
+    Host,
+}
+
+// one clock ref pair of the handshake: the ref the device sent and the one we
+// replied with, device + ClockRefOffsets for the role
+#[derive(Clone, Copy, Debug)]
+pub struct ClockRefMapping {
+    pub role: ClockRole,
+    pub device_clock_ref: u64,
+    pub host_clock_ref: u64,
+}
+
+impl Default for ClockRefOffsets {
+    fn default() -> Self {
+        ClockRefOffsets {
+            cwpa: CWPA_CLOCK_REF_OFFSET,
+            cvrp: CVRP_CLOCK_REF_OFFSET,
+            clok: CLOK_CLOCK_REF_OFFSET,
+        }
+    }
+}
 
 pub struct QuickTime {
-    device: AppleDevice,
+    device: Box<dyn UsbTransport>,
     term: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    ping_request: Arc<AtomicBool>,
     clock: Option<Clock>,
+    // answers TIME that came before CLOK, never counts as the host clock
+    time_clock: Option<Clock>,
     need_clock_ref: Option<u64>,
     local_audio_clock: Option<Clock>,
     device_audio_clock: Option<u64>,
@@ -26,8 +98,82 @@ pub struct QuickTime {
     last_eat_frame_received_device_audio_clock: Option<Time>,
     packet_pool: Cursor<Vec<u8>>,
     tx: SyncSender<Result<SampleBuffer, Error>>,
+    audio_tx: Option<SyncSender<Result<SampleBuffer, Error>>>,
+    send_mode: SendMode,
+    stats: Arc<QTStats>,
+    watchdog: Option<Duration>,
+    restart_retry: RetryPolicy,
+    last_data: Instant,
+    // last bulk read brought no bytes (timeout or zlp)
+    bulk_empty: bool,
+    idle_backoff: Duration,
+    read_buffer: Vec<u8>,
+    read_buffer_size: usize,
+    clock_ref_offsets: ClockRefOffsets,
+    last_feed: Option<Instant>,
+    og_value: Option<u32>,
+    audio_format: Option<AudioStreamDescription>,
+    // last sound format seen inline or built from AFMT, given to EAT samples
+    // that come without one
+    audio_format_desc: Option<FormatDescriptor>,
+    sample_properties: Vec<QTValue>,
+    clock_ref_map: Vec<ClockRefMapping>,
+    // clock ref -> base clock ref from TBAS
+    time_bases: Vec<(u64, u64)>,
+    capabilities: DeviceCapabilities,
+    cvrp_format: Option<FormatDescriptor>,
+    cvrp_format_primed: bool,
+    // last video format from CVRP or a FEED
+    video_format: Option<FormatDescriptor>,
+    attach_video_format: bool,
+    orientation: Option<Orientation>,
+    session_established: bool,
+    on_session_established: Option<Box<dyn FnMut() + Send>>,
+    on_progress: Option<Box<dyn FnMut(HandshakeProgress) + Send>>,
+    reset_on_init: bool,
+    // HPD1/HPA1 dictionaries replacing the built in ones
+    display_device_info: Option<QTValue>,
+    audio_device_info: Option<QTValue>,
+    display_info_overrides: Vec<(String, QTValue)>,
+    display_size: Option<(u32, u32)>,
+    frame_rate_hint: Option<f64>,
+    // video dimensions of the last format seen, kept across restarts
+    device_display_size: Option<(u32, u32)>,
+    audio_info_overrides: Vec<(String, QTValue)>,
+    time_source: Arc<dyn TimeSource>,
+    // header and send time of the ping send_ping is waiting on
+    host_ping: Cell<Option<(u64, Instant)>>,
+    host_ping_seq: Cell<u32>,
+}
+
+// timings measured by QuickTime::self_test from the start of the handshake
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTestReport {
+    pub clock: Duration,
+    pub first_feed: Duration,
 }
 
+// upper bound for a single packet and so for bytes waiting in the pool,
+// well above the largest keyframe a device sends
+const MAX_PACKET_POOL_SIZE: usize = 16 * 1024 * 1024;
+
+// one bulk read covers many endpoint packets, the host controller splits it
+const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// run_with_sink drains after every packet, only the final pool drain can
+// queue up more than one sample
+const SINK_CHANNEL_SIZE: usize = 256;
+
+const DEFAULT_IDLE_BACKOFF: Duration = Duration::from_millis(1);
+
+// clock ref and magic, sync adds the correlation id
+const ASYN_HEADER_LEN: u64 = 12;
+const SYNC_HEADER_LEN: u64 = 20;
+
+// high half of the header of pings sent by send_ping, the device's own
+// pings carry 1 there
+const HOST_PING_MARKER: u64 = 0x5154_0000_0000_0000;
+
 const HPD1: u32 = 0x68706431;
 const HPA1: u32 = 0x68706131;
 const HPD0: u32 = 0x68706430;
@@ -35,6 +181,98 @@ const HPA0: u32 = 0x68706130;
 const NEED: u32 = 0x6E656564;
 const EMPTY_CF_TYPE: u64 = 1;
 
+// what the packet parsers return for a payload that doesn't parse
+fn is_malformed(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof)
+}
+
+// whether data begins with a plausible packet header: a length within the
+// pool cap followed by one of the top level magics. incomplete headers pass,
+// there is nothing to judge yet
+pub(crate) fn is_packet_start(data: &[u8]) -> bool {
+    if data.len() < 4 {
+        return true;
+    }
+
+    let pkt_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+
+    if !(8..=MAX_PACKET_POOL_SIZE).contains(&pkt_len) {
+        return false;
+    }
+
+    if data.len() < 8 {
+        return true;
+    }
+
+    matches!(
+        u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+        qt_pkt::PACKET_MAGIC_PING | qt_pkt::PACKET_MAGIC_SYNC | qt_pkt::PACKET_MAGIC_ASYN
+    )
+}
+
+// handshake milestones in the order they normally happen, CVRP and CLOK may
+// come the other way round
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HandshakeProgress {
+    EnablingQuickTime,
+    ClaimingInterface,
+    // usb side ready, the device starts with PING and CWPA
+    WaitingForCwpa,
+    // CWPA, audio clocks set up and device info sent
+    AudioClock,
+    // CVRP, the video clock is known
+    VideoClock,
+    // CLOK, the host clock is running
+    ClockEstablished,
+    FirstFrame,
+}
+
+// the device shows no QuickTime interface (vendor class, subclass 0x2a),
+// it isn't or is no longer in QuickTime mode. init returns it inside an
+// io::Error of kind NotFound, enable qt mode with set_qt_enabled(true) and
+// try again
+#[derive(Debug)]
+pub struct QuickTimeInterfaceMissing;
+
+impl Display for QuickTimeInterfaceMissing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("quicktime interface missing, device not in qt mode")
+    }
+}
+
+impl std::error::Error for QuickTimeInterfaceMissing {}
+
+// tell QuickTimeInterfaceMissing apart from other NotFound errors
+pub fn is_quicktime_interface_missing(e: &Error) -> bool {
+    match e.get_ref() {
+        Some(inner) => inner.is::<QuickTimeInterfaceMissing>(),
+        None => false,
+    }
+}
+
+// the interface is there but another process holds it, QuickTime Player or
+// a capture that didn't exit cleanly
+#[derive(Debug)]
+pub struct QuickTimeInterfaceBusy(pub rusb::Error);
+
+impl Display for QuickTimeInterfaceBusy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "quicktime interface busy, claimed by another process ({})",
+            self.0
+        ))
+    }
+}
+
+impl std::error::Error for QuickTimeInterfaceBusy {}
+
+pub fn is_quicktime_interface_busy(e: &Error) -> bool {
+    match e.get_ref() {
+        Some(inner) => inner.is::<QuickTimeInterfaceBusy>(),
+        None => false,
+    }
+}
+
 impl AsRef<QuickTime> for QuickTime {
     fn as_ref(&self) -> &QuickTime {
         self
@@ -42,13 +280,19 @@ impl AsRef<QuickTime> for QuickTime {
 }
 
 impl QuickTime {
-    pub fn new(device: AppleDevice, tx: SyncSender<Result<SampleBuffer, Error>>) -> QuickTime {
+    pub fn new<D: UsbTransport + 'static>(
+        device: D,
+        tx: SyncSender<Result<SampleBuffer, Error>>,
+    ) -> QuickTime {
         // let (close_tx, close_rx): (Sender<()>, Receiver<()>) = mpsc::channel();
 
         return QuickTime {
-            device,
+            device: Box::new(device),
             term: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            ping_request: Arc::new(AtomicBool::new(false)),
             clock: None,
+            time_clock: None,
             need_clock_ref: None,
             local_audio_clock: None,
             device_audio_clock: None,
@@ -58,6 +302,44 @@ impl QuickTime {
             last_eat_frame_received_device_audio_clock: None,
             packet_pool: Cursor::new(Vec::new()),
             tx,
+            audio_tx: None,
+            send_mode: SendMode::Block,
+            stats: Arc::new(QTStats::new()),
+            watchdog: None,
+            restart_retry: RetryPolicy::no_retry(),
+            last_data: Instant::now(),
+            bulk_empty: false,
+            idle_backoff: DEFAULT_IDLE_BACKOFF,
+            read_buffer: Vec::new(),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            clock_ref_offsets: ClockRefOffsets::default(),
+            last_feed: None,
+            og_value: None,
+            audio_format: None,
+            audio_format_desc: None,
+            sample_properties: Vec::new(),
+            time_bases: Vec::new(),
+            clock_ref_map: Vec::new(),
+            capabilities: DeviceCapabilities::new(),
+            cvrp_format: None,
+            cvrp_format_primed: false,
+            video_format: None,
+            attach_video_format: false,
+            orientation: None,
+            session_established: false,
+            on_session_established: None,
+            on_progress: None,
+            reset_on_init: false,
+            display_device_info: None,
+            audio_device_info: None,
+            display_info_overrides: Vec::new(),
+            display_size: None,
+            frame_rate_hint: None,
+            device_display_size: None,
+            audio_info_overrides: Vec::new(),
+            time_source: Arc::new(HostTimeSource),
+            host_ping: Cell::new(None),
+            host_ping_seq: Cell::new(0),
             // close_tx,
             // close_rx,
         };
@@ -67,257 +349,924 @@ impl QuickTime {
         return &self.term;
     }
 
-    pub fn init(&mut self) -> Result<(), Error> {
-        self.device.set_qt_enabled(true).expect("set qt enabled");
+    // while set, samples are parsed and NEED is still sent so the device
+    // keeps streaming, but nothing goes down the channel. the handle can be
+    // cloned before qt moves to the loop thread, like term
+    pub fn paused(&self) -> &Arc<AtomicBool> {
+        &self.paused
+    }
 
-        match self.device.claim_interface() {
-            Some(_) => return Err(Error::new(ErrorKind::Other, "claim interface")),
-            _ => {}
-        };
+    // store true to have the loop send_ping on its next round, from any
+    // thread while run() is going. the echo is read and timed by the loop,
+    // stats().ping_rtt() has the round trip once it came back
+    pub fn ping_request(&self) -> &Arc<AtomicBool> {
+        &self.ping_request
+    }
 
-        match self.device.init_bulk_endpoint() {
-            Some(_) => return Err(Error::new(ErrorKind::Other, "init bulk endpoint")),
-            _ => {}
-        };
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
 
-        match self.device.clear_feature() {
-            Some(_) => return Err(Error::new(ErrorKind::Other, "clear feature")),
-            _ => {}
-        };
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
 
-        Ok(())
+    pub fn stats(&self) -> &Arc<QTStats> {
+        return &self.stats;
     }
 
-    fn read(&mut self) -> Result<Option<QTPacket>, Error> {
-        let mut buffer: Vec<u8> = vec![0; self.device.max_read_packet_size() as usize];
-        let buffer_size = match self.device.read_bulk(&mut buffer) {
-            Ok(e) => e,
-            Err(e) => {
-                return Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    format!("read bulk {}", e),
-                ))
-            }
-        };
+    // usb level counters, compare with stats() to tell transfer problems
+    // from parsing/consumer ones
+    pub fn transfer_stats(&self) -> TransferStats {
+        self.device.transfer_stats()
+    }
 
-        if buffer_size <= 0 {
-            return Ok(None);
-        }
+    pub fn set_send_mode(&mut self, mode: SendMode) {
+        self.send_mode = mode;
+    }
 
-        self.packet_pool
-            .seek(SeekFrom::End(0))
-            .expect("packet pool seek to end");
+    pub fn clock_ref_offsets(&self) -> ClockRefOffsets {
+        self.clock_ref_offsets
+    }
 
-        match self.packet_pool.write(&buffer[..buffer_size]) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
+    // for experimenting only, see ClockRefOffsets
+    pub fn set_clock_ref_offsets(&mut self, offsets: ClockRefOffsets) {
+        self.clock_ref_offsets = offsets;
+    }
 
-        self.packet_pool
-            .seek(SeekFrom::Start(0))
-            .expect("packet pool seek to start");
+    pub fn need_clock_ref(&self) -> Option<u64> {
+        self.need_clock_ref
+    }
 
-        let pkt_len = match self.packet_pool.read_u32::<LittleEndian>() {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    pub fn device_audio_clock(&self) -> Option<u64> {
+        self.device_audio_clock
+    }
 
-        let pool_len = self
-            .packet_pool
-            .seek(SeekFrom::End(0))
-            .expect("packet pool seek to end");
+    pub fn host_clock_id(&self) -> Option<u64> {
+        self.clock.as_ref().map(Clock::id)
+    }
 
-        if pool_len >= pkt_len as u64 {
-            self.packet_pool
-                .seek(SeekFrom::Start(0))
-                .expect("packet pool seek to start");
+    pub fn local_audio_clock_id(&self) -> Option<u64> {
+        self.local_audio_clock.as_ref().map(Clock::id)
+    }
 
-            let mut pkt_buffer: Vec<u8> = vec![0; pkt_len as usize];
-            self.packet_pool
-                .read_exact(&mut pkt_buffer)
-                .expect("packet pool read");
+    // value the device sent in the OG packet of the current session
+    pub fn og_value(&self) -> Option<u32> {
+        self.og_value
+    }
 
-            let pkt = QTPacket::from_bytes(&pkt_buffer).expect("qt packet from bytes");
+    // lpcm format the device announced in AFMT, what EAT sample data is in
+    pub fn audio_format(&self) -> Option<&AudioStreamDescription> {
+        self.audio_format.as_ref()
+    }
 
-            let remain = self.packet_pool.fill_buf().expect("remain");
+    // sound format attached to EAT samples that don't carry their own
+    pub fn audio_format_description(&self) -> Option<&FormatDescriptor> {
+        self.audio_format_desc.as_ref()
+    }
 
-            self.packet_pool = Cursor::new(Vec::from(remain));
+    // key/value pairs the device sent in SPRP packets of the current session
+    pub fn sample_properties(&self) -> &[QTValue] {
+        &self.sample_properties
+    }
 
-            return Ok(Some(pkt));
-        }
+    // everything the device advertised in the current session
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
 
-        Ok(None)
+    // device clock ref -> the host clock ref replied for it, one entry per
+    // role as the handshake gets there. a released clock is removed
+    pub fn clock_ref_map(&self) -> &[ClockRefMapping] {
+        &self.clock_ref_map
     }
 
-    fn write(&self, data: &mut QTPacket) -> Result<usize, Error> {
-        let buf = match data.as_bytes() {
-            Ok(d) => d,
-            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "packet as_bytes")),
-        };
+    // host ref we replied for a device clock ref
+    pub fn host_clock_ref(&self, device_clock_ref: u64) -> Option<u64> {
+        self.clock_ref_map
+            .iter()
+            .find(|m| m.device_clock_ref == device_clock_ref)
+            .map(|m| m.host_clock_ref)
+    }
 
-        match self.device.write_bulk(buf) {
-            Ok(e) => Ok(e),
-            Err(e) => Err(Error::new(
-                ErrorKind::BrokenPipe,
-                format!("write bulk {}", e),
-            )),
-        }
+    fn map_clock_ref(&mut self, role: ClockRole, device_clock_ref: u64, host_clock_ref: u64) {
+        log::debug!(
+            "{:?} clock {:#x} -> {:#x}",
+            role,
+            device_clock_ref,
+            host_clock_ref
+        );
+
+        self.clock_ref_map.retain(|m| m.role != role);
+        self.clock_ref_map.push(ClockRefMapping {
+            role,
+            device_clock_ref,
+            host_clock_ref,
+        });
     }
 
-    fn handle_pkt(&mut self, pkt: &mut QTPacket, sync: bool) -> Result<(), Error> {
-        let clock_ref = match pkt.read_u64() {
-            Err(e) => return Err(e),
-            Ok(e) => e,
-        };
+    // base clock the device announced in TBAS for clock_ref
+    pub fn time_base(&self, clock_ref: u64) -> Option<u64> {
+        self.time_bases
+            .iter()
+            .find(|(c, _)| *c == clock_ref)
+            .map(|(_, base)| *base)
+    }
 
-        let magic = match pkt.read_u32() {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    // whether EAT and FEED timestamps are on the same base, so the tracks
+    // can be muxed without offsetting one against the other. None until TBAS
+    // arrived for both clocks
+    pub fn shared_time_base(&self) -> Option<bool> {
+        let audio = self.time_base(self.device_audio_clock?)?;
+        let video = self.time_base(self.need_clock_ref?)?;
 
-        match sync {
-            true => {
-                let correlation_id = match pkt.read_u64() {
-                    Ok(m) => m,
-                    Err(e) => return Err(e),
-                };
-                self.handle_sync_pkt(pkt, clock_ref, magic, correlation_id)
-            }
-            false => self.handle_asyn_pkt(pkt, clock_ref, magic),
-        }
+        Some(audio == video)
     }
 
-    fn handle_sync_pkt(
-        &mut self,
-        pkt: &mut QTPacket,
-        clock_ref: u64,
-        magic: u32,
-        correlation_id: u64,
-    ) -> Result<(), Error> {
-        match magic {
-            qt_pkt::SYNC_PACKET_MAGIC_OG => {
-                let og_pkt = match qt_pkt::QTPacketOG::from_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    // video format the device put in the CVRP payload, None when it only
+    // sends it inline with the samples
+    pub fn cvrp_format(&self) -> Option<&FormatDescriptor> {
+        self.cvrp_format.as_ref()
+    }
 
-                let mut reply_packet = match og_pkt.reply_packet(correlation_id) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    // most recent video format of the session, FEEDs after the first one
+    // usually leave it out
+    pub fn video_format(&self) -> Option<&FormatDescriptor> {
+        self.video_format.as_ref()
+    }
 
-                match self.write(&mut reply_packet) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                }
-            }
-            qt_pkt::SYNC_PACKET_MAGIC_CWPA => {
-                let cwpa_pkt = match qt_pkt::QTPacketCWPA::from_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    // give every FEED without a format the current one, so consumers that
+    // only look at the sample always find sps/pps. off by default, annex-b
+    // writers like to_access_unit then repeat the parameter sets per frame
+    pub fn set_attach_video_format(&mut self, attach: bool) {
+        self.attach_video_format = attach;
+    }
 
-                let device_clock_ref = cwpa_pkt.device_clock_ref() + 1000;
+    // orientation of the last video format seen, from CVRP or a FEED that
+    // carries one. there is no dedicated packet for it, rotating the device
+    // makes it send a new format descriptor with swapped dimensions
+    pub fn current_orientation(&self) -> Option<Orientation> {
+        self.orientation
+    }
 
-                self.local_audio_clock = Some(Clock::new_with_host_time(device_clock_ref));
+    fn update_orientation(&mut self, fd: &FormatDescriptor) {
+        let orientation = match fd.orientation() {
+            Some(e) => e,
+            None => return,
+        };
 
-                self.device_audio_clock = Some(cwpa_pkt.device_clock_ref());
+        if self.orientation != Some(orientation) {
+            log::info!(
+                "orientation {:?} ({}x{})",
+                orientation,
+                fd.video_dimension_width(),
+                fd.video_dimension_height()
+            );
+            self.orientation = Some(orientation);
+        }
+    }
 
-                let display_device_info = qt_hpd1_device_info();
-                let audio_device_info = qt_hpa1_device_info();
+    fn update_display_size(&mut self, fd: &FormatDescriptor) {
+        if fd.media_type() != MEDIA_TYPE_VIDEO || fd.video_dimension_width() == 0 {
+            return;
+        }
 
-                let mut display_pkt =
-                    match QTPacketASYN::new(Some(display_device_info), HPD1, EMPTY_CF_TYPE)
-                        .as_qt_packet()
-                    {
-                        Ok(e) => e,
-                        Err(e) => return Err(e),
-                    };
+        self.device_display_size = Some((fd.video_dimension_width(), fd.video_dimension_height()));
+    }
 
-                match self.write(&mut display_pkt) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                }
+    // handshake done: host clock (CLOK), video clock ref NEED goes to (CVRP)
+    // and the audio clocks (CWPA) are all known, samples can flow
+    pub fn is_session_established(&self) -> bool {
+        self.clock.is_some()
+            && self.need_clock_ref.is_some()
+            && self.local_audio_clock.is_some()
+            && self.device_audio_clock.is_some()
+    }
 
-                let mut reply_packet = match cwpa_pkt.reply_packet(correlation_id, device_clock_ref)
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    // called on the loop thread each time a session becomes established,
+    // again after a watchdog restart, a RELS or a new CWPA
+    pub fn set_on_session_established(&mut self, callback: Option<Box<dyn FnMut() + Send>>) {
+        self.on_session_established = callback;
+    }
 
-                let display_pkt_buf = match display_pkt.as_bytes() {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    // called on each handshake milestone, from init on the calling thread and
+    // from the loop afterwards. every restart goes through them again
+    pub fn set_on_progress(&mut self, callback: Option<Box<dyn FnMut(HandshakeProgress) + Send>>) {
+        self.on_progress = callback;
+    }
 
-                match reply_packet.write(display_pkt_buf) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                };
+    fn progress(&mut self, progress: HandshakeProgress) {
+        log::debug!("handshake {:?}", progress);
 
-                match self.write(&mut reply_packet) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                }
+        match &mut self.on_progress {
+            Some(callback) => callback(progress),
+            None => {}
+        };
+    }
 
-                let mut audio_pkt = match QTPacketASYN::new(
-                    Some(audio_device_info),
-                    HPA1,
-                    cwpa_pkt.device_clock_ref(),
-                )
-                .as_qt_packet()
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    fn check_session_established(&mut self) {
+        if self.session_established || !self.is_session_established() {
+            return;
+        }
 
-                match self.write(&mut audio_pkt) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                }
-            }
-            qt_pkt::SYNC_PACKET_MAGIC_CVRP => {
-                let cvrp_pkt = match qt_pkt::QTPacketCVRP::from_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+        self.session_established = true;
 
-                self.need_clock_ref = Some(cvrp_pkt.device_clock_ref());
+        match &mut self.on_session_established {
+            Some(callback) => callback(),
+            None => {}
+        };
+    }
 
-                let mut need_pkt = match QTPacketASYN::new(None, NEED, cvrp_pkt.device_clock_ref())
-                    .as_qt_packet()
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    fn publish_clock_refs(&self) {
+        self.stats.set_clock_refs(
+            self.need_clock_ref,
+            self.device_audio_clock,
+            self.host_clock_id(),
+        );
+    }
 
-                match self.write(&mut need_pkt) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                }
+    // clocks created during the handshake read time from here, defaults to
+    // the host clock
+    pub fn set_time_source(&mut self, source: Arc<dyn TimeSource>) {
+        self.time_source = source;
+    }
 
-                let device_clock_ref = cvrp_pkt.device_clock_ref() + 0x1000AF;
+    // send this HPD1 dictionary instead of qt_hpd1_device_info, e.g. one
+    // loaded from json. DisplaySize and the overrides are still applied on
+    // top, the frame rate hint isn't. None goes back to the built in one
+    pub fn set_display_device_info(&mut self, info: Option<QTValue>) {
+        self.display_device_info = info;
+    }
 
-                let mut reply_packet = match cvrp_pkt.reply_packet(correlation_id, device_clock_ref)
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
+    // same for HPA1 and qt_hpa1_device_info
+    pub fn set_audio_device_info(&mut self, info: Option<QTValue>) {
+        self.audio_device_info = info;
+    }
 
-                match self.write(&mut reply_packet) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                }
-            }
-            qt_pkt::SYNC_PACKET_MAGIC_CLOK => {
-                let host_time = clock_ref + 0x10000;
+    // extra or replaced keys for the HPD1 display info dictionary, applied in
+    // order, e.g. ("HEVCDecoderSupports444", Boolean(false))
+    pub fn set_display_info_overrides(&mut self, overrides: Vec<(String, QTValue)>) {
+        self.display_info_overrides = overrides;
+    }
 
-                self.clock = Some(Clock::new_with_host_time(host_time));
+    // DisplaySize advertised in HPD1 until the device told us its video
+    // dimensions, None keeps the 1920x1200 default. CWPA comes before CVRP so
+    // the first session always uses this, a restart already matches the
+    // device. a DisplaySize display info override still wins
+    pub fn set_display_size(&mut self, display_size: Option<(u32, u32)>) {
+        self.display_size = display_size;
+    }
 
-                let mut reply_packet =
-                    match QTPacketCLOCK::new().reply_packet(correlation_id, host_time) {
-                        Err(e) => return Err(e),
-                        Ok(e) => e,
+    // maximum frame rate to suggest in HPD1, see FRAME_RATE_HINT_KEY. only a
+    // hint, the device is free to ignore it
+    pub fn set_frame_rate_hint(&mut self, frame_rate_hint: Option<f64>) {
+        self.frame_rate_hint = frame_rate_hint;
+    }
+
+    // size HPD1 advertises in the next CWPA
+    pub fn display_size(&self) -> Option<(u32, u32)> {
+        self.device_display_size.or(self.display_size)
+    }
+
+    // same as set_display_info_overrides for the HPA1 audio info dictionary
+    pub fn set_audio_info_overrides(&mut self, overrides: Vec<(String, QTValue)>) {
+        self.audio_info_overrides = overrides;
+    }
+
+    // restart the session when no bulk data arrives for the given period,
+    // covers a wedged device that stops sending even PING
+    pub fn set_watchdog(&mut self, timeout: Option<Duration>) {
+        self.watchdog = timeout;
+    }
+
+    // how often a watchdog restart is tried before the loop gives up, one
+    // try by default
+    pub fn set_restart_retry(&mut self, policy: RetryPolicy) {
+        self.restart_retry = policy;
+    }
+
+    // usb reset at the start of every init, restarts included, to recover a
+    // device a crashed capture left claimed or wedged
+    pub fn set_reset_on_init(&mut self, reset_on_init: bool) {
+        self.reset_on_init = reset_on_init;
+    }
+
+    // which QuickTime interface init claims, an index into
+    // AppleDevice::qt_interfaces(), 0 by default
+    pub fn set_interface_choice(&mut self, choice: usize) {
+        self.device.set_interface_choice(choice);
+    }
+
+    // retries of a single bulk transfer hitting a busy bus
+    pub fn set_transfer_retry(&mut self, policy: RetryPolicy) {
+        self.device.set_transfer_retry(policy);
+    }
+
+    // how long init waits for the device to re-enumerate with QuickTime on
+    pub fn set_enable_retry(&mut self, policy: RetryPolicy) {
+        self.device.set_enable_retry(policy);
+    }
+
+    // sleep after a read that brought no bytes at all, so a flood of zlps
+    // doesn't spin a core. reads that return data never wait
+    pub fn set_idle_backoff(&mut self, backoff: Duration) {
+        self.idle_backoff = backoff;
+    }
+
+    // bytes asked for per bulk read, rounded up to whole endpoint packets.
+    // packets spanning reads are put together in the pool as before
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.read_buffer_size = size.max(1);
+    }
+
+    fn backoff(&self) {
+        if self.bulk_empty && !self.idle_backoff.is_zero() {
+            thread::sleep(self.idle_backoff);
+        }
+    }
+
+    // upper bound for one blocking bulk read and so for how long setting
+    // term takes to stop the loop, 500ms by default
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.device.set_read_timeout(timeout);
+    }
+
+    // ping the device on demand. the loop matches the echo by header and
+    // stores the round trip in stats().ping_rtt(). a ping still unanswered is
+    // replaced. nothing is measured if the device doesn't echo host pings, so
+    // the rtt is only there when the link answered. the echo is only read by
+    // the loop, called before run() the rtt includes the wait for it to start,
+    // use ping_request() once qt is on the loop thread
+    pub fn send_ping(&self) -> Result<(), Error> {
+        let seq = self.host_ping_seq.get().wrapping_add(1);
+        self.host_ping_seq.set(seq);
+
+        let header = HOST_PING_MARKER | seq as u64;
+        let mut pkt = qt_pkt::QTPacketPing::new(header);
+
+        self.host_ping.set(Some((header, Instant::now())));
+
+        match self.write(&mut pkt) {
+            Err(e) => {
+                self.host_ping.set(None);
+                return Err(e);
+            }
+            _ => {}
+        };
+
+        Ok(())
+    }
+
+    // route audio samples to their own channel so audio and video can be
+    // consumed on different threads, the main channel then only gets video
+    pub fn set_audio_sender(&mut self, audio_tx: SyncSender<Result<SampleBuffer, Error>>) {
+        self.audio_tx = Some(audio_tx);
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        if self.reset_on_init {
+            match self.device.reset() {
+                Err(e) => return Err(Error::new(ErrorKind::Other, format!("reset device {}", e))),
+                _ => log::debug!("device reset"),
+            };
+        }
+
+        self.progress(HandshakeProgress::EnablingQuickTime);
+
+        match self.device.set_qt_enabled(true) {
+            Ok(change) => log::debug!("set qt enabled: {:?}", change),
+            Err(e) => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("set qt enabled {}", e),
+                ))
+            }
+        };
+
+        self.progress(HandshakeProgress::ClaimingInterface);
+
+        match self.device.claim_interface() {
+            Some(rusb::Error::NotFound) => {
+                return Err(Error::new(ErrorKind::NotFound, QuickTimeInterfaceMissing))
+            }
+            // linux reports a claimed interface as Busy, macos as Access. the
+            // device is open already so Access isn't about permissions
+            Some(e @ rusb::Error::Busy) | Some(e @ rusb::Error::Access) => {
+                return Err(Error::new(ErrorKind::Other, QuickTimeInterfaceBusy(e)))
+            }
+            Some(e) => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("claim interface {}", e),
+                ))
+            }
+            _ => {}
+        };
+
+        match self.device.init_bulk_endpoint() {
+            Some(rusb::Error::NotFound) => {
+                return Err(Error::new(ErrorKind::NotFound, QuickTimeInterfaceMissing))
+            }
+            Some(e) => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("init bulk endpoint {}", e),
+                ))
+            }
+            _ => {}
+        };
+
+        match self.device.clear_feature() {
+            Some(_) => return Err(Error::new(ErrorKind::Other, "clear feature")),
+            _ => {}
+        };
+
+        self.progress(HandshakeProgress::WaitingForCwpa);
+
+        Ok(())
+    }
+
+    // re-negotiate the session on the same usb handle, e.g. after device sleep
+    pub fn restart(&mut self) -> Result<(), Error> {
+        match self.close_session() {
+            Err(e) => log::warn!("restart close session failed {}", e),
+            _ => {}
+        };
+
+        match self.device.set_qt_enabled(false) {
+            Err(e) => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("set qt disabled {}", e),
+                ))
+            }
+            _ => {}
+        };
+
+        self.reset_session_state();
+
+        self.init()
+    }
+
+    fn reset_session_state(&mut self) {
+        self.clock = None;
+        self.time_clock = None;
+        self.need_clock_ref = None;
+        self.local_audio_clock = None;
+        self.device_audio_clock = None;
+        self.reset_audio_timing();
+        self.last_feed = None;
+        self.og_value = None;
+        self.audio_format = None;
+        self.audio_format_desc = None;
+        self.sample_properties.clear();
+        self.time_bases.clear();
+        self.clock_ref_map.clear();
+        self.capabilities = DeviceCapabilities::new();
+        self.cvrp_format = None;
+        self.cvrp_format_primed = false;
+        self.video_format = None;
+        self.orientation = None;
+        self.session_established = false;
+        self.packet_pool = Cursor::new(Vec::new());
+        self.publish_clock_refs();
+    }
+
+    fn reset_audio_timing(&mut self) {
+        self.start_time_local_audio_clock = None;
+        self.last_eat_frame_received_local_audio_clock = None;
+        self.start_time_device_audio_clock = None;
+        self.last_eat_frame_received_device_audio_clock = None;
+    }
+
+    // device audio clock rate measured against the local one. until two EAT
+    // frames came in since CWPA (or renegotiate_audio) there is nothing to
+    // measure, answer the nominal AFMT rate then
+    fn audio_skew(&self) -> f64 {
+        match (
+            &self.start_time_local_audio_clock,
+            &self.last_eat_frame_received_local_audio_clock,
+            &self.start_time_device_audio_clock,
+            &self.last_eat_frame_received_device_audio_clock,
+        ) {
+            (Some(stlac), Some(lefrlac), Some(stdac), Some(lefrdac))
+                if lefrdac.value() > stdac.value() && lefrlac.value() >= stlac.value() =>
+            {
+                Clock::calculate_skew(stlac, lefrlac, stdac, lefrdac)
+            }
+            _ => match &self.audio_format {
+                Some(asbd) => asbd.sample_rate(),
+                None => AudioStreamDescription::default().sample_rate(),
+            },
+        }
+    }
+
+    // take one complete packet from the pool if there is one
+    fn next_packet(&mut self) -> Result<Option<QTPacket>, Error> {
+        loop {
+            let pool = self.packet_pool.get_ref();
+
+            if pool.len() < 4 {
+                return Ok(None);
+            }
+
+            let pkt_len = u32::from_le_bytes([pool[0], pool[1], pool[2], pool[3]]) as usize;
+
+            // a length beyond the cap can't be real, waiting for it would only
+            // grow the pool until memory runs out. a header that isn't a
+            // packet start means an earlier bogus length threw framing off
+            if !is_packet_start(pool) {
+                self.resync_pool();
+                continue;
+            }
+
+            if pool.len() < pkt_len {
+                return Ok(None);
+            }
+
+            let pkt = match QTPacket::from_bytes(&pool[..pkt_len]) {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            self.packet_pool = Cursor::new(Vec::from(&pool[pkt_len..]));
+
+            return Ok(Some(pkt));
+        }
+    }
+
+    // drop bytes up to the next plausible packet start
+    fn resync_pool(&mut self) {
+        let pool = self.packet_pool.get_ref();
+
+        let start = (1..pool.len().saturating_sub(7)).find(|i| is_packet_start(&pool[*i..]));
+
+        // nothing found, keep the tail in case a header is split across reads
+        let start = match start {
+            Some(e) => e,
+            None => pool.len().saturating_sub(7).max(1),
+        };
+
+        log::warn!("packet framing lost, dropped {} bytes", start);
+
+        self.stats.inc_resyncs();
+
+        self.packet_pool = Cursor::new(Vec::from(&pool[start..]));
+    }
+
+    fn read(&mut self) -> Result<Option<QTPacket>, Error> {
+        match self.next_packet() {
+            Ok(Some(pkt)) => return Ok(Some(pkt)),
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let packet_size = self.device.max_read_packet_size().max(1) as usize;
+        // transfers are whole endpoint packets, a partial one would overflow
+        let size = self.read_buffer_size.div_ceil(packet_size) * packet_size;
+
+        if self.read_buffer.len() != size {
+            self.read_buffer = vec![0; size];
+        }
+
+        let buffer_size = match self.device.read_bulk(&mut self.read_buffer) {
+            Ok(e) => e,
+            // quiet device, let the caller decide what silence means
+            Err(rusb::Error::Timeout) => {
+                self.bulk_empty = true;
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(Error::new(
+                    ErrorKind::BrokenPipe,
+                    format!("read bulk {}", e),
+                ))
+            }
+        };
+
+        // a zero length packet (zlp) is not a timeout, some hubs send them as
+        // keepalive. it proves the device is alive so it refreshes the
+        // watchdog, but carries nothing for the pool and is only counted
+        self.last_data = Instant::now();
+
+        self.bulk_empty = buffer_size == 0;
+
+        if buffer_size == 0 {
+            self.stats.inc_zero_length_reads();
+            return Ok(None);
+        }
+
+        self.packet_pool
+            .seek(SeekFrom::End(0))
+            .expect("packet pool seek to end");
+
+        match self.packet_pool.write(&self.read_buffer[..buffer_size]) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.next_packet()
+    }
+
+    fn write(&self, data: &mut QTPacket) -> Result<usize, Error> {
+        let buf = match data.as_bytes() {
+            Ok(d) => d,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "packet as_bytes")),
+        };
+
+        // rusb may write less than asked, a truncated reply breaks the
+        // handshake without any error from the device
+        let mut written = 0;
+
+        while written < buf.len() {
+            let n = match self.device.write_bulk(&buf[written..]) {
+                Ok(e) => e,
+                Err(e) => {
+                    return Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        format!("write bulk {}", e),
+                    ))
+                }
+            };
+
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    format!("write bulk stalled at {} of {} bytes", written, buf.len()),
+                ));
+            }
+
+            written += n;
+        }
+
+        Ok(written)
+    }
+
+    fn send_sample(&self, mut sample_buffer: SampleBuffer) -> Result<(), Error> {
+        if self.is_paused() {
+            return Ok(());
+        }
+
+        sample_buffer.set_host_time(Some(self.time_source.now()));
+
+        let tx = match &self.audio_tx {
+            Some(audio_tx) if sample_buffer.media_type() == MEDIA_TYPE_SOUND => audio_tx,
+            _ => &self.tx,
+        };
+
+        match self.send_mode {
+            SendMode::Block => match tx.send(Ok(sample_buffer)) {
+                Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+                _ => {}
+            },
+            SendMode::DropNewest => match tx.try_send(Ok(sample_buffer)) {
+                Err(TrySendError::Full(_)) => {
+                    self.stats.inc_dropped_samples();
+                    return Ok(());
+                }
+                Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+                _ => {}
+            },
+        };
+
+        self.stats.inc_sent_samples();
+
+        Ok(())
+    }
+
+    fn handle_pkt(&mut self, pkt: &mut QTPacket, sync: bool) -> Result<(), Error> {
+        // a runt too short for the header, seen right after a reconnect, is
+        // dropped instead of ending the loop
+        let header_len = match sync {
+            true => SYNC_HEADER_LEN,
+            false => ASYN_HEADER_LEN,
+        };
+
+        if pkt.remaining() < header_len {
+            log::warn!(
+                "drop runt {} packet, {} bytes after the magic",
+                if sync { "sync" } else { "asyn" },
+                pkt.remaining()
+            );
+            self.stats.inc_runt_packets();
+            return Ok(());
+        }
+
+        let clock_ref = match pkt.read_u64() {
+            Err(e) => return Err(e),
+            Ok(e) => e,
+        };
+
+        let magic = match pkt.read_u32() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        match sync {
+            true => {
+                let correlation_id = match pkt.read_u64() {
+                    Ok(m) => m,
+                    Err(e) => return Err(e),
+                };
+                self.handle_sync_pkt(pkt, clock_ref, magic, correlation_id)
+            }
+            false => self.handle_asyn_pkt(pkt, clock_ref, magic),
+        }
+    }
+
+    fn handle_sync_pkt(
+        &mut self,
+        pkt: &mut QTPacket,
+        clock_ref: u64,
+        magic: u32,
+        correlation_id: u64,
+    ) -> Result<(), Error> {
+        match magic {
+            qt_pkt::SYNC_PACKET_MAGIC_OG => {
+                let og_pkt = match qt_pkt::QTPacketOG::from_packet(pkt) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                log::debug!("OG value {:#x}", og_pkt.value());
+
+                self.og_value = Some(og_pkt.value());
+
+                let mut reply_packet = match og_pkt.reply_packet(correlation_id) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                match self.write(&mut reply_packet) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
+            }
+            qt_pkt::SYNC_PACKET_MAGIC_CWPA => {
+                let cwpa_pkt = match qt_pkt::QTPacketCWPA::from_packet(pkt) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                let device_clock_ref = cwpa_pkt.device_clock_ref() + self.clock_ref_offsets.cwpa;
+
+                self.map_clock_ref(
+                    ClockRole::Audio,
+                    cwpa_pkt.device_clock_ref(),
+                    device_clock_ref,
+                );
+
+                // audio can be renegotiated mid session, skew must restart
+                // from the new clocks instead of mixing in the old samples
+                self.reset_audio_timing();
+                self.session_established = false;
+
+                self.local_audio_clock = Some(Clock::new_with_time_source(
+                    device_clock_ref,
+                    Arc::clone(&self.time_source),
+                ));
+
+                self.device_audio_clock = Some(cwpa_pkt.device_clock_ref());
+
+                let mut display_device_info = match &self.display_device_info {
+                    Some(info) => info.clone(),
+                    None => qt_hpd1_device_info(self.frame_rate_hint),
+                };
+                match self.display_size() {
+                    Some((width, height)) => {
+                        display_device_info.set_key("DisplaySize", qt_display_size(width, height))
+                    }
+                    None => {}
+                };
+                for (key, value) in &self.display_info_overrides {
+                    display_device_info.set_key(key, value.clone());
+                }
+
+                let audio_device_info = self.audio_device_info();
+
+                let mut display_pkt =
+                    match QTPacketASYN::new(Some(display_device_info), HPD1, EMPTY_CF_TYPE)
+                        .as_qt_packet()
+                    {
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    };
+
+                match self.write(&mut display_pkt) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
+
+                let mut reply_packet = match cwpa_pkt.reply_packet(correlation_id, device_clock_ref)
+                {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                let display_pkt_buf = match display_pkt.as_bytes() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                match reply_packet.write(display_pkt_buf) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+
+                match self.write(&mut reply_packet) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
+
+                let mut audio_pkt = match QTPacketASYN::new(
+                    Some(audio_device_info),
+                    HPA1,
+                    cwpa_pkt.device_clock_ref(),
+                )
+                .as_qt_packet()
+                {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                match self.write(&mut audio_pkt) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
+
+                self.progress(HandshakeProgress::AudioClock);
+            }
+            qt_pkt::SYNC_PACKET_MAGIC_CVRP => {
+                let cvrp_pkt = match qt_pkt::QTPacketCVRP::from_packet(pkt) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                self.need_clock_ref = Some(cvrp_pkt.device_clock_ref());
+
+                self.progress(HandshakeProgress::VideoClock);
+
+                self.capabilities.add_value(cvrp_pkt.payload());
+
+                match cvrp_pkt.format_descriptor() {
+                    Some(fd) => {
+                        log::debug!("CVRP carries the video format");
+                        self.update_orientation(fd);
+                        self.update_display_size(fd);
+                        self.cvrp_format = Some(fd.clone());
+                        self.video_format = Some(fd.clone());
+                    }
+                    None => {}
+                };
+
+                let mut need_pkt = match QTPacketASYN::new(None, NEED, cvrp_pkt.device_clock_ref())
+                    .as_qt_packet()
+                {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                match self.write(&mut need_pkt) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
+
+                let device_clock_ref = cvrp_pkt.device_clock_ref() + self.clock_ref_offsets.cvrp;
+
+                self.map_clock_ref(
+                    ClockRole::Video,
+                    cvrp_pkt.device_clock_ref(),
+                    device_clock_ref,
+                );
+
+                let mut reply_packet = match cvrp_pkt.reply_packet(correlation_id, device_clock_ref)
+                {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                match self.write(&mut reply_packet) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
+            }
+            qt_pkt::SYNC_PACKET_MAGIC_CLOK => {
+                let host_time = clock_ref + self.clock_ref_offsets.clok;
+
+                self.map_clock_ref(ClockRole::Host, clock_ref, host_time);
+
+                self.clock = Some(Clock::new_with_time_source(
+                    host_time,
+                    Arc::clone(&self.time_source),
+                ));
+                self.time_clock = None;
+
+                self.progress(HandshakeProgress::ClockEstablished);
+
+                let mut reply_packet =
+                    match QTPacketCLOCK::new().reply_packet(correlation_id, host_time) {
+                        Err(e) => return Err(e),
+                        Ok(e) => e,
                     };
 
                 match self.write(&mut reply_packet) {
@@ -326,12 +1275,29 @@ impl QuickTime {
                 }
             }
             qt_pkt::SYNC_PACKET_MAGIC_TIME => {
-                QTPacketTIME::new()
-                    .reply_packet(
-                        correlation_id,
-                        self.clock.as_ref().expect("clock none").get_time(),
-                    )
-                    .expect("qt packet time reply");
+                // some iOS versions ask for TIME before CLOK, the clock ref in
+                // the packet is the host clock they expect so start one here.
+                // it only answers TIME, the session still waits for CLOK
+                let time_source = &self.time_source;
+                let t = match &self.clock {
+                    Some(clock) => clock.get_time(),
+                    None => self
+                        .time_clock
+                        .get_or_insert_with(|| {
+                            Clock::new_with_time_source(clock_ref, Arc::clone(time_source))
+                        })
+                        .get_time(),
+                };
+
+                let mut reply_packet = match QTPacketTIME::new().reply_packet(correlation_id, t) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                match self.write(&mut reply_packet) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                }
             }
             qt_pkt::SYNC_PACKET_MAGIC_AFMT => {
                 let afmt_pkt = match QTPacketAFMT::from_packet(pkt) {
@@ -339,6 +1305,10 @@ impl QuickTime {
                     Err(e) => return Err(e),
                 };
 
+                self.audio_format = Some(*afmt_pkt.audio_desc());
+                self.audio_format_desc = Some(FormatDescriptor::new_audio(*afmt_pkt.audio_desc()));
+                self.capabilities.add_audio_format(*afmt_pkt.audio_desc());
+
                 let mut reply_packet = match afmt_pkt.reply_packet(correlation_id) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
@@ -350,27 +1320,7 @@ impl QuickTime {
                 }
             }
             qt_pkt::SYNC_PACKET_MAGIC_SKEW => {
-                let stlac = self
-                    .start_time_local_audio_clock
-                    .as_ref()
-                    .expect("start_time_local_audio_clock None");
-
-                let stdac = self
-                    .start_time_device_audio_clock
-                    .as_ref()
-                    .expect("start_time_device_audio_clock None");
-
-                let lefrlac = self
-                    .last_eat_frame_received_local_audio_clock
-                    .as_ref()
-                    .expect("last_eat_frame_received_local_audio_clock None");
-
-                let lefrdac = self
-                    .last_eat_frame_received_device_audio_clock
-                    .as_ref()
-                    .expect("last_eat_frame_received_device_audio_clock None");
-
-                let skew = Clock::calculate_skew(stlac, lefrlac, stdac, lefrdac);
+                let skew = self.audio_skew();
 
                 let mut pkt = match QTPacketSKEW::new().reply_packet(correlation_id, skew) {
                     Ok(e) => e,
@@ -392,94 +1342,246 @@ impl QuickTime {
                     Err(e) => return Err(e),
                     _ => {}
                 };
+
+                // the device ended the stream, leave run() so consumers get
+                // the closed sentinel and can finalize their output
+                self.term.store(true, Ordering::Relaxed);
             }
             _ => {
-                println!("SYNC_UNKNOWN_MAGIC - {}", magic);
+                log::warn!(
+                    "unknown sync packet {:#x} {}",
+                    magic,
+                    hex::encode(pkt.to_vec())
+                );
             }
         };
 
+        self.publish_clock_refs();
+        self.check_session_established();
+
         Ok(())
     }
 
     fn handle_asyn_pkt(
         &mut self,
         pkt: &mut QTPacket,
-        _clock_ref: u64,
+        clock_ref: u64,
         magic: u32,
     ) -> Result<(), Error> {
         match magic {
             qt_pkt::ASYN_PACKET_MAGIC_EAT => {
-                let sample_buffer = match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_SOUND) {
+                let mut sample_buffer = match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_SOUND) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 };
 
-                if self.last_eat_frame_received_device_audio_clock.is_none() {
-                    self.start_time_device_audio_clock =
-                        sample_buffer.output_presentation_time_stamp();
-                    self.start_time_local_audio_clock = Some(
-                        self.local_audio_clock
-                            .as_ref()
-                            .expect("local audio clock")
-                            .get_time(),
-                    );
-                    self.last_eat_frame_received_device_audio_clock =
-                        sample_buffer.output_presentation_time_stamp();
-                    self.last_eat_frame_received_local_audio_clock =
-                        self.start_time_local_audio_clock.clone();
-                } else {
-                    self.last_eat_frame_received_device_audio_clock =
-                        sample_buffer.output_presentation_time_stamp();
-                    self.last_eat_frame_received_local_audio_clock = Some(
-                        self.local_audio_clock
-                            .as_ref()
-                            .expect("invalid lac")
-                            .get_time(),
-                    );
-                }
+                // most EAT frames come without a format, audio only consumers
+                // need the asbd on every one of them
+                match sample_buffer.format_description() {
+                    Some(fd) => self.audio_format_desc = Some(fd.clone()),
+                    None => sample_buffer.set_format_description(self.audio_format_desc.clone()),
+                };
+
+                // an EAT before CWPA has no local clock to measure against,
+                // the sample still goes out and SKEW answers the nominal rate
+                match &self.local_audio_clock {
+                    Some(local_audio_clock) => {
+                        let now = local_audio_clock.get_time();
+
+                        if self.last_eat_frame_received_device_audio_clock.is_none() {
+                            self.start_time_device_audio_clock =
+                                sample_buffer.output_presentation_time_stamp();
+                            self.start_time_local_audio_clock = Some(now.clone());
+                        }
 
-                match self.tx.send(Ok(sample_buffer)) {
-                    Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+                        self.last_eat_frame_received_device_audio_clock =
+                            sample_buffer.output_presentation_time_stamp();
+                        self.last_eat_frame_received_local_audio_clock = Some(now);
+                    }
+                    None => {}
+                };
+
+                match self.send_sample(sample_buffer) {
+                    Err(e) => return Err(e),
                     _ => {}
                 };
             }
             qt_pkt::ASYN_PACKET_MAGIC_FEED => {
-                let sample_buffer = match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_VIDEO) {
+                let mut sample_buffer = match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_VIDEO) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 };
 
-                let mut pkt = match QTPacketASYN::new(
-                    None,
-                    NEED,
-                    self.need_clock_ref.expect("need clock ref"),
-                )
-                .as_qt_packet()
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
+                // hand the CVRP format to consumers with the first frame when
+                // the stream doesn't carry one inline, they keep sps/pps from
+                // there on
+                if !self.cvrp_format_primed {
+                    self.cvrp_format_primed = true;
+
+                    if sample_buffer.format_description().is_none() {
+                        sample_buffer.set_format_description(self.cvrp_format.clone());
+                    }
+                }
+
+                match sample_buffer.format_description() {
+                    Some(fd) => {
+                        self.update_orientation(fd);
+                        self.update_display_size(fd);
+                        self.capabilities.add_format(fd);
+                        self.video_format = Some(fd.clone());
+                    }
+                    None if self.attach_video_format => {
+                        sample_buffer.set_format_description(self.video_format.clone())
+                    }
+                    None => {}
                 };
 
-                match self.write(&mut pkt) {
-                    Err(e) => return Err(e),
-                    _ => {}
+                if self.last_feed.is_none() {
+                    self.progress(HandshakeProgress::FirstFrame);
+                }
+
+                self.last_feed = Some(Instant::now());
+
+                // no NEED once the device released the video clock
+                match self.need_clock_ref {
+                    Some(need_clock_ref) => {
+                        let mut pkt =
+                            match QTPacketASYN::new(None, NEED, need_clock_ref).as_qt_packet() {
+                                Ok(e) => e,
+                                Err(e) => return Err(e),
+                            };
+
+                        match self.write(&mut pkt) {
+                            Err(e) => return Err(e),
+                            _ => {}
+                        };
+                    }
+                    None => {}
                 };
 
-                match self.tx.send(Ok(sample_buffer)) {
-                    Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+                match self.send_sample(sample_buffer) {
+                    Err(e) => return Err(e),
                     _ => {}
                 };
             }
-            qt_pkt::ASYN_PACKET_MAGIC_SPRP => {}
+            qt_pkt::ASYN_PACKET_MAGIC_SPRP => {
+                let sprp_pkt = match qt_pkt::QTPacketSPRP::from_packet(pkt) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                log::debug!(
+                    "SPRP clock ref {:#x}\n{}",
+                    clock_ref,
+                    sprp_pkt.property().to_str(String::new())
+                );
+
+                self.capabilities.add_value(sprp_pkt.property());
+                self.sample_properties.push(sprp_pkt.into_property());
+            }
             qt_pkt::ASYN_PACKET_MAGIC_TJMP => {}
             qt_pkt::ASYN_PACKET_MAGIC_SRAT => {}
-            qt_pkt::ASYN_PACKET_MAGIC_TBAS => {}
-            qt_pkt::ASYN_PACKET_MAGIC_RELS => {}
-            _ => {}
+            qt_pkt::ASYN_PACKET_MAGIC_TBAS => {
+                let tbas_pkt = match qt_pkt::QTPacketTBAS::from_packet(pkt, clock_ref) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                log::debug!(
+                    "TBAS clock {:#x} based on {:#x}",
+                    tbas_pkt.clock_ref(),
+                    tbas_pkt.base_clock_ref()
+                );
+
+                self.time_bases.retain(|(c, _)| *c != tbas_pkt.clock_ref());
+                self.time_bases
+                    .push((tbas_pkt.clock_ref(), tbas_pkt.base_clock_ref()));
+            }
+            qt_pkt::ASYN_PACKET_MAGIC_RELS => {
+                // the header clock ref is the clock the device lets go of,
+                // forget it so nothing stale is sent to it later (HPA0 in
+                // close_session, NEED on a late FEED)
+                if self.device_audio_clock == Some(clock_ref) {
+                    log::debug!("RELS audio clock {:#x}", clock_ref);
+                    self.device_audio_clock = None;
+                } else if self.need_clock_ref == Some(clock_ref) {
+                    log::debug!("RELS video clock {:#x}", clock_ref);
+                    self.need_clock_ref = None;
+                } else {
+                    log::debug!("RELS unknown clock {:#x}", clock_ref);
+                }
+
+                self.clock_ref_map
+                    .retain(|m| m.device_clock_ref != clock_ref);
+
+                // a clock is gone, the next CWPA/CVRP establishes it again
+                self.session_established = self.is_session_established();
+
+                self.publish_clock_refs();
+            }
+            _ => {
+                log::debug!(
+                    "unknown asyn packet {:#x} {}",
+                    magic,
+                    hex::encode(pkt.to_vec())
+                );
+            }
         }
         Ok(())
     }
 
+    // HPA1 dictionary with the caller's overrides applied
+    fn audio_device_info(&self) -> QTValue {
+        let mut audio_device_info = match &self.audio_device_info {
+            Some(info) => info.clone(),
+            None => qt_hpa1_device_info(),
+        };
+        for (key, value) in &self.audio_info_overrides {
+            audio_device_info.set_key(key, value.clone());
+        }
+        audio_device_info
+    }
+
+    // restart only the audio stream when EAT stopped but FEED goes on: HPA0
+    // then HPA1 again on the device audio clock, without touching video.
+    // audio timing starts over, a fresh CWPA from the device is handled as
+    // usual. needs an established audio clock (CWPA seen, no RELS since).
+    // not every iOS version is known to resume audio this way, if the device
+    // ignores it the session carries on unchanged and restart is the fallback
+    pub fn renegotiate_audio(&mut self) -> Result<(), Error> {
+        let clock = match self.device_audio_clock {
+            Some(e) => e,
+            None => return Err(Error::new(ErrorKind::NotConnected, "no device audio clock")),
+        };
+
+        let mut off_audio = match QTPacketASYN::new(None, HPA0, clock).as_qt_packet() {
+            Err(e) => return Err(e),
+            Ok(e) => e,
+        };
+
+        match self.write(&mut off_audio) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.reset_audio_timing();
+
+        let mut audio_pkt =
+            match QTPacketASYN::new(Some(self.audio_device_info()), HPA1, clock).as_qt_packet() {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+        match self.write(&mut audio_pkt) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        log::debug!("audio renegotiated on clock {:#x}", clock);
+
+        Ok(())
+    }
+
     fn close_session(&mut self) -> Result<(), Error> {
         match self.device_audio_clock {
             Some(clock) => {
@@ -509,68 +1611,1019 @@ impl QuickTime {
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        while !self.term.load(Ordering::Relaxed) {
-            // ping request
-            let o_pkt = match self.read() {
-                Ok(e) => e,
+    fn dispatch(&mut self, pkt: &mut QTPacket) -> Result<(), Error> {
+        let magic = match pkt.read_u32() {
+            Ok(m) => m,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "read magic failed")),
+        };
+
+        match magic {
+            qt_pkt::PACKET_MAGIC_PING => {
+                // the echo of our own ping is measured, not answered again
+                let header = qt_pkt::QTPacketPing::from_packet(pkt).map(|p| p.header());
+                match (self.host_ping.get(), header) {
+                    (Some((sent_header, sent)), Ok(header)) if sent_header == header => {
+                        self.host_ping.set(None);
+                        self.stats.set_ping_rtt(sent.elapsed());
+                        log::debug!("ping rtt {:?}", sent.elapsed());
+                        return Ok(());
+                    }
+                    _ => {}
+                };
+
+                pkt.rewind();
+                match self.write(pkt) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+            }
+            qt_pkt::PACKET_MAGIC_SYNC | qt_pkt::PACKET_MAGIC_ASYN => {
+                let sync = magic == qt_pkt::PACKET_MAGIC_SYNC;
+
+                match self.handle_pkt(pkt, sync) {
+                    // the payload didn't parse, the pool is framed by length
+                    // so the next packet is fine. usb and channel errors
+                    // still end the loop
+                    Err(e) if is_malformed(&e) => {
+                        log::warn!(
+                            "drop malformed {} packet: {}",
+                            if sync { "sync" } else { "asyn" },
+                            e
+                        );
+                        self.stats.inc_malformed_packets();
+                    }
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+            }
+            _ => {
+                log::warn!("unknown packet magic {:#x}", magic);
+            }
+        };
+
+        Ok(())
+    }
+
+    // hand out complete packets still sitting in the pool after term is set
+    fn drain(&mut self) -> Result<(), Error> {
+        loop {
+            let mut pkt = match self.next_packet() {
+                Ok(Some(e)) => e,
+                Ok(None) => return Ok(()),
                 Err(e) => return Err(e),
             };
 
-            if o_pkt.is_none() {
-                continue;
+            match self.dispatch(&mut pkt) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+    }
+
+    // run the handshake after init until the host clock is set and the first
+    // FEED arrives, then close the session again. samples received meanwhile
+    // still go to the channel
+    pub fn self_test(&mut self, timeout: Duration) -> Result<SelfTestReport, Error> {
+        let start = Instant::now();
+        let mut clock: Option<Duration> = None;
+
+        while !self.term.load(Ordering::Relaxed) {
+            if start.elapsed() >= timeout {
+                let stage = match clock {
+                    Some(_) => "no FEED after clock",
+                    None => "no clock",
+                };
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("self test timed out: {}", stage),
+                ));
             }
 
-            let mut pkt = o_pkt.unwrap();
+            let mut pkt = match self.read() {
+                Ok(Some(e)) => e,
+                Ok(None) => {
+                    self.backoff();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
-            let magic = match pkt.read_u32() {
-                Ok(m) => m,
-                Err(_) => return Err(Error::new(ErrorKind::InvalidData, "read magic failed")),
+            match self.dispatch(&mut pkt) {
+                Err(e) => return Err(e),
+                _ => {}
             };
 
-            match magic {
-                qt_pkt::PACKET_MAGIC_PING => {
-                    pkt.borrow_mut().seek(SeekFrom::Start(0)).expect("seek");
-                    self.write(&mut pkt).expect("write ping");
-                }
-                qt_pkt::PACKET_MAGIC_SYNC => {
-                    self.handle_pkt(&mut pkt, true).expect("sync");
+            if clock.is_none() && self.clock.is_some() {
+                clock = Some(start.elapsed());
+            }
+
+            match (clock, self.last_feed) {
+                (Some(clock), Some(feed)) => {
+                    let report = SelfTestReport {
+                        clock,
+                        first_feed: feed.duration_since(start),
+                    };
+
+                    match self.close_session() {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
+
+                    self.reset_session_state();
+
+                    return Ok(report);
                 }
-                qt_pkt::PACKET_MAGIC_ASYN => {
-                    self.handle_pkt(&mut pkt, false).expect("asyn");
+                _ => {}
+            };
+        }
+
+        Err(Error::new(ErrorKind::Interrupted, "self test interrupted"))
+    }
+
+    fn restart_with_retry(&mut self) -> Result<(), Error> {
+        let mut attempt = 1;
+
+        loop {
+            match self.restart() {
+                Ok(_) => return Ok(()),
+                Err(e) if self.restart_retry.should_retry(attempt) => {
+                    log::warn!(
+                        "restart failed {}, retry {}/{}",
+                        e,
+                        attempt,
+                        self.restart_retry.max_attempts - 1
+                    );
+                    thread::sleep(self.restart_retry.delay(attempt));
+                    attempt += 1;
                 }
-                _ => {
-                    println!("magic: PACKET_MAGIC_UNKNOWN {:#2x?}", magic);
+                Err(e) => return Err(e),
+            };
+
+            if self.term.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+        }
+    }
+
+    // one read and dispatch round of the loop, watchdog included
+    fn step(&mut self) -> Result<(), Error> {
+        if self.ping_request.swap(false, Ordering::Relaxed) {
+            match self.send_ping() {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        let o_pkt = match self.read() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if o_pkt.is_none() {
+            match self.watchdog {
+                Some(timeout) if self.last_data.elapsed() >= timeout => {
+                    log::warn!("watchdog: no data for {:?}, restarting session", timeout);
+
+                    self.stats.inc_watchdog_restarts();
+
+                    match self.restart_with_retry() {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
+
+                    self.last_data = Instant::now();
                 }
+                _ => {}
             };
+
+            self.backoff();
+            return Ok(());
         }
 
-        self.tx
+        let mut pkt = o_pkt.unwrap();
+
+        self.dispatch(&mut pkt)
+    }
+
+    // flush what is left in the pool and tell the consumers the stream ended
+    fn finish(&mut self) {
+        match self.drain() {
+            Err(e) => log::warn!("drain packet pool failed {}", e),
+            _ => {}
+        };
+
+        match self
+            .tx
             .send(Err(Error::new(ErrorKind::BrokenPipe, "manual closed")))
-            .expect("send close to channel");
+        {
+            Err(_) => log::warn!("consumer gone before the close marker"),
+            _ => {}
+        };
+
+        match &self.audio_tx {
+            Some(audio_tx) => {
+                // audio consumer may already be gone, nothing left to tell it
+                let _ = audio_tx.send(Err(Error::new(ErrorKind::BrokenPipe, "manual closed")));
+            }
+            None => {}
+        };
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        while !self.term.load(Ordering::Relaxed) {
+            match self.step() {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        self.finish();
 
         Ok(())
     }
+
+    // run the loop and feed the sink on the calling thread, no consumer thread
+    // needed. samples go through a private channel drained after every packet,
+    // the tx passed to new and any audio channel are not used. returns when
+    // term is set (STOP, ctrl-c) or on the first loop or sink error, finalize
+    // runs in every case
+    pub fn run_with_sink<S: SampleSink>(mut self, mut sink: S) -> Result<(), Error> {
+        let (tx, rx) = mpsc::sync_channel(SINK_CHANNEL_SIZE);
+
+        self.tx = tx;
+        self.audio_tx = None;
+
+        let mut result: Result<(), Error> = Ok(());
+
+        while result.is_ok() && !self.term.load(Ordering::Relaxed) {
+            result = match self.step() {
+                Err(e) => Err(e),
+                _ => write_pending(&rx, &mut sink),
+            };
+        }
+
+        if result.is_ok() {
+            result = self.drain_to_sink(&rx, &mut sink);
+        }
+
+        match sink.finalize() {
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(e) => log::error!("sink finalize failed {}", e),
+            _ => {}
+        };
+
+        result
+    }
+
+    // what is left in the pool after term, like finish() but the sink gets
+    // the samples packet by packet. queueing all of them first would block
+    // once there are more than SINK_CHANNEL_SIZE with nobody reading
+    fn drain_to_sink<S: SampleSink>(
+        &mut self,
+        rx: &Receiver<Result<SampleBuffer, Error>>,
+        sink: &mut S,
+    ) -> Result<(), Error> {
+        loop {
+            let mut pkt = match self.next_packet() {
+                Ok(Some(e)) => e,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    log::warn!("drain packet pool failed {}", e);
+                    return Ok(());
+                }
+            };
+
+            match self.dispatch(&mut pkt) {
+                Err(e) => {
+                    log::warn!("drain packet pool failed {}", e);
+                    return Ok(());
+                }
+                _ => {}
+            };
+
+            match write_pending(rx, sink) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+    }
+}
+
+// hand everything queued on the run_with_sink channel to the sink
+fn write_pending<S: SampleSink>(
+    rx: &Receiver<Result<SampleBuffer, Error>>,
+    sink: &mut S,
+) -> Result<(), Error> {
+    while let Ok(Ok(sample_buffer)) = rx.try_recv() {
+        match sink.write(&sample_buffer) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+    }
+
+    Ok(())
+}
+
+impl Debug for QuickTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "og_value: {:?}\nneed_clock_ref: {:?}\ndevice_audio_clock: {:?}\nhost_clock_id: {:?}\nlocal_audio_clock_id: {:?}\n{:?}",
+            self.og_value,
+            self.need_clock_ref,
+            self.device_audio_clock,
+            self.host_clock_id(),
+            self.local_audio_clock_id(),
+            self.stats,
+        ))
+    }
 }
 
 impl Drop for QuickTime {
     fn drop(&mut self) {
-        self.close_session().expect("close session failed");
+        match self.close_session() {
+            Err(e) => log::warn!("close session failed {}", e),
+            _ => {}
+        };
 
         match self.device.is_qt_enabled() {
             Ok(enabled) => {
                 if enabled {
                     match self.device.set_qt_enabled(!enabled) {
                         Err(e) => {
-                            println!("set_qt_disabled failed {}", e);
+                            log::error!("set_qt_disabled failed {}", e);
                         }
                         _ => {}
                     }
                 }
             }
             Err(e) => {
-                println!("dispose failed {}", e);
+                log::error!("dispose failed {}", e);
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apple::QTModeChange;
+    use crate::coremedia::audio_desc::AUDIO_FORMAT_ID_LPCM;
+    use crate::coremedia::clock::ManualTimeSource;
+    use crate::qt_pkt::QTPacketPing;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicU32, AtomicUsize};
+    use std::sync::Mutex;
+
+    const SBUF: u32 = 0x73627566;
+    const OPTS: u32 = 0x6F707473;
+
+    const AUDIO_CLOCK_REF: u64 = 0x7FA66CE20B80;
+    const HOST_CLOCK_REF: u64 = 0x7FA66CE20C40;
+    const VIDEO_CLOCK_REF: u64 = 0x7FA66CE20CB0;
+
+    // stands in for the usb device: keeps what the host writes, reads time out
+    struct FakeDevice {
+        written: Arc<Mutex<Vec<Vec<u8>>>>,
+        // pings written by the host, read back as the device's echo
+        echoes: Mutex<VecDeque<Vec<u8>>>,
+        // writes fail like an unplugged device
+        broken: Arc<AtomicBool>,
+        // most bytes a write takes, short writes like a busy endpoint
+        write_limit: Arc<AtomicUsize>,
+    }
+
+    impl UsbTransport for FakeDevice {
+        fn transfer_stats(&self) -> TransferStats {
+            TransferStats {
+                bytes_read: 0,
+                bytes_written: 0,
+                reads: 0,
+                writes: 0,
+                elapsed: Duration::ZERO,
+            }
+        }
+
+        fn is_qt_enabled(&self) -> Result<bool, rusb::Error> {
+            Ok(false)
+        }
+
+        fn set_qt_enabled(&mut self, _: bool) -> Result<QTModeChange, rusb::Error> {
+            Ok(QTModeChange::NoChange)
+        }
+
+        fn set_interface_choice(&mut self, _: usize) {}
+
+        fn claim_interface(&mut self) -> Option<rusb::Error> {
+            None
+        }
+
+        fn init_bulk_endpoint(&mut self) -> Option<rusb::Error> {
+            None
+        }
+
+        fn clear_feature(&self) -> Option<rusb::Error> {
+            None
+        }
+
+        fn reset(&mut self) -> Result<(), rusb::Error> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&mut self, _: Duration) {}
+
+        fn set_transfer_retry(&mut self, _: RetryPolicy) {}
+
+        fn set_enable_retry(&mut self, _: RetryPolicy) {}
+
+        fn max_read_packet_size(&self) -> u16 {
+            512
+        }
+
+        fn read_bulk(&self, buf: &mut [u8]) -> Result<usize, rusb::Error> {
+            match self.echoes.lock().unwrap().pop_front() {
+                Some(echo) => {
+                    buf[..echo.len()].copy_from_slice(&echo);
+                    Ok(echo.len())
+                }
+                None => Err(rusb::Error::Timeout),
+            }
+        }
+
+        fn write_bulk(&self, buf: &[u8]) -> Result<usize, rusb::Error> {
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(rusb::Error::NoDevice);
+            }
+            if buf.len() >= 8 && buf[4..8] == qt_pkt::PACKET_MAGIC_PING.to_le_bytes() {
+                self.echoes.lock().unwrap().push_back(Vec::from(buf));
+            }
+            let n = buf.len().min(self.write_limit.load(Ordering::Relaxed));
+            self.written.lock().unwrap().push(Vec::from(&buf[..n]));
+            Ok(n)
+        }
+    }
+
+    struct Session {
+        qt: QuickTime,
+        rx: Receiver<Result<SampleBuffer, Error>>,
+        written: Arc<Mutex<Vec<Vec<u8>>>>,
+        broken: Arc<AtomicBool>,
+        write_limit: Arc<AtomicUsize>,
+        time: Arc<ManualTimeSource>,
+    }
+
+    impl Session {
+        fn new() -> Session {
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let broken = Arc::new(AtomicBool::new(false));
+            let write_limit = Arc::new(AtomicUsize::new(usize::MAX));
+            let (tx, rx) = mpsc::sync_channel(16);
+
+            let mut qt = QuickTime::new(
+                FakeDevice {
+                    written: Arc::clone(&written),
+                    echoes: Mutex::new(VecDeque::new()),
+                    broken: Arc::clone(&broken),
+                    write_limit: Arc::clone(&write_limit),
+                },
+                tx,
+            );
+
+            let time = Arc::new(ManualTimeSource::new(Duration::from_secs(100)));
+            qt.set_time_source(Arc::clone(&time) as Arc<dyn TimeSource>);
+
+            Session {
+                qt,
+                rx,
+                written,
+                broken,
+                write_limit,
+                time,
             }
+        }
+
+        fn dispatch(&mut self, buf: &[u8]) {
+            self.qt
+                .dispatch(&mut QTPacket::from_bytes(buf).unwrap())
+                .unwrap();
+        }
+
+        // payload of the last rply written, after the 20 byte header
+        fn last_reply(&self) -> Vec<u8> {
+            let written = self.written.lock().unwrap();
+            let reply = written
+                .iter()
+                .rev()
+                .find(|w| &w[4..8] == b"ylpr")
+                .expect("no reply written");
+            Vec::from(&reply[20..])
+        }
+
+        fn skew(&mut self) -> f64 {
+            self.dispatch(&sync_packet(
+                HOST_CLOCK_REF,
+                qt_pkt::SYNC_PACKET_MAGIC_SKEW,
+                &[],
+            ));
+            let reply = self.last_reply();
+            f64::from_le_bytes(reply[..8].try_into().unwrap())
+        }
+    }
+
+    // value and scale of the TIME reply
+    fn time(session: &mut Session, clock_ref: u64) -> (u64, u32) {
+        session.dispatch(&sync_packet(clock_ref, qt_pkt::SYNC_PACKET_MAGIC_TIME, &[]));
+        let reply = session.last_reply();
+        (
+            u64::from_le_bytes(reply[..8].try_into().unwrap()),
+            u32::from_le_bytes(reply[8..12].try_into().unwrap()),
+        )
+    }
+
+    fn sync_packet(clock_ref: u64, magic: u32, payload: &[u8]) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(&(28 + payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&qt_pkt::PACKET_MAGIC_SYNC.to_le_bytes());
+        buf.extend_from_slice(&clock_ref.to_le_bytes());
+        buf.extend_from_slice(&magic.to_le_bytes());
+        buf.extend_from_slice(&0x55u64.to_le_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn cwpa(device_clock_ref: u64) -> Vec<u8> {
+        sync_packet(
+            0,
+            qt_pkt::SYNC_PACKET_MAGIC_CWPA,
+            &device_clock_ref.to_le_bytes(),
+        )
+    }
+
+    fn cvrp(device_clock_ref: u64) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::from(device_clock_ref.to_le_bytes());
+        payload.extend_from_slice(
+            QTValue::Object(Vec::new())
+                .as_qt_packet()
+                .unwrap()
+                .as_bytes()
+                .unwrap(),
+        );
+        sync_packet(0, qt_pkt::SYNC_PACKET_MAGIC_CVRP, &payload)
+    }
+
+    fn asyn_packet(clock_ref: u64, magic: u32) -> Vec<u8> {
+        let mut pkt = QTPacketASYN::new(None, magic, clock_ref)
+            .as_qt_packet()
+            .unwrap();
+        pkt.as_bytes().unwrap().to_vec()
+    }
+
+    fn afmt(sample_rate: f64) -> Vec<u8> {
+        let asbd =
+            AudioStreamDescription::new(sample_rate, AUDIO_FORMAT_ID_LPCM, 12, 4, 1, 4, 2, 16);
+        sync_packet(
+            AUDIO_CLOCK_REF,
+            qt_pkt::SYNC_PACKET_MAGIC_AFMT,
+            &asbd.as_buffer().unwrap(),
+        )
+    }
+
+    // audio frame stamped value/scale on the device audio clock
+    fn eat(value: u64, scale: u32) -> Vec<u8> {
+        let mut opts = QTPacket::new_with_magic(OPTS);
+        opts.write(&Time::new(value, scale, 1, 0).as_bytes().unwrap())
+            .unwrap();
+
+        let mut sbuf = QTPacket::new_with_magic(SBUF);
+        sbuf.write(opts.as_bytes().unwrap()).unwrap();
+
+        let mut pkt = QTPacketASYN::new(None, qt_pkt::ASYN_PACKET_MAGIC_EAT, AUDIO_CLOCK_REF)
+            .as_qt_packet()
+            .unwrap();
+        pkt.write(sbuf.as_bytes().unwrap()).unwrap();
+        pkt.as_bytes().unwrap().to_vec()
+    }
+
+    #[test]
+    fn skew_after_second_cwpa_is_nominal() {
+        let mut session = Session::new();
+
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        session.dispatch(&eat(0, 48000));
+        session.time.advance(Duration::from_millis(1001));
+        session.dispatch(&eat(48000, 48000));
+
+        let skew = session.skew();
+        assert!((skew - 48048f64).abs() < 0.5, "skew {}", skew);
+
+        // audio renegotiated by the device, no EAT on the new clocks yet
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        assert_eq!(session.skew(), 48000f64);
+
+        assert_eq!(session.rx.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn skew_after_renegotiate_audio() {
+        let mut session = Session::new();
+
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        session.dispatch(&afmt(44100f64));
+        session.dispatch(&eat(0, 44100));
+        session.time.advance(Duration::from_secs(1));
+        session.dispatch(&eat(44100, 44100));
+        assert_eq!(session.skew(), 44100f64);
+
+        session.qt.renegotiate_audio().unwrap();
+        assert_eq!(session.skew(), 44100f64);
+
+        // measured from the frames after the renegotiation only
+        session.dispatch(&eat(88200, 44100));
+        session.time.advance(Duration::from_millis(250));
+        session.dispatch(&eat(132300, 44100));
+        assert_eq!(session.skew(), 11025f64);
+    }
+
+    #[test]
+    fn time_before_clok() {
+        let mut session = Session::new();
+
+        session.dispatch(&cvrp(VIDEO_CLOCK_REF));
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+
+        // TIME gets a clock on the ref it came with, but that isn't CLOK
+        assert_eq!(time(&mut session, HOST_CLOCK_REF), (0, 1_000_000_000));
+        assert_eq!(session.qt.host_clock_id(), None);
+        assert!(!session.qt.is_session_established());
+
+        session.time.advance(Duration::from_millis(20));
+        assert_eq!(
+            time(&mut session, HOST_CLOCK_REF),
+            (20_000_000, 1_000_000_000)
+        );
+
+        // CLOK replaces it with the clock we answer with
+        session.dispatch(&sync_packet(
+            HOST_CLOCK_REF,
+            qt_pkt::SYNC_PACKET_MAGIC_CLOK,
+            &[],
+        ));
+        let host_clock = HOST_CLOCK_REF + CLOK_CLOCK_REF_OFFSET;
+        assert_eq!(session.last_reply(), host_clock.to_le_bytes());
+        assert_eq!(session.qt.host_clock_id(), Some(host_clock));
+        assert!(session.qt.is_session_established());
+
+        session.time.advance(Duration::from_millis(1));
+        assert_eq!(time(&mut session, host_clock), (1_000_000, 1_000_000_000));
+    }
+
+    #[test]
+    fn replies_follow_the_time_source() {
+        let mut session = Session::new();
+        let host_clock = HOST_CLOCK_REF + CLOK_CLOCK_REF_OFFSET;
+
+        session.dispatch(&sync_packet(
+            HOST_CLOCK_REF,
+            qt_pkt::SYNC_PACKET_MAGIC_CLOK,
+            &[],
+        ));
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        session.dispatch(&eat(0, 48000));
+        session.time.advance(Duration::from_micros(1_501_500));
+        session.dispatch(&eat(72000, 48000));
+
+        // 1.5015s on our clocks against 1.5s of device frames
+        assert_eq!(
+            time(&mut session, host_clock),
+            (1_501_500_000, 1_000_000_000)
+        );
+        assert!((session.skew() - 48048f64).abs() < 1e-6);
+
+        // nothing moves while the source stands still
+        assert_eq!(
+            time(&mut session, host_clock),
+            (1_501_500_000, 1_000_000_000)
+        );
+        assert!((session.skew() - 48048f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rels_clears_device_audio_clock() {
+        let mut session = Session::new();
+
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        assert_eq!(session.qt.device_audio_clock(), Some(AUDIO_CLOCK_REF));
+
+        session.dispatch(&asyn_packet(0x1234, qt_pkt::ASYN_PACKET_MAGIC_RELS));
+        assert_eq!(session.qt.device_audio_clock(), Some(AUDIO_CLOCK_REF));
+
+        session.dispatch(&asyn_packet(
+            AUDIO_CLOCK_REF,
+            qt_pkt::ASYN_PACKET_MAGIC_RELS,
+        ));
+        assert_eq!(session.qt.device_audio_clock(), None);
+        assert!(session
+            .qt
+            .clock_ref_map()
+            .iter()
+            .all(|m| m.role != ClockRole::Audio));
+
+        // nothing left to send HPA0 to
+        let err = session.qt.renegotiate_audio().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn session_established_again_after_rels_and_cwpa() {
+        let mut session = Session::new();
+
+        let established = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&established);
+        session
+            .qt
+            .set_on_session_established(Some(Box::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })));
+
+        session.dispatch(&sync_packet(
+            HOST_CLOCK_REF,
+            qt_pkt::SYNC_PACKET_MAGIC_CLOK,
+            &[],
+        ));
+        session.dispatch(&cvrp(VIDEO_CLOCK_REF));
+        assert!(!session.qt.is_session_established());
+
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        assert!(session.qt.is_session_established());
+        assert_eq!(established.load(Ordering::Relaxed), 1);
+
+        // audio renegotiated by the device
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        assert_eq!(established.load(Ordering::Relaxed), 2);
+
+        session.dispatch(&asyn_packet(
+            AUDIO_CLOCK_REF,
+            qt_pkt::ASYN_PACKET_MAGIC_RELS,
+        ));
+        assert!(!session.qt.is_session_established());
+        assert_eq!(established.load(Ordering::Relaxed), 2);
+
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        assert_eq!(established.load(Ordering::Relaxed), 3);
+    }
+
+    // packets from fuzzing that used to panic dispatch
+    #[test]
+    fn malformed_packets_are_dropped() {
+        let mut session = Session::new();
+
+        // CWPA without its clock ref
+        let short_cwpa = sync_packet(0, qt_pkt::SYNC_PACKET_MAGIC_CWPA, &[0x01, 0x02]);
+        session.dispatch(&short_cwpa);
+
+        // AFMT cut off inside the asbd
+        let mut short_afmt = afmt(48000f64);
+        short_afmt.truncate(40);
+        short_afmt[..4].copy_from_slice(&40u32.to_le_bytes());
+        session.dispatch(&short_afmt);
+
+        // EAT whose sbuf is not a sbuf
+        let mut bad_eat = asyn_packet(AUDIO_CLOCK_REF, qt_pkt::ASYN_PACKET_MAGIC_EAT);
+        bad_eat.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0x78, 0x78, 0x78, 0x78]);
+        let len = bad_eat.len() as u32;
+        bad_eat[..4].copy_from_slice(&len.to_le_bytes());
+        session.dispatch(&bad_eat);
+
+        assert_eq!(session.qt.stats().malformed_packets(), 3);
+        assert!(session.written.lock().unwrap().is_empty());
+        assert_eq!(session.rx.try_iter().count(), 0);
+
+        // the session goes on with the next packet
+        session.dispatch(&cwpa(AUDIO_CLOCK_REF));
+        assert_eq!(session.qt.device_audio_clock(), Some(AUDIO_CLOCK_REF));
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        let mut session = Session::new();
+        session.broken.store(true, Ordering::Relaxed);
+
+        let mut ping = QTPacketPing::new(0x0000000100000000);
+        let ping = ping.as_bytes().unwrap().to_vec();
+        let err = session
+            .qt
+            .dispatch(&mut QTPacket::from_bytes(&ping).unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        let err = session
+            .qt
+            .dispatch(&mut QTPacket::from_bytes(&cwpa(AUDIO_CLOCK_REF)).unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(session.qt.stats().malformed_packets(), 0);
+    }
+
+    #[test]
+    fn short_writes_are_completed() {
+        let mut session = Session::new();
+        session.write_limit.store(7, Ordering::Relaxed);
+
+        let skew = sync_packet(HOST_CLOCK_REF, qt_pkt::SYNC_PACKET_MAGIC_SKEW, &[]);
+        session.dispatch(&skew);
+
+        let written = session.written.lock().unwrap();
+        assert_eq!(written.len(), 4);
+        assert!(written.iter().all(|w| w.len() <= 7));
+        let reply: Vec<u8> = written.concat();
+        assert_eq!(reply.len(), 28);
+        assert_eq!(&reply[..4], &28u32.to_le_bytes());
+        assert_eq!(&reply[4..8], b"ylpr");
+        assert_eq!(
+            f64::from_le_bytes(reply[20..].try_into().unwrap()),
+            AudioStreamDescription::default().sample_rate()
+        );
+        drop(written);
+
+        // a device that takes nothing is an error, not a busy loop
+        session.write_limit.store(0, Ordering::Relaxed);
+        let err = session
+            .qt
+            .dispatch(&mut QTPacket::from_bytes(&skew).unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+    }
+
+    struct CountingSink {
+        samples: Arc<AtomicU32>,
+        finalized: Arc<AtomicBool>,
+    }
+
+    impl SampleSink for CountingSink {
+        fn write(&mut self, _: &SampleBuffer) -> Result<(), Error> {
+            self.samples.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<(), Error> {
+            self.finalized.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_with_sink_drains_more_than_the_channel_holds() {
+        let session = Session::new();
+        let mut qt = session.qt;
+
+        let count = SINK_CHANNEL_SIZE as u64 + 44;
+        let mut pool: Vec<u8> = Vec::new();
+        for i in 0..count {
+            pool.extend(eat(i * 1024, 48000));
+        }
+        qt.packet_pool = Cursor::new(pool);
+        qt.term.store(true, Ordering::Relaxed);
+
+        let samples = Arc::new(AtomicU32::new(0));
+        let finalized = Arc::new(AtomicBool::new(false));
+        let sink = CountingSink {
+            samples: Arc::clone(&samples),
+            finalized: Arc::clone(&finalized),
         };
+
+        // a deadlock fails the test instead of hanging it
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = done_tx.send(qt.run_with_sink(sink).is_ok());
+        });
+
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(10)), Ok(true));
+        assert_eq!(samples.load(Ordering::Relaxed) as u64, count);
+        assert!(finalized.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn pool_is_delivered_before_close() {
+        let mut session = Session::new();
+
+        let mut pool: Vec<u8> = Vec::new();
+        for i in 0..5 {
+            pool.extend(eat(i * 1024, 48000));
+        }
+        session.qt.packet_pool = Cursor::new(pool);
+        session.qt.term.store(true, Ordering::Relaxed);
+
+        session.qt.run().unwrap();
+
+        let received: Vec<Result<SampleBuffer, Error>> = session.rx.try_iter().collect();
+        assert_eq!(received.len(), 6);
+
+        for (i, sample) in received[..5].iter().enumerate() {
+            let pts = sample.as_ref().unwrap().output_presentation_time_stamp();
+            assert_eq!(pts.unwrap().value(), i as u64 * 1024);
+        }
+
+        let close = received[5].as_ref().unwrap_err();
+        assert_eq!(close.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn packet_start_needs_length_and_magic() {
+        let skew = sync_packet(HOST_CLOCK_REF, qt_pkt::SYNC_PACKET_MAGIC_SKEW, &[]);
+        assert!(is_packet_start(&skew));
+        assert!(is_packet_start(&asyn_packet(
+            VIDEO_CLOCK_REF,
+            qt_pkt::ASYN_PACKET_MAGIC_FEED
+        )));
+        assert!(is_packet_start(&QTPacketPing::new(1).as_bytes().unwrap()));
+
+        // nothing to judge yet
+        assert!(is_packet_start(&[]));
+        assert!(is_packet_start(&[0xFF, 0xFF, 0xFF]));
+        assert!(is_packet_start(&skew[..7]));
+
+        // shorter than its own header
+        assert!(!is_packet_start(&[7, 0, 0, 0]));
+        assert!(!is_packet_start(&[0xFF, 0xFF, 0xFF, 0xFF]));
+
+        let mut unknown = skew.clone();
+        unknown[4..8].copy_from_slice(b"zzzz");
+        assert!(!is_packet_start(&unknown));
+    }
+
+    #[test]
+    fn pool_resyncs_after_garbage() {
+        let mut session = Session::new();
+        let skew = sync_packet(HOST_CLOCK_REF, qt_pkt::SYNC_PACKET_MAGIC_SKEW, &[]);
+        let feed = asyn_packet(VIDEO_CLOCK_REF, qt_pkt::ASYN_PACKET_MAGIC_FEED);
+
+        let mut pool: Vec<u8> = vec![0xFF; 13];
+        pool.extend_from_slice(&skew);
+        pool.extend_from_slice(&feed);
+        session.qt.packet_pool = Cursor::new(pool);
+
+        let mut pkt = session.qt.next_packet().unwrap().unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), skew.as_slice());
+        let mut pkt = session.qt.next_packet().unwrap().unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), feed.as_slice());
+        assert!(session.qt.next_packet().unwrap().is_none());
+        assert_eq!(session.qt.stats().resyncs(), 1);
+
+        // a header split across reads survives the resync of the garbage
+        // in front of it
+        let mut pool: Vec<u8> = vec![0xFF; 20];
+        pool.extend_from_slice(&skew[..6]);
+        session.qt.packet_pool = Cursor::new(pool);
+        assert!(session.qt.next_packet().unwrap().is_none());
+
+        session
+            .qt
+            .packet_pool
+            .get_mut()
+            .extend_from_slice(&skew[6..]);
+        let mut pkt = session.qt.next_packet().unwrap().unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), skew.as_slice());
+        assert!(session.qt.packet_pool.get_ref().is_empty());
+    }
+
+    #[test]
+    fn pool_doesnt_wait_for_a_packet_above_the_cap() {
+        let mut session = Session::new();
+        let skew = sync_packet(HOST_CLOCK_REF, qt_pkt::SYNC_PACKET_MAGIC_SKEW, &[]);
+
+        // a largest possible packet is waited for
+        let mut pool: Vec<u8> = Vec::from((MAX_PACKET_POOL_SIZE as u32).to_le_bytes());
+        pool.extend_from_slice(&qt_pkt::PACKET_MAGIC_SYNC.to_le_bytes());
+        session.qt.packet_pool = Cursor::new(pool.clone());
+        assert!(session.qt.next_packet().unwrap().is_none());
+        assert_eq!(session.qt.packet_pool.get_ref(), &pool);
+
+        // one byte more can't be real, it's dropped instead of buffering
+        // 16MiB for it
+        let mut pool: Vec<u8> = Vec::from((MAX_PACKET_POOL_SIZE as u32 + 1).to_le_bytes());
+        pool.extend_from_slice(&qt_pkt::PACKET_MAGIC_SYNC.to_le_bytes());
+        pool.extend_from_slice(&skew);
+        session.qt.packet_pool = Cursor::new(pool);
+
+        let mut pkt = session.qt.next_packet().unwrap().unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), skew.as_slice());
+        assert_eq!(session.qt.stats().resyncs(), 1);
+    }
+
+    #[test]
+    fn ping_request_is_sent_and_timed_by_the_loop() {
+        let mut session = Session::new();
+        let ping_request = Arc::clone(session.qt.ping_request());
+        let stats = Arc::clone(session.qt.stats());
+
+        session.qt.step().unwrap();
+        assert!(session.written.lock().unwrap().is_empty());
+
+        ping_request.store(true, Ordering::Relaxed);
+        session.qt.step().unwrap();
+
+        assert!(!ping_request.load(Ordering::Relaxed));
+        assert!(stats.ping_rtt().is_some());
+        // the echo of our own ping isn't answered
+        assert_eq!(session.written.lock().unwrap().len(), 1);
     }
 }
@@ -0,0 +1,100 @@
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::format_desc::FormatDescriptor;
+use crate::coremedia::sample::{CODEC_HVC1, MEDIA_TYPE_VIDEO};
+use crate::qt_value::QTValue;
+
+// what the device told us about itself over the session, gathered from the
+// CVRP payload, SPRP properties, AFMT and the video format descriptors. only
+// what was actually sent ends up here, a missing key means unknown rather
+// than unsupported
+#[derive(Clone, Default)]
+pub struct DeviceCapabilities {
+    // string keyed values in the order seen, nested dictionaries are kept
+    // whole and their pairs listed as well
+    properties: Vec<(String, QTValue)>,
+    video_codecs: Vec<u32>,
+    video_sizes: Vec<(u32, u32)>,
+    audio_formats: Vec<AudioStreamDescription>,
+}
+
+impl DeviceCapabilities {
+    pub fn new() -> DeviceCapabilities {
+        DeviceCapabilities::default()
+    }
+
+    pub fn properties(&self) -> &[(String, QTValue)] {
+        &self.properties
+    }
+
+    // last value sent for key
+    pub fn property(&self, key: &str) -> Option<&QTValue> {
+        self.properties
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    // codec fourccs of the video formats seen, e.g. CODEC_AVC1
+    pub fn video_codecs(&self) -> &[u32] {
+        &self.video_codecs
+    }
+
+    // width x height of the video formats seen, both orientations show up
+    // once the device was rotated
+    pub fn video_sizes(&self) -> &[(u32, u32)] {
+        &self.video_sizes
+    }
+
+    pub fn audio_formats(&self) -> &[AudioStreamDescription] {
+        &self.audio_formats
+    }
+
+    pub fn supports_hevc(&self) -> bool {
+        self.video_codecs.contains(&CODEC_HVC1)
+    }
+
+    pub fn supports_444(&self) -> Option<bool> {
+        self.property("HEVCDecoderSupports444")?.as_bool()
+    }
+
+    pub(crate) fn add_value(&mut self, value: &QTValue) {
+        match value {
+            QTValue::KeyValuePair(kv) => {
+                match kv.key().as_string() {
+                    Some(key) => self.properties.push((key, kv.value().clone())),
+                    None => {}
+                };
+                self.add_value(kv.value());
+            }
+            QTValue::Object(o) => {
+                for v in o {
+                    self.add_value(v);
+                }
+            }
+            QTValue::FormatDescriptor(fd) => self.add_format(fd),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn add_format(&mut self, fd: &FormatDescriptor) {
+        if fd.media_type() != MEDIA_TYPE_VIDEO {
+            return;
+        }
+
+        if !self.video_codecs.contains(&fd.codec()) {
+            self.video_codecs.push(fd.codec());
+        }
+
+        let size = (fd.video_dimension_width(), fd.video_dimension_height());
+        if !self.video_sizes.contains(&size) {
+            self.video_sizes.push(size);
+        }
+    }
+
+    pub(crate) fn add_audio_format(&mut self, asbd: AudioStreamDescription) {
+        if !self.audio_formats.contains(&asbd) {
+            self.audio_formats.push(asbd);
+        }
+    }
+}
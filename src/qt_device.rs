@@ -1,10 +1,35 @@
 use crate::coremedia::audio_desc::AudioStreamDescription;
 use crate::qt_value::{QTKeyValuePair, QTValue};
 
-pub fn qt_hpd1_device_info() -> QTValue {
-    let mut arr: Vec<QTValue> = Vec::new();
+// advertised when neither the device nor the caller told us better
+pub const DEFAULT_DISPLAY_WIDTH: u32 = 1920;
+pub const DEFAULT_DISPLAY_HEIGHT: u32 = 1200;
+
+// the DisplaySize dictionary of HPD1
+pub fn qt_display_size(width: u32, height: u32) -> QTValue {
     let mut display_arr: Vec<QTValue> = Vec::new();
 
+    display_arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
+        QTValue::StringKey(String::from("Width")),
+        QTValue::Float(width as f64),
+    )));
+
+    display_arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
+        QTValue::StringKey(String::from("Height")),
+        QTValue::Float(height as f64),
+    )));
+
+    QTValue::Object(display_arr)
+}
+
+// frame rate hint advertised in HPD1. no device is known to read this key,
+// whether any honors it is unverified. NEED pacing is what reliably bounds
+// the rate
+pub const FRAME_RATE_HINT_KEY: &str = "PreferredFrameRate";
+
+pub fn qt_hpd1_device_info(frame_rate_hint: Option<f64>) -> QTValue {
+    let mut arr: Vec<QTValue> = Vec::new();
+
     arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
         QTValue::StringKey(String::from("Valeria")),
         QTValue::Boolean(true),
@@ -15,21 +40,19 @@ pub fn qt_hpd1_device_info() -> QTValue {
         QTValue::Boolean(true),
     )));
 
-    display_arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
-        QTValue::StringKey(String::from("Width")),
-        QTValue::Float(1920f64),
-    )));
-
-    display_arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
-        QTValue::StringKey(String::from("Height")),
-        QTValue::Float(1200f64),
-    )));
-
     arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
         QTValue::StringKey(String::from("DisplaySize")),
-        QTValue::Object(display_arr),
+        qt_display_size(DEFAULT_DISPLAY_WIDTH, DEFAULT_DISPLAY_HEIGHT),
     )));
 
+    match frame_rate_hint {
+        Some(fps) => arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(String::from(FRAME_RATE_HINT_KEY)),
+            QTValue::Float(fps),
+        ))),
+        None => {}
+    };
+
     QTValue::Object(arr)
 }
 
@@ -37,7 +60,7 @@ pub fn qt_hpa1_device_info() -> QTValue {
     let mut arr: Vec<QTValue> = Vec::new();
 
     let buffer = AudioStreamDescription::default()
-        .as_buffer()
+        .as_format_buffer()
         .expect("audio stream description failed");
 
     arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
@@ -72,3 +95,62 @@ pub fn qt_hpa1_device_info() -> QTValue {
 
     QTValue::Object(arr)
 }
+
+// what --display-info-json and --audio-info-json load must match the built in
+// dictionaries once dumped and read back
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    fn json_round_trip(value: &QTValue) -> QTValue {
+        let text = serde_json::to_string(&value.to_json()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        QTValue::from_json(&json).unwrap()
+    }
+
+    fn get<'a>(dict: &'a QTValue, key: &str) -> &'a QTValue {
+        dict.as_vec()
+            .unwrap()
+            .iter()
+            .filter_map(|v| v.as_pair())
+            .find(|kv| kv.key().as_string().as_deref() == Some(key))
+            .map(|kv| kv.value())
+            .unwrap()
+    }
+
+    #[test]
+    fn device_info_json_round_trip() {
+        let hpd1 = qt_hpd1_device_info(Some(60f64));
+        let back = json_round_trip(&hpd1);
+        assert_eq!(back.to_json(), hpd1.to_json());
+        assert_eq!(back.as_vec().unwrap().len(), 4);
+        assert_eq!(get(&back, "Valeria").as_bool(), Some(true));
+        assert_eq!(get(&back, FRAME_RATE_HINT_KEY).as_f64(), Some(60f64));
+        assert_eq!(
+            get(get(&back, "DisplaySize"), "Width").as_f64(),
+            Some(1920f64)
+        );
+
+        let hpa1 = qt_hpa1_device_info();
+        let back = json_round_trip(&hpa1);
+        assert_eq!(back.to_json(), hpa1.to_json());
+        assert_eq!(back.as_vec().unwrap().len(), 6);
+        assert_eq!(
+            get(&back, "formats").as_data(),
+            Some(
+                &AudioStreamDescription::default()
+                    .as_format_buffer()
+                    .unwrap()
+            )
+        );
+        assert_eq!(get(&back, "EDIDAC3Support").as_u32(), Some(0));
+        assert_eq!(
+            get(&back, "BufferAheadInterval").as_f64(),
+            Some(0.07300000000000001f64)
+        );
+        assert_eq!(
+            get(&back, "deviceName").as_string().as_deref(),
+            Some("Valeria")
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::format_desc::FormatDescriptor;
 use crate::coremedia::time::Time;
 use crate::qt_value::{QTKeyValuePair, QTValue};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::borrow::BorrowMut;
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter};
 use std::io;
-use std::io::{BufRead, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
 
 pub struct QTPacket {
     inner: Cursor<Vec<u8>>,
@@ -25,6 +27,14 @@ impl QTPacket {
     }
 
     pub fn read_qt_packet(pkt: &mut QTPacket, size: usize) -> Result<QTPacket, Error> {
+        // size comes from the wire, don't allocate more than is there
+        if size as u64 > pkt.remaining() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "qt package length not compare data size",
+            ));
+        }
+
         let mut data: Vec<u8> = vec![0; size];
         match pkt.read_exact(&mut data) {
             Ok(e) => e,
@@ -87,12 +97,15 @@ impl QTPacket {
             Err(e) => return Err(e),
         };
 
-        let pkt_len = match pkt.len() {
-            Err(e) => return Err(e),
-            Ok(e) => e,
-        } as u32;
+        if read_pkt_len < 4 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "qt package length smaller than header",
+            ));
+        }
 
-        if pkt_len < read_pkt_len {
+        // declared length counts its own 4 bytes which are already consumed
+        if pkt.remaining() < read_pkt_len as u64 - 4 {
             return Err(Error::new(
                 ErrorKind::UnexpectedEof,
                 "qt package length not compare data size",
@@ -101,12 +114,10 @@ impl QTPacket {
 
         let mut buffer: Vec<u8> = vec![0; read_pkt_len as usize];
 
-        if read_pkt_len > 0 {
-            match pkt.read_exact(&mut buffer[4..]) {
-                Err(e) => return Err(e),
-                _ => {}
-            };
-        }
+        match pkt.read_exact(&mut buffer[4..]) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
 
         let mut cur = Cursor::new(buffer);
 
@@ -116,7 +127,18 @@ impl QTPacket {
     }
 
     pub fn from_bytes(data: &[u8]) -> Result<QTPacket, Error> {
+        if data.len() < 4 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "qt package header"));
+        }
+
         let pkt_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if pkt_len < 4 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "qt package length smaller than header",
+            ));
+        }
+
         if data.len() < pkt_len {
             return Err(Error::new(
                 ErrorKind::UnexpectedEof,
@@ -135,20 +157,49 @@ impl QTPacket {
         return self.inner.position();
     }
 
-    pub fn len(&mut self) -> Result<u64, Error> {
+    // read length and magic of the next sub packet without consuming it
+    pub fn peek_header(&mut self) -> Result<(u32, u32), Error> {
         let cur = self.inner.position();
 
-        let size = match self.inner.seek(SeekFrom::End(0)) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
+        let header = match self.read_u32() {
+            Ok(len) => match self.read_u32() {
+                Ok(magic) => Ok((len, magic)),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
         };
 
-        match self.inner.seek(SeekFrom::Start(cur)) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
+        self.inner.set_position(cur);
 
-        Ok(size)
+        header
+    }
+
+    pub fn skip(&mut self, n: u64) -> Result<(), Error> {
+        if self.remaining() < n {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "skip beyond packet end",
+            ));
+        }
+
+        let cur = self.inner.position();
+        self.inner.set_position(cur + n);
+
+        Ok(())
+    }
+
+    // the backing buffer already knows its size, no need to seek to the end
+    pub fn len(&mut self) -> Result<u64, Error> {
+        Ok(self.inner.get_ref().len() as u64)
+    }
+
+    // bytes left between the cursor and the end of the packet
+    pub fn remaining(&mut self) -> u64 {
+        (self.inner.get_ref().len() as u64).saturating_sub(self.inner.position())
+    }
+
+    pub fn rewind(&mut self) {
+        self.inner.set_position(0);
     }
 
     pub fn write_u8(&mut self, d: u8) -> Result<(), Error> {
@@ -206,14 +257,29 @@ impl QTPacket {
         }
     }
 
+    // finalizes the length prefix to the current packet size and returns the
+    // whole packet. the cursor is left where it was so reads and writes carry
+    // on unaffected, calling it again yields the same bytes
     pub fn as_bytes(&mut self) -> Result<&[u8], Error> {
-        let pkt_len = self.inner.seek(SeekFrom::End(0)).expect("seek failed") as u32;
+        let buf = self.inner.get_mut();
 
-        self.inner.seek(SeekFrom::Start(0)).expect("seek failed");
-        self.write_u32(pkt_len).expect("write pkg len");
-        self.inner.seek(SeekFrom::Start(0)).expect("seek failed");
+        if buf.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "packet without length"));
+        }
 
-        self.inner.fill_buf()
+        let pkt_len = buf.len() as u32;
+        buf[..4].copy_from_slice(&pkt_len.to_le_bytes());
+
+        Ok(self.inner.get_ref().as_slice())
+    }
+
+    // copy of the finalized packet for logging or forwarding, the cursor is
+    // left alone like in as_bytes
+    pub fn to_vec(&mut self) -> Vec<u8> {
+        match self.as_bytes() {
+            Ok(e) => e.to_vec(),
+            Err(_) => self.inner.get_ref().clone(),
+        }
     }
 
     pub fn borrow_mut(&mut self) -> &mut Cursor<Vec<u8>> {
@@ -221,6 +287,29 @@ impl QTPacket {
     }
 }
 
+// same checks as from_bytes, bytes past the length prefix are ignored
+impl TryFrom<&[u8]> for QTPacket {
+    type Error = Error;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        QTPacket::from_bytes(data)
+    }
+}
+
+// the finalized packet, length prefix fixed up like as_bytes
+impl TryFrom<QTPacket> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(mut pkt: QTPacket) -> Result<Self, Self::Error> {
+        match pkt.as_bytes() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        Ok(pkt.inner.into_inner())
+    }
+}
+
 impl Debug for QTPacket {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(
@@ -252,6 +341,10 @@ impl QTPacketPing {
         pkt
     }
 
+    pub fn header(&self) -> u64 {
+        self.header
+    }
+
     pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketPing, Error> {
         let header = match pkt.read_u64() {
             Ok(m) => m,
@@ -289,6 +382,17 @@ pub struct QTPacketCWPA {
     device_clock_ref: u64,
 }
 
+// every reply starts with the same 20 byte header, the payload follows it:
+//   0..4   length (fixed up by as_bytes)
+//   4..8   RPLY magic
+//   8..16  correlation id of the sync packet answered
+//   16..20 zero
+const REPLY_HEADER_LEN: u64 = 20;
+
+// OG and STOP replies carry a status after the header. the device doesn't
+// look at it, always answer 0 like the mac does
+const REPLY_STATUS_OK: u32 = 0;
+
 fn reply_packet(correlation_id: u64) -> Result<QTPacket, Error> {
     let mut pkt = QTPacket::new();
 
@@ -310,12 +414,16 @@ fn reply_packet(correlation_id: u64) -> Result<QTPacket, Error> {
     Ok(pkt)
 }
 
+// CWPA/CVRP/CLOK replies are the header plus the host clock ref at 20..28,
+// 28 bytes in total. same layout quicktime_video_hack sends (clockRefReply)
 fn reply_packet_with_clock_ref(correlation_id: u64, clock_ref: u64) -> Result<QTPacket, Error> {
     let mut pkt = match reply_packet(correlation_id) {
         Ok(e) => e,
         Err(e) => return Err(e),
     };
 
+    debug_assert_eq!(pkt.pos(), REPLY_HEADER_LEN);
+
     match pkt.write_u64(clock_ref) {
         Err(e) => return Err(e),
         _ => {}
@@ -399,18 +507,24 @@ impl QTPacketASYN {
 }
 
 pub struct QTPacketOG {
-    unknown: u32,
+    value: u32,
 }
 
 impl QTPacketOG {
+    // meaning is unknown, kept around so differences between iOS versions
+    // show up in the logs
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
     pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketOG, Error> {
         // read reversed
-        let unknown = match pkt.read_u32() {
+        let value = match pkt.read_u32() {
             Ok(m) => m,
             Err(e) => return Err(e),
         };
 
-        Ok(QTPacketOG { unknown })
+        Ok(QTPacketOG { value })
     }
 
     pub fn reply_packet(&self, correlation_id: u64) -> Result<QTPacket, Error> {
@@ -419,7 +533,7 @@ impl QTPacketOG {
             Err(e) => return Err(e),
         };
 
-        match pkt.write_u32(0) {
+        match pkt.write_u32(REPLY_STATUS_OK) {
             Err(e) => return Err(e),
             _ => {}
         };
@@ -442,6 +556,12 @@ impl QTPacketCVRP {
         &self.payload
     }
 
+    // some iOS versions announce the video format here instead of in the
+    // first FEED
+    pub fn format_descriptor(&self) -> Option<&FormatDescriptor> {
+        self.payload.find_format_descriptor()
+    }
+
     pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketCVRP, Error> {
         // read reversed
         let device_clock_ref = match pkt.read_u64() {
@@ -465,6 +585,66 @@ impl QTPacketCVRP {
     }
 }
 
+// set property, seen right after the stream starts with one key/value pair
+// per packet, e.g. ObeyEmptyMediaMarkers=true or RenderEmptyMedia=false:
+//   0..4 length, 4..8 keyv magic, then a StringKey and the value
+pub struct QTPacketSPRP {
+    property: QTValue,
+}
+
+impl QTPacketSPRP {
+    pub fn property(&self) -> &QTValue {
+        &self.property
+    }
+
+    pub fn into_property(self) -> QTValue {
+        self.property
+    }
+
+    pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketSPRP, Error> {
+        let property = match QTValue::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(QTPacketSPRP { property })
+    }
+}
+
+// time base, one per stream clock shortly after the first samples. the
+// header clock ref is the clock being described, the payload names the clock
+// its timestamps are based on:
+//   0..4 length, 4..8 ASYN magic, 8..16 clock ref, 16..20 tbas magic,
+//   20..28 clock ref of the base clock
+// audio and video sharing a base clock means their timestamps are directly
+// comparable, otherwise the clocks have to be related through CLOK/SKEW
+pub struct QTPacketTBAS {
+    clock_ref: u64,
+    base_clock_ref: u64,
+}
+
+impl QTPacketTBAS {
+    pub fn clock_ref(&self) -> u64 {
+        self.clock_ref
+    }
+
+    pub fn base_clock_ref(&self) -> u64 {
+        self.base_clock_ref
+    }
+
+    pub fn from_packet(pkt: &mut QTPacket, clock_ref: u64) -> Result<QTPacketTBAS, Error> {
+        let base_clock_ref = match pkt.read_u64() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(QTPacketTBAS {
+            clock_ref,
+            base_clock_ref,
+        })
+    }
+}
+
 pub struct QTPacketCLOCK {}
 
 impl QTPacketCLOCK {
@@ -509,6 +689,10 @@ pub struct QTPacketAFMT {
 }
 
 impl QTPacketAFMT {
+    pub fn audio_desc(&self) -> &AudioStreamDescription {
+        &self.audio_desc
+    }
+
     pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketAFMT, Error> {
         let audio_desc = match AudioStreamDescription::from_qt_packet(pkt) {
             Ok(e) => e,
@@ -584,7 +768,7 @@ impl QTPacketSTOP {
             Err(e) => return Err(e),
         };
 
-        match pkt.write_u32(0) {
+        match pkt.write_u32(REPLY_STATUS_OK) {
             Err(e) => return Err(e),
             _ => {}
         };
@@ -592,3 +776,143 @@ impl QTPacketSTOP {
         Ok(pkt)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORRELATION_ID: u64 = 0x0102030405060708;
+    const CLOCK_REF: u64 = 0x1122334455667788;
+
+    // length, rply, correlation id and the zero word
+    fn reply_header(len: u32) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(b"ylpr");
+        buf.extend_from_slice(&CORRELATION_ID.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf
+    }
+
+    fn clock_ref_reply() -> Vec<u8> {
+        let mut buf = reply_header(28);
+        buf.extend_from_slice(&CLOCK_REF.to_le_bytes());
+        buf
+    }
+
+    fn sync_payload(payload: &[u8]) -> QTPacket {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(&(payload.len() as u32 + 4).to_le_bytes());
+        buf.extend_from_slice(payload);
+        QTPacket::from_bytes(&buf).unwrap()
+    }
+
+    #[test]
+    fn clock_ref_reply_bytes() {
+        let expected: [u8; 28] = [
+            0x1c, 0x00, 0x00, 0x00, 0x79, 0x6c, 0x70, 0x72, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03,
+            0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+        ];
+        assert_eq!(clock_ref_reply(), expected);
+        assert_eq!(REPLY_HEADER_LEN as usize, expected.len() - 8);
+
+        let mut pkt = reply_packet_with_clock_ref(CORRELATION_ID, CLOCK_REF).unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn cwpa_reply_bytes() {
+        let cwpa = QTPacketCWPA::from_packet(&mut sync_payload(&7u64.to_le_bytes())).unwrap();
+        assert_eq!(cwpa.device_clock_ref(), 7);
+
+        let mut pkt = cwpa.reply_packet(CORRELATION_ID, CLOCK_REF).unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), clock_ref_reply().as_slice());
+    }
+
+    #[test]
+    fn cvrp_reply_bytes() {
+        let mut payload: Vec<u8> = Vec::from(7u64.to_le_bytes());
+        payload.extend_from_slice(
+            QTValue::Object(Vec::new())
+                .as_qt_packet()
+                .unwrap()
+                .as_bytes()
+                .unwrap(),
+        );
+
+        let cvrp = QTPacketCVRP::from_packet(&mut sync_payload(&payload)).unwrap();
+        assert_eq!(cvrp.device_clock_ref(), 7);
+
+        let mut pkt = cvrp.reply_packet(CORRELATION_ID, CLOCK_REF).unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), clock_ref_reply().as_slice());
+    }
+
+    #[test]
+    fn clok_reply_bytes() {
+        let mut pkt = QTPacketCLOCK::new()
+            .reply_packet(CORRELATION_ID, CLOCK_REF)
+            .unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), clock_ref_reply().as_slice());
+    }
+
+    #[test]
+    fn og_reply_bytes() {
+        let og = QTPacketOG::from_packet(&mut sync_payload(&1u32.to_le_bytes())).unwrap();
+        assert_eq!(og.value(), 1);
+
+        let mut expected = reply_header(24);
+        expected.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut pkt = og.reply_packet(CORRELATION_ID).unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), expected.as_slice());
+    }
+
+    #[test]
+    fn stop_reply_bytes() {
+        let mut expected = reply_header(24);
+        expected.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut pkt = QTPacketSTOP::new().reply_packet(CORRELATION_ID).unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), expected.as_slice());
+    }
+
+    #[test]
+    fn time_reply_bytes() {
+        let t = Time::new(0x0A0B0C0D, 1_000_000_000, 1, 0);
+
+        let mut expected = reply_header(44);
+        expected.extend_from_slice(&0x0A0B0C0Du64.to_le_bytes());
+        expected.extend_from_slice(&1_000_000_000u32.to_le_bytes());
+        expected.extend_from_slice(&1u32.to_le_bytes());
+        expected.extend_from_slice(&0u64.to_le_bytes());
+
+        let mut pkt = QTPacketTIME::new().reply_packet(CORRELATION_ID, t).unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), expected.as_slice());
+    }
+
+    #[test]
+    fn skew_reply_bytes() {
+        let mut expected = reply_header(28);
+        expected.extend_from_slice(&48000f64.to_le_bytes());
+
+        let mut pkt = QTPacketSKEW::new()
+            .reply_packet(CORRELATION_ID, 48000f64)
+            .unwrap();
+        assert_eq!(pkt.as_bytes().unwrap(), expected.as_slice());
+    }
+
+    #[test]
+    fn as_bytes_twice_is_stable() {
+        let mut pkt = QTPacketSKEW::new()
+            .reply_packet(CORRELATION_ID, 48000f64)
+            .unwrap();
+        pkt.write_u32(0xAABBCCDD).unwrap();
+
+        let first = pkt.as_bytes().unwrap().to_vec();
+        let second = pkt.as_bytes().unwrap().to_vec();
+
+        assert_eq!(first, second);
+        assert_eq!(&first[..4], &32u32.to_le_bytes());
+        assert_eq!(pkt.to_vec(), first);
+    }
+}
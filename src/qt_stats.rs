@@ -0,0 +1,161 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub struct QTStats {
+    sent_samples: AtomicU64,
+    dropped_samples: AtomicU64,
+    watchdog_restarts: AtomicU64,
+    zero_length_reads: AtomicU64,
+    resyncs: AtomicU64,
+    runt_packets: AtomicU64,
+    malformed_packets: AtomicU64,
+    // last send_ping round trip in microseconds, 0 until measured
+    ping_rtt_us: AtomicU64,
+    // clock refs negotiated in the handshake, 0 until known
+    need_clock_ref: AtomicU64,
+    device_audio_clock: AtomicU64,
+    host_clock_id: AtomicU64,
+}
+
+fn clock_ref(v: &AtomicU64) -> Option<u64> {
+    match v.load(Ordering::Relaxed) {
+        0 => None,
+        r => Some(r),
+    }
+}
+
+impl QTStats {
+    pub fn new() -> QTStats {
+        QTStats {
+            sent_samples: AtomicU64::new(0),
+            dropped_samples: AtomicU64::new(0),
+            watchdog_restarts: AtomicU64::new(0),
+            zero_length_reads: AtomicU64::new(0),
+            resyncs: AtomicU64::new(0),
+            runt_packets: AtomicU64::new(0),
+            malformed_packets: AtomicU64::new(0),
+            ping_rtt_us: AtomicU64::new(0),
+            need_clock_ref: AtomicU64::new(0),
+            device_audio_clock: AtomicU64::new(0),
+            host_clock_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sent_samples(&self) -> u64 {
+        self.sent_samples.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples.load(Ordering::Relaxed)
+    }
+
+    pub fn watchdog_restarts(&self) -> u64 {
+        self.watchdog_restarts.load(Ordering::Relaxed)
+    }
+
+    pub fn zero_length_reads(&self) -> u64 {
+        self.zero_length_reads.load(Ordering::Relaxed)
+    }
+
+    // times packet framing was lost and the pool realigned
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs.load(Ordering::Relaxed)
+    }
+
+    // sync/asyn packets too short for their header, dropped
+    pub fn runt_packets(&self) -> u64 {
+        self.runt_packets.load(Ordering::Relaxed)
+    }
+
+    // sync/asyn packets whose payload didn't parse, dropped
+    pub fn malformed_packets(&self) -> u64 {
+        self.malformed_packets.load(Ordering::Relaxed)
+    }
+
+    // round trip of the last answered QuickTime::send_ping
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        match self.ping_rtt_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    pub fn need_clock_ref(&self) -> Option<u64> {
+        clock_ref(&self.need_clock_ref)
+    }
+
+    pub fn device_audio_clock(&self) -> Option<u64> {
+        clock_ref(&self.device_audio_clock)
+    }
+
+    pub fn host_clock_id(&self) -> Option<u64> {
+        clock_ref(&self.host_clock_id)
+    }
+
+    pub(crate) fn set_clock_refs(
+        &self,
+        need_clock_ref: Option<u64>,
+        device_audio_clock: Option<u64>,
+        host_clock_id: Option<u64>,
+    ) {
+        self.need_clock_ref
+            .store(need_clock_ref.unwrap_or(0), Ordering::Relaxed);
+        self.device_audio_clock
+            .store(device_audio_clock.unwrap_or(0), Ordering::Relaxed);
+        self.host_clock_id
+            .store(host_clock_id.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_sent_samples(&self) {
+        self.sent_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_dropped_samples(&self) {
+        self.dropped_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_watchdog_restarts(&self) {
+        self.watchdog_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_zero_length_reads(&self) {
+        self.zero_length_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_resyncs(&self) {
+        self.resyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_ping_rtt(&self, rtt: Duration) {
+        self.ping_rtt_us
+            .store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_runt_packets(&self) {
+        self.runt_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_malformed_packets(&self) {
+        self.malformed_packets.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Debug for QTStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "sent_samples: {}\ndropped_samples: {}\nwatchdog_restarts: {}\nzero_length_reads: {}\nresyncs: {}\nrunt_packets: {}\nmalformed_packets: {}\nping_rtt: {:?}\nneed_clock_ref: {:?}\ndevice_audio_clock: {:?}\nhost_clock_id: {:?}\n",
+            self.sent_samples(),
+            self.dropped_samples(),
+            self.watchdog_restarts(),
+            self.zero_length_reads(),
+            self.resyncs(),
+            self.runt_packets(),
+            self.malformed_packets(),
+            self.ping_rtt(),
+            self.need_clock_ref(),
+            self.device_audio_clock(),
+            self.host_clock_id(),
+        ))
+    }
+}
@@ -13,6 +13,20 @@ const MAGIC_KEY_STRING_VALUE: u32 = 0x73747276; // strv - vrts
 const MAGIC_KEY_NUMBER_VALUE: u32 = 0x6E6D6276; // nmbv - vbmn
 const MAGIC_KEY_IDX: u32 = 0x6964786B;
 
+// real dictionaries go 3-4 levels deep
+const MAX_NESTING_DEPTH: u32 = 32;
+
+// bytes a number value needs for its spec byte, unknown specs are rejected
+// after the length check
+fn number_len(data: &[u8]) -> usize {
+    match data.first() {
+        Some(6) | Some(4) => 9,
+        Some(5) | Some(3) => 5,
+        _ => 1,
+    }
+}
+
+#[derive(Clone)]
 pub struct QTKeyValuePair {
     key: QTValue,
     value: QTValue,
@@ -32,6 +46,7 @@ impl QTKeyValuePair {
     }
 }
 
+#[derive(Clone)]
 pub enum QTValue {
     StringKey(String),
     StringValue(String),
@@ -181,22 +196,11 @@ impl QTValue {
                 Err(e) => return Err(e),
                 _ => {}
             },
-            QTValue::FormatDescriptor(d) => {
-                let mut fd_pkt = match d.as_qt_packet() {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                let fd_buffer = match fd_pkt.as_bytes() {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                match pkt.write(fd_buffer) {
-                    Err(e) => return Err(e),
-                    _ => {}
-                };
-            }
+            // the fdsc header is already written, only the boxes follow
+            QTValue::FormatDescriptor(d) => match d.write_boxes(&mut pkt) {
+                Err(e) => return Err(e),
+                _ => {}
+            },
             QTValue::IdxKey(i) => match pkt.write_u16(*i) {
                 Err(e) => return Err(e),
                 _ => {}
@@ -207,6 +211,19 @@ impl QTValue {
     }
 
     pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<QTValue, Error> {
+        QTValue::from_qt_packet_nested(pkt, 0)
+    }
+
+    // depth counts enclosing values, format descriptors included, so a
+    // device can't nest its way into a stack overflow
+    pub(crate) fn from_qt_packet_nested(pkt: &mut QTPacket, depth: u32) -> Result<QTValue, Error> {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "qt value nested too deep",
+            ));
+        }
+
         let pkt_len = match pkt.read_u32() {
             Ok(m) => m,
             Err(e) => return Err(e),
@@ -217,13 +234,17 @@ impl QTValue {
             Err(e) => return Err(e),
         };
 
+        if pkt_len < 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "qt value length"));
+        }
+
         let obj_val = match magic {
             MAGIC_KEY_VALUE_PAIR => Some(QTValue::KeyValuePair(Box::new(QTKeyValuePair {
-                key: match QTValue::from_qt_packet(pkt) {
+                key: match QTValue::from_qt_packet_nested(pkt, depth + 1) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 },
-                value: match QTValue::from_qt_packet(pkt) {
+                value: match QTValue::from_qt_packet_nested(pkt, depth + 1) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 },
@@ -237,7 +258,7 @@ impl QTValue {
 
                 let mut arr: Vec<QTValue> = Vec::new();
                 loop {
-                    match QTValue::from_qt_packet(&mut obj_pkt) {
+                    match QTValue::from_qt_packet_nested(&mut obj_pkt, depth + 1) {
                         Ok(e) => arr.push(e),
                         Err(e) => match e.kind() {
                             ErrorKind::UnexpectedEof => break,
@@ -248,10 +269,12 @@ impl QTValue {
 
                 Some(QTValue::Object(arr))
             }
-            MAGIC_FORMAT_DESCRIPTOR => match FormatDescriptor::from_qt_packet(pkt) {
-                Ok(e) => Some(QTValue::FormatDescriptor(Box::new(e))),
-                Err(e) => return Err(e),
-            },
+            MAGIC_FORMAT_DESCRIPTOR => {
+                match FormatDescriptor::from_qt_packet_nested(pkt, depth + 1) {
+                    Ok(e) => Some(QTValue::FormatDescriptor(Box::new(e))),
+                    Err(e) => return Err(e),
+                }
+            }
             _ => None,
         };
 
@@ -259,6 +282,10 @@ impl QTValue {
             return Ok(obj_val.unwrap());
         }
 
+        if pkt_len as u64 - 8 > pkt.remaining() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "qt value length"));
+        }
+
         let mut data: Vec<u8> = vec![0; pkt_len as usize - 8];
         match pkt.read_exact(&mut data) {
             Ok(e) => e,
@@ -270,16 +297,23 @@ impl QTValue {
                 Ok(e) => e,
                 Err(_err) => return Err(Error::new(ErrorKind::InvalidData, "string utf8")),
             })),
-            MAGIC_KEY_STRING_VALUE => Ok(QTValue::StringKey(match String::from_utf8(data) {
+            MAGIC_KEY_STRING_VALUE => Ok(QTValue::StringValue(match String::from_utf8(data) {
                 Ok(e) => e,
                 Err(_err) => return Err(Error::new(ErrorKind::InvalidData, "string utf8")),
             })),
+            MAGIC_KEY_BOOLEAN if data.is_empty() => {
+                Err(Error::new(ErrorKind::InvalidData, "boolean length"))
+            }
             MAGIC_KEY_BOOLEAN => match data[0] {
                 0 => Ok(QTValue::Boolean(false)),
                 1 => Ok(QTValue::Boolean(true)),
                 _ => return Err(Error::new(ErrorKind::InvalidData, "boolean overflow")),
             },
             MAGIC_KEY_DATA_VALUE => Ok(QTValue::Data(data)),
+            // spec byte followed by the value
+            MAGIC_KEY_NUMBER_VALUE if data.len() < number_len(&data) => {
+                Err(Error::new(ErrorKind::InvalidData, "number length"))
+            }
             MAGIC_KEY_NUMBER_VALUE => match data[0] {
                 6 => Ok(QTValue::Float(f64::from_le_bytes([
                     data[1], data[2], data[3], data[4], data[5], data[6], data[7], data[8],
@@ -295,41 +329,50 @@ impl QTValue {
                 ]))),
                 _ => return Err(Error::new(ErrorKind::InvalidData, "unknown number spec")),
             },
+            MAGIC_KEY_IDX if data.len() < 2 => {
+                Err(Error::new(ErrorKind::InvalidData, "idx length"))
+            }
             MAGIC_KEY_IDX => Ok(QTValue::IdxKey(u16::from_le_bytes([data[0], data[1]]))),
             _ => return Err(Error::new(ErrorKind::InvalidData, "unknown magic")),
         }
     }
 
+    // ident prefixes the first line, every nesting level adds 4 spaces
     pub fn to_str(&self, ident: String) -> String {
+        format!("{}{}", ident, self.to_str_inline(&ident))
+    }
+
+    // the value without a leading indent, to follow a label like "Key: ".
+    // lines below the first are indented one level deeper than ident
+    fn to_str_inline(&self, ident: &str) -> String {
+        let inner = format!("{}    ", ident);
+
         match self {
-            QTValue::StringKey(s) => format!("{}StringKey={}", ident, s.as_str()),
-            QTValue::StringValue(s) => format!("{}StringValue={}", ident, s.as_str()),
-            QTValue::Boolean(b) => format!("{}Boolean={}", ident, b),
+            QTValue::StringKey(s) => format!("StringKey={}", s.as_str()),
+            QTValue::StringValue(s) => format!("StringValue={}", s.as_str()),
+            QTValue::Boolean(b) => format!("Boolean={}", b),
             QTValue::KeyValuePair(kv) => format!(
-                "{}KeyValuePair(\n{}  Key: {},\n{}  Value: {},\n{})",
-                ident,
-                ident,
-                kv.key.to_str(String::from(&ident)),
-                ident,
-                kv.value.to_str(String::from(&ident)),
+                "KeyValuePair(\n{}Key: {},\n{}Value: {},\n{})",
+                inner,
+                kv.key.to_str_inline(&inner),
+                inner,
+                kv.value.to_str_inline(&inner),
                 ident
             ),
             QTValue::Object(o) => {
-                let mut str = format!("{}Object(\n", ident);
+                let mut str = String::from("Object(\n");
                 for v in o {
-                    let mut si = String::from(ident.as_str());
-                    si.push_str("    ");
-                    str += format!("{}\n", v.to_str(si)).as_str()
+                    str += format!("{}\n", v.to_str(inner.clone())).as_str()
                 }
-                str += format!("{}  )", ident).as_str();
+                str += format!("{})", ident).as_str();
                 str
             }
-            QTValue::Data(d) => format!("{}Data={}", ident, hex::encode(d)),
-            QTValue::Float(f) => format!("{}Float={}", ident, f),
-            QTValue::UInt32(i) => format!("{}UInt32={}", ident, i),
-            QTValue::UInt64(i) => format!("{}UInt64={}", ident, i),
-            QTValue::IdxKey(i) => format!("{}IdxKey={}", ident, i),
-            QTValue::FormatDescriptor(_fd) => format!("{}FormatDescriptor=...", ident),
+            QTValue::Data(d) => format!("Data={}", hex::encode(d)),
+            QTValue::Float(f) => format!("Float={}", f),
+            QTValue::UInt32(i) => format!("UInt32={}", i),
+            QTValue::UInt64(i) => format!("UInt64={}", i),
+            QTValue::IdxKey(i) => format!("IdxKey={}", i),
+            QTValue::FormatDescriptor(_fd) => String::from("FormatDescriptor=..."),
         }
     }
 
@@ -396,6 +439,239 @@ impl QTValue {
             _ => None,
         }
     }
+
+    // set a string keyed entry of an object, replacing an existing value or
+    // appending the pair. no-op on anything but an object
+    pub fn set_key(&mut self, key: &str, value: QTValue) {
+        let o = match self {
+            QTValue::Object(o) => o,
+            _ => return,
+        };
+
+        for v in o.iter_mut() {
+            match v {
+                QTValue::KeyValuePair(kv) if kv.key.as_string().as_deref() == Some(key) => {
+                    kv.value = value;
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        o.push(QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(String::from(key)),
+            value,
+        )));
+    }
+
+    pub fn as_format_descriptor(&self) -> Option<&FormatDescriptor> {
+        match self {
+            QTValue::FormatDescriptor(fd) => Some(fd),
+            _ => None,
+        }
+    }
+
+    // depth first search through objects and key/value pairs for the first
+    // format descriptor
+    pub fn find_format_descriptor(&self) -> Option<&FormatDescriptor> {
+        match self {
+            QTValue::FormatDescriptor(fd) => Some(fd),
+            QTValue::KeyValuePair(kv) => kv.value.find_format_descriptor(),
+            QTValue::Object(o) => o.iter().find_map(|v| v.find_format_descriptor()),
+            _ => None,
+        }
+    }
+}
+
+// json objects from_json reads back as something other than a dictionary
+#[cfg(feature = "serde")]
+fn is_json_tag(dict: &serde_json::Map<String, serde_json::Value>) -> bool {
+    match dict.len() {
+        1 => dict.contains_key("$data") || dict.contains_key("$fdsc"),
+        2 => dict.contains_key("key") && dict.contains_key("value"),
+        _ => false,
+    }
+}
+
+// compact json for dumping, diffing and replaying dictionaries. an object of unique
+// string keyed pairs becomes a json object (keys sorted), any other object an array,
+// a lone pair {"key": .., "value": ..}. numbers lose their width, data is hex
+// wrapped as {"$data": ".."} so it stays apart from strings, a format descriptor
+// its whole fdsc box as {"$fdsc": ".."}. a dictionary that would look like one of
+// those tags is written as an array of pairs instead
+#[cfg(feature = "serde")]
+impl QTValue {
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::{Map, Value};
+
+        match self {
+            QTValue::StringKey(s) | QTValue::StringValue(s) => Value::String(s.clone()),
+            QTValue::Boolean(b) => Value::Bool(*b),
+            QTValue::UInt32(i) => Value::from(*i),
+            QTValue::UInt64(i) => Value::from(*i),
+            QTValue::IdxKey(i) => Value::from(*i),
+            // nan and infinities have no json number, they come out as null
+            QTValue::Float(f) => Value::from(*f),
+            QTValue::Data(d) => {
+                let mut data = Map::new();
+                data.insert(String::from("$data"), Value::String(hex::encode(d)));
+                Value::Object(data)
+            }
+            QTValue::KeyValuePair(kv) => {
+                let mut pair = Map::new();
+                pair.insert(String::from("key"), kv.key.to_json());
+                pair.insert(String::from("value"), kv.value.to_json());
+                Value::Object(pair)
+            }
+            QTValue::Object(o) => {
+                let mut dict = Map::new();
+
+                for v in o {
+                    match v {
+                        QTValue::KeyValuePair(kv) => match &kv.key {
+                            // a repeated key would be lost in a json object
+                            QTValue::StringKey(key) if !dict.contains_key(key) => {
+                                dict.insert(key.clone(), kv.value.to_json());
+                                continue;
+                            }
+                            _ => {}
+                        },
+                        _ => {}
+                    };
+
+                    return Value::Array(o.iter().map(|v| v.to_json()).collect());
+                }
+
+                if is_json_tag(&dict) {
+                    return Value::Array(o.iter().map(|v| v.to_json()).collect());
+                }
+
+                Value::Object(dict)
+            }
+            QTValue::FormatDescriptor(fd) => match fd.as_qt_packet() {
+                Ok(mut pkt) => {
+                    let mut fdsc = Map::new();
+                    fdsc.insert(
+                        String::from("$fdsc"),
+                        Value::String(hex::encode(pkt.to_vec())),
+                    );
+                    Value::Object(fdsc)
+                }
+                // only a failed in memory write, nothing to tag
+                Err(_) => Value::Null,
+            },
+        }
+    }
+
+    // a whole fdsc box as written by FormatDescriptor::as_qt_packet
+    fn format_descriptor_from_hex(fdsc: &str) -> Result<QTValue, Error> {
+        let data = match hex::decode(fdsc) {
+            Ok(e) => e,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        let mut pkt = match QTPacket::from_bytes(&data) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        match pkt.read_u32() {
+            Ok(MAGIC_FORMAT_DESCRIPTOR) => {}
+            Ok(_) => return Err(Error::new(ErrorKind::InvalidData, "json fdsc magic")),
+            Err(e) => return Err(e),
+        };
+
+        match FormatDescriptor::from_qt_packet(&mut pkt) {
+            Ok(e) => Ok(QTValue::FormatDescriptor(Box::new(e))),
+            Err(e) => Err(e),
+        }
+    }
+
+    // the reverse of to_json, to build dictionaries at runtime. strings are
+    // StringValue (StringKey as object keys), whole numbers UInt32 or UInt64
+    // when they don't fit, anything with a fraction or sign Float. an object
+    // with exactly "key" and "value" is read back as a lone pair, one with only
+    // "$data" or "$fdsc" as data or a format descriptor
+    pub fn from_json(value: &serde_json::Value) -> Result<QTValue, Error> {
+        use serde_json::Value;
+
+        match value {
+            Value::Null => Err(Error::new(
+                ErrorKind::InvalidData,
+                "json null has no qt value",
+            )),
+            Value::Bool(b) => Ok(QTValue::Boolean(*b)),
+            Value::Number(n) => match (n.as_u64(), n.as_f64()) {
+                (Some(u), _) if u <= u32::MAX as u64 => Ok(QTValue::UInt32(u as u32)),
+                (Some(u), _) => Ok(QTValue::UInt64(u)),
+                (None, Some(f)) => Ok(QTValue::Float(f)),
+                (None, None) => Err(Error::new(ErrorKind::InvalidData, "json number")),
+            },
+            Value::String(s) => Ok(QTValue::StringValue(s.clone())),
+            Value::Array(arr) => {
+                let mut o: Vec<QTValue> = Vec::with_capacity(arr.len());
+                for v in arr {
+                    match QTValue::from_json(v) {
+                        Ok(e) => o.push(e),
+                        Err(e) => return Err(e),
+                    };
+                }
+                Ok(QTValue::Object(o))
+            }
+            Value::Object(dict) => {
+                match (dict.len(), dict.get("$data")) {
+                    (1, Some(Value::String(data))) => {
+                        return match hex::decode(data) {
+                            Ok(e) => Ok(QTValue::Data(e)),
+                            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+                        }
+                    }
+                    _ => {}
+                };
+
+                match (dict.len(), dict.get("$fdsc")) {
+                    (1, Some(Value::String(fdsc))) => {
+                        return QTValue::format_descriptor_from_hex(fdsc)
+                    }
+                    _ => {}
+                };
+
+                match (dict.len(), dict.get("key"), dict.get("value")) {
+                    (2, Some(key), Some(value)) => {
+                        let key = match key {
+                            Value::String(s) => QTValue::StringKey(s.clone()),
+                            Value::Number(n) => match n.as_u64() {
+                                Some(idx) if idx <= u16::MAX as u64 => QTValue::IdxKey(idx as u16),
+                                _ => {
+                                    return Err(Error::new(ErrorKind::InvalidData, "json pair key"))
+                                }
+                            },
+                            _ => return Err(Error::new(ErrorKind::InvalidData, "json pair key")),
+                        };
+
+                        return match QTValue::from_json(value) {
+                            Ok(value) => Ok(QTValue::KeyValuePair(QTKeyValuePair::new(key, value))),
+                            Err(e) => Err(e),
+                        };
+                    }
+                    _ => {}
+                };
+
+                let mut o: Vec<QTValue> = Vec::with_capacity(dict.len());
+                for (key, v) in dict {
+                    let value = match QTValue::from_json(v) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    };
+                    o.push(QTValue::KeyValuePair(QTKeyValuePair::new(
+                        QTValue::StringKey(key.clone()),
+                        value,
+                    )));
+                }
+                Ok(QTValue::Object(o))
+            }
+        }
+    }
 }
 
 impl Debug for QTValue {
@@ -405,3 +681,122 @@ impl Debug for QTValue {
         f.write_str("\n")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_str_indents_by_depth() {
+        let inner = QTValue::Object(vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(String::from("b")),
+            QTValue::UInt32(1),
+        ))]);
+        let value = QTValue::Object(vec![
+            QTValue::KeyValuePair(QTKeyValuePair::new(
+                QTValue::StringKey(String::from("a")),
+                inner,
+            )),
+            QTValue::Boolean(true),
+        ]);
+
+        let expected = [
+            "Object(",
+            "    KeyValuePair(",
+            "        Key: StringKey=a,",
+            "        Value: Object(",
+            "            KeyValuePair(",
+            "                Key: StringKey=b,",
+            "                Value: UInt32=1,",
+            "            )",
+            "        ),",
+            "    )",
+            "    Boolean=true",
+            ")",
+        ]
+        .join("\n");
+        assert_eq!(value.to_str(String::new()), expected);
+
+        // the ident prefixes the first line and every line below it
+        let pair = QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(52),
+            QTValue::Data(vec![0xAB]),
+        ));
+        assert_eq!(
+            pair.to_str(String::from("  ")),
+            "  KeyValuePair(\n      Key: IdxKey=52,\n      Value: Data=ab,\n  )"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    fn json_round_trip(value: &QTValue) -> QTValue {
+        let text = serde_json::to_string(&value.to_json()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        QTValue::from_json(&json).unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    fn get<'a>(dict: &'a QTValue, key: &str) -> &'a QTValue {
+        dict.as_vec()
+            .unwrap()
+            .iter()
+            .filter_map(|v| v.as_pair())
+            .find(|kv| kv.key().as_string().as_deref() == Some(key))
+            .map(|kv| kv.value())
+            .unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    fn pair(key: &str, value: QTValue) -> QTValue {
+        QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(String::from(key)),
+            value,
+        ))
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn tag_like_dictionaries_stay_dictionaries() {
+        let key_value = QTValue::Object(vec![
+            pair("key", QTValue::StringValue(String::from("a"))),
+            pair("value", QTValue::UInt32(1)),
+        ]);
+        let back = json_round_trip(&key_value);
+        assert!(back.as_pair().is_none());
+        assert_eq!(back.as_vec().unwrap().len(), 2);
+        assert_eq!(get(&back, "value").as_u32(), Some(1));
+
+        let data = QTValue::Object(vec![pair(
+            "$data",
+            QTValue::StringValue(String::from("00")),
+        )]);
+        let back = json_round_trip(&data);
+        assert!(back.as_data().is_none());
+        assert_eq!(get(&back, "$data").as_string().as_deref(), Some("00"));
+
+        // a real pair and real data still come back as such
+        let back = json_round_trip(&pair("key", QTValue::Data(vec![1, 2])));
+        assert_eq!(back.as_pair().unwrap().value().as_data(), Some(&vec![1, 2]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn format_descriptor_json_round_trip() {
+        use crate::coremedia::audio_desc::AudioStreamDescription;
+
+        let fd = FormatDescriptor::new_audio(AudioStreamDescription::default());
+        let dict = QTValue::Object(vec![pair("fdsc", QTValue::FormatDescriptor(Box::new(fd)))]);
+
+        let back = json_round_trip(&dict);
+        assert_eq!(
+            back.as_qt_packet().unwrap().to_vec(),
+            dict.as_qt_packet().unwrap().to_vec()
+        );
+        assert_eq!(
+            back.find_format_descriptor()
+                .unwrap()
+                .audio_stream_description(),
+            &AudioStreamDescription::default()
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+// how often and how patiently something is retried: bulk transfers, the
+// wait for the device to come back with QuickTime enabled, session restarts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    // tries including the first one, 0 and 1 both mean no retry
+    pub max_attempts: u32,
+    // wait before the first retry
+    pub base_delay: Duration,
+    // the wait never grows past this
+    pub max_delay: Duration,
+    // factor the wait grows by per retry, 1.0 keeps base_delay
+    pub backoff: f64,
+}
+
+impl RetryPolicy {
+    // fail on the first error
+    pub const fn no_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            backoff: 1f64,
+        }
+    }
+
+    pub const fn fixed(max_attempts: u32, delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: delay,
+            max_delay: delay,
+            backoff: 1f64,
+        }
+    }
+
+    // doubles the wait every retry up to max_delay
+    pub const fn exponential(
+        max_attempts: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+    ) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay,
+            max_delay,
+            backoff: 2f64,
+        }
+    }
+
+    // whether another try is allowed after attempt tries failed
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    // wait before retry n, 1 based
+    pub fn delay(&self, retry: u32) -> Duration {
+        let max = self.max_delay.max(self.base_delay).as_secs_f64();
+        let secs = self.base_delay.as_secs_f64()
+            * self.backoff.max(1f64).powi(retry.saturating_sub(1) as i32);
+
+        match secs.is_finite() && secs < max {
+            true => Duration::from_secs_f64(secs),
+            false => Duration::from_secs_f64(max),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::no_retry()
+    }
+}
@@ -0,0 +1,240 @@
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use crate::h264;
+use std::io::{Error, ErrorKind};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::SystemTime;
+
+pub const RTP_PAYLOAD_TYPE_H264: u8 = 96;
+
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_LEN: usize = 12;
+const RTP_CLOCK_RATE: u128 = 90_000;
+const DEFAULT_MTU: usize = 1400;
+// header, FU indicator, FU header and one byte of the nal unit
+const MIN_MTU: usize = RTP_HEADER_LEN + 3;
+
+const NALU_TYPE_FU_A: u8 = 28;
+
+// RFC 6184 packetization over udp, single nal unit packets when they fit the
+// mtu and FU-A fragments otherwise. sps/pps are resent ahead of every keyframe
+pub struct RtpSender {
+    socket: UdpSocket,
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+    mtu: usize,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl RtpSender {
+    pub fn new<A: ToSocketAddrs>(dest: A) -> Result<RtpSender, Error> {
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        match socket.connect(dest) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let seed = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(e) => e.subsec_nanos(),
+            Err(_) => 0,
+        } ^ std::process::id();
+
+        Ok(RtpSender {
+            socket,
+            payload_type: RTP_PAYLOAD_TYPE_H264,
+            ssrc: seed,
+            sequence: seed as u16,
+            mtu: DEFAULT_MTU,
+            sps: None,
+            pps: None,
+        })
+    }
+
+    pub fn set_payload_type(&mut self, payload_type: u8) {
+        self.payload_type = payload_type & 0x7F;
+    }
+
+    // anything below MIN_MTU can't carry a FU-A fragment, the old mtu is kept
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
+        if mtu < MIN_MTU {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("rtp mtu {} below minimum {}", mtu, MIN_MTU),
+            ));
+        }
+
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    pub fn send(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        if sample_buffer.media_type() != MEDIA_TYPE_VIDEO {
+            return Ok(());
+        }
+
+        match sample_buffer.format_description().and_then(|fd| fd.avc1()) {
+            Some(avc1) => {
+                self.sps = Some(Vec::from(avc1.sps()));
+                self.pps = Some(Vec::from(avc1.pps()));
+            }
+            None => {}
+        };
+
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let nalus = match h264::split_nalus(data) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let timestamp = match sample_buffer.presentation_time_stamp() {
+            Some(t) if t.scale() > 0 => {
+                (t.value() as u128 * RTP_CLOCK_RATE / t.scale() as u128) as u32
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "sample without pts")),
+        };
+
+        if sample_buffer.is_sync_sample() {
+            let sps = self.sps.clone();
+            let pps = self.pps.clone();
+            for params in [sps, pps].iter().flatten() {
+                match self.send_nalu(params, timestamp, false) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+            }
+        }
+
+        for (i, nalu) in nalus.iter().enumerate() {
+            match self.send_nalu(nalu, timestamp, i == nalus.len() - 1) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        Ok(())
+    }
+
+    fn send_nalu(&mut self, nalu: &[u8], timestamp: u32, marker: bool) -> Result<(), Error> {
+        let max_payload = self.mtu - RTP_HEADER_LEN;
+
+        if nalu.len() <= max_payload {
+            return self.send_packet(nalu, timestamp, marker);
+        }
+
+        let header = nalu[0];
+        let indicator = (header & 0xE0) | NALU_TYPE_FU_A;
+        let nalu_type = header & 0x1F;
+
+        let mut payload: Vec<u8> = Vec::with_capacity(max_payload);
+        let mut chunks = nalu[1..].chunks(max_payload - 2).peekable();
+        let mut start = true;
+
+        while let Some(chunk) = chunks.next() {
+            let end = chunks.peek().is_none();
+
+            let mut fu_header = nalu_type;
+            if start {
+                fu_header |= 0x80;
+            }
+            if end {
+                fu_header |= 0x40;
+            }
+
+            payload.clear();
+            payload.push(indicator);
+            payload.push(fu_header);
+            payload.extend_from_slice(chunk);
+
+            match self.send_packet(&payload, timestamp, marker && end) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+
+            start = false;
+        }
+
+        Ok(())
+    }
+
+    fn send_packet(&mut self, payload: &[u8], timestamp: u32, marker: bool) -> Result<(), Error> {
+        let mut buf: Vec<u8> = Vec::with_capacity(RTP_HEADER_LEN + payload.len());
+
+        let marker_bit = match marker {
+            true => 0x80,
+            false => 0,
+        };
+
+        buf.push(RTP_VERSION << 6);
+        buf.push(marker_bit | self.payload_type);
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.ssrc.to_be_bytes());
+        buf.extend_from_slice(payload);
+
+        self.sequence = self.sequence.wrapping_add(1);
+
+        match self.socket.send(&buf) {
+            Err(e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_mtu_is_rejected() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sender = RtpSender::new(receiver.local_addr().unwrap()).unwrap();
+
+        let err = sender.set_mtu(MIN_MTU - 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(sender.mtu, DEFAULT_MTU);
+        assert!(sender.set_mtu(0).is_err());
+    }
+
+    #[test]
+    fn min_mtu_fragments_one_byte_per_packet() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sender = RtpSender::new(receiver.local_addr().unwrap()).unwrap();
+        sender.set_mtu(MIN_MTU).unwrap();
+
+        // idr header and 4 bytes of slice data
+        let nalu = [0x65, 1, 2, 3, 4];
+        sender.send_nalu(&nalu, 0, true).unwrap();
+
+        let mut buf = [0u8; 64];
+        for i in 0..4 {
+            let n = receiver.recv(&mut buf).unwrap();
+            assert_eq!(n, MIN_MTU);
+            assert_eq!(buf[RTP_HEADER_LEN], 0x60 | NALU_TYPE_FU_A);
+
+            let mut fu_header = 0x05;
+            if i == 0 {
+                fu_header |= 0x80;
+            }
+            if i == 3 {
+                fu_header |= 0x40;
+            }
+            assert_eq!(buf[RTP_HEADER_LEN + 1], fu_header);
+            assert_eq!(buf[RTP_HEADER_LEN + 2], i as u8 + 1);
+            // marker only on the last fragment
+            assert_eq!(buf[1] & 0x80 != 0, i == 3);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::coremedia::sample::SampleBuffer;
+use crate::sink::SampleSink;
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// collects the annex-b video stream in memory, sps/pps in front of every
+// sample that carries a format description. once the next access unit would
+// go past cap the capture is full: nothing more is kept and term, if set, is
+// raised so the loop winds down. the output never exceeds cap and always ends
+// on a whole access unit
+pub struct MemorySink {
+    cap: usize,
+    data: Vec<u8>,
+    access_units: u64,
+    full: bool,
+    term: Option<Arc<AtomicBool>>,
+}
+
+impl MemorySink {
+    pub fn new(cap: usize) -> MemorySink {
+        MemorySink {
+            cap,
+            data: Vec::new(),
+            access_units: 0,
+            full: false,
+            term: None,
+        }
+    }
+
+    // usually QuickTime::term, stores true once the cap is hit
+    pub fn set_term(&mut self, term: Option<Arc<AtomicBool>>) {
+        self.term = term;
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn access_units(&self) -> u64 {
+        self.access_units
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+}
+
+impl SampleSink for MemorySink {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        if self.full {
+            return Ok(());
+        }
+
+        let au = match sample_buffer.to_access_unit() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if self.data.len() + au.data.len() > self.cap {
+            self.full = true;
+
+            match &self.term {
+                Some(term) => term.store(true, Ordering::Relaxed),
+                None => {}
+            };
+
+            return Ok(());
+        }
+
+        self.data.extend_from_slice(&au.data);
+        self.access_units += 1;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::coremedia::sample::SampleBuffer;
+#[cfg(feature = "mp4")]
+use crate::mux::fmp4::FragmentedMp4Writer;
+#[cfg(feature = "mkv")]
+use crate::mux::mkv::MkvWriter;
+#[cfg(feature = "mp4")]
+use crate::mux::mp4::Mp4Writer;
+#[cfg(feature = "rtp")]
+use crate::rtp::RtpSender;
+use std::io::Error;
+#[cfg(feature = "mp4")]
+use std::io::Seek;
+#[cfg(any(feature = "mkv", feature = "mp4"))]
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+pub mod memory;
+#[cfg(feature = "pcm")]
+pub mod pcm;
+pub mod ring;
+#[cfg(feature = "segment")]
+pub mod segment;
+#[cfg(feature = "wav")]
+pub mod wav;
+
+// consumer side of the sample channel, anything that takes the samples
+// QuickTime::run hands out and puts them somewhere
+pub trait SampleSink {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error>;
+
+    // called once after the last sample, muxers patch headers/indexes here
+    fn finalize(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// feed everything from the channel into the sink until the stream ends, by
+// sentinel (STOP, ctrl-c, loop error) or a dropped sender. finalize runs
+// exactly once in every case, also when a write failed, so files are closed
+// properly. the first error is returned
+pub fn consume<S: SampleSink + ?Sized>(
+    rx: &Receiver<Result<SampleBuffer, Error>>,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut result: Result<(), Error> = Ok(());
+
+    loop {
+        let sample_buffer = match rx.recv() {
+            Ok(Ok(e)) => e,
+            _ => break,
+        };
+
+        match sink.write(&sample_buffer) {
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+            _ => {}
+        };
+    }
+
+    match sink.finalize() {
+        Err(e) if result.is_ok() => result = Err(e),
+        _ => {}
+    };
+
+    result
+}
+
+#[cfg(feature = "mkv")]
+impl<W: Write> SampleSink for MkvWriter<W> {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        self.write_sample_buffer(sample_buffer)
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(feature = "mp4")]
+impl<W: Write + Seek> SampleSink for Mp4Writer<W> {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        self.write_sample_buffer(sample_buffer)
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(feature = "mp4")]
+impl<W: Write> SampleSink for FragmentedMp4Writer<W> {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        self.write_sample_buffer(sample_buffer)
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(feature = "rtp")]
+impl SampleSink for RtpSender {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        self.send(sample_buffer)
+    }
+}
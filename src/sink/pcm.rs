@@ -0,0 +1,73 @@
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND};
+use crate::sink::SampleSink;
+use std::io::{Error, Write};
+
+// headerless lpcm dump: EAT sample data concatenated as it arrives, in the
+// byte order the asbd format flags announce. the format is logged once so the
+// file can be wrapped later, e.g. ffmpeg -f s16le -ar 48000 -ac 2 -i audio.pcm
+pub struct RawPcmSink<W: Write> {
+    writer: W,
+    audio_desc: Option<AudioStreamDescription>,
+    bytes_written: u64,
+}
+
+impl<W: Write> RawPcmSink<W> {
+    pub fn new(writer: W) -> RawPcmSink<W> {
+        RawPcmSink {
+            writer,
+            audio_desc: None,
+            bytes_written: 0,
+        }
+    }
+
+    // format of the samples written so far, None before the first audio sample
+    pub fn audio_desc(&self) -> Option<&AudioStreamDescription> {
+        self.audio_desc.as_ref()
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+impl<W: Write> SampleSink for RawPcmSink<W> {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        if sample_buffer.media_type() != MEDIA_TYPE_SOUND {
+            return Ok(());
+        }
+
+        match sample_buffer.format_description() {
+            Some(fd) if self.audio_desc.is_none() => {
+                let asbd = *fd.audio_stream_description();
+                log::info!(
+                    "raw pcm format: {} {}Hz {}ch",
+                    asbd.sample_format()
+                        .unwrap_or_else(|| format!("flags {:#x}", asbd.format_flags())),
+                    asbd.sample_rate(),
+                    asbd.channels_per_frame()
+                );
+                self.audio_desc = Some(asbd);
+            }
+            _ => {}
+        };
+
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        match self.writer.write_all(data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.bytes_written += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,176 @@
+use crate::coremedia::sample::SampleBuffer;
+use crate::h264;
+use crate::sink::SampleSink;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+struct RingUnit {
+    data: Vec<u8>,
+    pts: f64,
+    keyframe: bool,
+    // sps/pps in effect, annex-b, kept on keyframes so any of them can start
+    // a dump
+    params: Option<Vec<u8>>,
+}
+
+struct Ring {
+    window: Duration,
+    max_bytes: Option<usize>,
+    units: VecDeque<RingUnit>,
+    bytes: usize,
+    params: Option<Vec<u8>>,
+}
+
+impl Ring {
+    fn span(&self, from: usize) -> f64 {
+        match (self.units.get(from), self.units.back()) {
+            (Some(first), Some(last)) => last.pts - first.pts,
+            _ => 0f64,
+        }
+    }
+
+    // drop whole gops from the front while what is left still covers the
+    // window, or while over the byte bound. the front is always a keyframe
+    fn trim(&mut self) {
+        loop {
+            let next_key = match self.units.iter().skip(1).position(|u| u.keyframe) {
+                Some(i) => i + 1,
+                None => return,
+            };
+
+            let over_bytes = match self.max_bytes {
+                Some(max) => self.bytes > max,
+                None => false,
+            };
+
+            if !over_bytes && self.span(next_key) < self.window.as_secs_f64() {
+                return;
+            }
+
+            for _ in 0..next_key {
+                match self.units.pop_front() {
+                    Some(u) => self.bytes -= u.data.len(),
+                    None => {}
+                };
+            }
+        }
+    }
+}
+
+// instant replay: keeps the last window of video as annex-b access units and
+// writes it out on demand. the window only ever starts on a keyframe, so it
+// may reach up to one gop further back than asked. clones share the window,
+// feed one through consume and dump from another
+#[derive(Clone)]
+pub struct RingSink {
+    inner: Arc<Mutex<Ring>>,
+}
+
+impl RingSink {
+    pub fn new(window: Duration) -> RingSink {
+        RingSink {
+            inner: Arc::new(Mutex::new(Ring {
+                window,
+                max_bytes: None,
+                units: VecDeque::new(),
+                bytes: 0,
+                params: None,
+            })),
+        }
+    }
+
+    // also bound the window by size, whichever limit is hit first. a single
+    // gop is kept even when it is larger
+    pub fn set_max_bytes(&self, max_bytes: Option<usize>) {
+        let mut ring = self.inner.lock().expect("ring lock");
+        ring.max_bytes = max_bytes;
+        ring.trim();
+    }
+
+    // bytes and seconds currently held
+    pub fn len_bytes(&self) -> usize {
+        self.inner.lock().expect("ring lock").bytes
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.inner.lock().expect("ring lock").span(0).max(0f64))
+    }
+
+    // write the window as a decodable annex-b stream, sps/pps first
+    pub fn dump<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let ring = self.inner.lock().expect("ring lock");
+
+        match ring.units.front().and_then(|u| u.params.as_ref()) {
+            Some(params) => match writer.write_all(params) {
+                Err(e) => return Err(e),
+                _ => {}
+            },
+            None => {}
+        };
+
+        for unit in &ring.units {
+            match writer.write_all(&unit.data) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        writer.flush()
+    }
+
+    pub fn dump_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let file = match File::create(path) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        self.dump(&mut BufWriter::new(file))
+    }
+}
+
+impl SampleSink for RingSink {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        let au = match sample_buffer.to_access_unit() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let mut ring = self.inner.lock().expect("ring lock");
+
+        match sample_buffer.format_description().and_then(|fd| fd.avc1()) {
+            Some(avc1) => {
+                let mut params: Vec<u8> = Vec::new();
+                h264::write_annexb(&mut params, avc1.sps());
+                h264::write_annexb(&mut params, avc1.pps());
+                ring.params = Some(params);
+            }
+            None => {}
+        };
+
+        // nothing before the first keyframe can be decoded
+        if ring.units.is_empty() && !au.keyframe {
+            return Ok(());
+        }
+
+        let params = match au.keyframe {
+            true => ring.params.clone(),
+            false => None,
+        };
+
+        ring.bytes += au.data.len();
+        ring.units.push_back(RingUnit {
+            data: au.data,
+            pts: au.pts,
+            keyframe: au.keyframe,
+            params,
+        });
+
+        ring.trim();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_VIDEO};
+use crate::h264;
+use crate::sink::SampleSink;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::time::Duration;
+
+// writes annex-b h264 into numbered files `<prefix>-0000.h264`, starting a new
+// one once the duration or size threshold is crossed. rollover waits for the
+// next idr and every file starts with sps, pps and that idr so it decodes on
+// its own, frames before the first idr are dropped
+pub struct SegmentedH264Sink {
+    prefix: String,
+    max_duration: Option<Duration>,
+    max_size: Option<u64>,
+    file: Option<File>,
+    index: u32,
+    segment_start: f64,
+    segment_size: u64,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    params_changed: bool,
+}
+
+impl SegmentedH264Sink {
+    pub fn new(prefix: &str) -> SegmentedH264Sink {
+        SegmentedH264Sink {
+            prefix: String::from(prefix),
+            max_duration: None,
+            max_size: None,
+            file: None,
+            index: 0,
+            segment_start: 0f64,
+            segment_size: 0,
+            sps: None,
+            pps: None,
+            params_changed: false,
+        }
+    }
+
+    pub fn set_max_duration(&mut self, max_duration: Option<Duration>) {
+        self.max_duration = max_duration;
+    }
+
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
+    // number of segments opened so far
+    pub fn segments(&self) -> u32 {
+        self.index
+    }
+
+    fn segment_path(&self) -> String {
+        format!("{}-{:04}.h264", self.prefix, self.index)
+    }
+
+    fn threshold_reached(&self, pts: f64) -> bool {
+        let duration = match self.max_duration {
+            Some(max) => pts - self.segment_start >= max.as_secs_f64(),
+            None => false,
+        };
+
+        let size = match self.max_size {
+            Some(max) => self.segment_size >= max,
+            None => false,
+        };
+
+        duration || size
+    }
+
+    fn roll(&mut self, pts: f64) -> Result<(), Error> {
+        match self.file.take() {
+            Some(mut file) => match file.flush() {
+                Err(e) => return Err(e),
+                _ => {}
+            },
+            None => {}
+        };
+
+        let file = match File::create(self.segment_path()) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        self.file = Some(file);
+        self.index += 1;
+        self.segment_start = pts;
+        self.segment_size = 0;
+        self.params_changed = true;
+
+        Ok(())
+    }
+}
+
+impl SampleSink for SegmentedH264Sink {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        if sample_buffer.media_type() != MEDIA_TYPE_VIDEO {
+            return Ok(());
+        }
+
+        match sample_buffer.format_description().and_then(|fd| fd.avc1()) {
+            Some(avc1) => {
+                let sps = avc1.sps();
+                let pps = avc1.pps();
+
+                if self.sps.as_deref() != Some(sps) || self.pps.as_deref() != Some(pps) {
+                    self.sps = Some(Vec::from(sps));
+                    self.pps = Some(Vec::from(pps));
+                    self.params_changed = true;
+                }
+            }
+            None => {}
+        };
+
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let pts = match sample_buffer.presentation_time_stamp() {
+            Some(e) => e.as_secs_f64(),
+            None => return Err(Error::new(ErrorKind::InvalidData, "sample without pts")),
+        };
+
+        let nalus = match h264::split_nalus(data) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        // only an idr with known parameter sets can open a segment, a file
+        // starting anywhere else shows garbage until the next keyframe
+        let keyframe = sample_buffer.is_sync_sample()
+            && nalus.iter().any(|nalu| h264::is_idr(nalu))
+            && self.sps.is_some()
+            && self.pps.is_some();
+
+        if self.file.is_none() && !keyframe {
+            return Ok(());
+        }
+
+        if self.file.is_none() || (keyframe && self.threshold_reached(pts)) {
+            match self.roll(pts) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+
+        if self.params_changed {
+            for params in [&self.sps, &self.pps].iter().copied().flatten() {
+                h264::write_annexb(&mut buf, params);
+            }
+            self.params_changed = false;
+        }
+
+        for nalu in nalus {
+            h264::write_annexb(&mut buf, nalu);
+        }
+
+        match self.file.as_mut().unwrap().write_all(&buf) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.segment_size += buf.len() as u64;
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        match self.file.take() {
+            Some(mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coremedia::format_desc::FormatDescriptor;
+    use crate::coremedia::time::Time;
+
+    const SPS: [u8; 4] = [0x67, 0x64, 0x00, 0x1F];
+    const PPS: [u8; 2] = [0x68, 0xCE];
+
+    fn sample(frame: u64, fd: Option<&FormatDescriptor>) -> SampleBuffer {
+        let nalu: [u8; 3] = match frame % 30 {
+            0 => [0x65, 0x88, frame as u8],
+            _ => [0x41, 0x9A, frame as u8],
+        };
+        let mut data: Vec<u8> = Vec::from((nalu.len() as u32).to_be_bytes());
+        data.extend_from_slice(&nalu);
+
+        let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        sample.set_sample_data(Some(data));
+        sample.set_output_presentation_time_stamp(Some(Time::new(frame * 20, 600, 1, 0)));
+        sample.set_format_description(fd.cloned());
+        sample
+    }
+
+    #[test]
+    fn every_segment_starts_with_parameter_sets_and_idr() {
+        let dir = std::env::temp_dir().join(format!("qtstream-segment-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("seg");
+
+        let mut avcc: Vec<u8> = vec![1, 100, 0, 31, 0xFF, 0xE1, 0, SPS.len() as u8];
+        avcc.extend_from_slice(&SPS);
+        avcc.extend_from_slice(&[1, 0, PPS.len() as u8]);
+        avcc.extend_from_slice(&PPS);
+        let fd = FormatDescriptor::new_video(1170, 2532, &avcc).unwrap();
+
+        let mut sink = SegmentedH264Sink::new(prefix.to_str().unwrap());
+        sink.set_max_duration(Some(Duration::from_secs(1)));
+
+        // 30fps with an idr every second, the first p frames come before any
+        // idr and are dropped
+        for frame in 25..100 {
+            let fd = match frame {
+                30 => Some(&fd),
+                _ => None,
+            };
+            sink.write(&sample(frame, fd)).unwrap();
+        }
+        sink.finalize().unwrap();
+
+        assert_eq!(sink.segments(), 3);
+
+        for index in 0..sink.segments() {
+            let path = format!("{}-{:04}.h264", prefix.to_str().unwrap(), index);
+            let data = std::fs::read(&path).unwrap();
+            let nalus = h264::split_annexb(&data).unwrap();
+
+            assert_eq!(nalus[0], SPS, "{}", path);
+            assert_eq!(nalus[1], PPS, "{}", path);
+            assert!(h264::is_idr(nalus[2]), "{}", path);
+            assert_eq!(nalus[2][2], 30 * (index as u8 + 1), "{}", path);
+            assert!(nalus[3..].iter().all(|nalu| !h264::is_idr(nalu)));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
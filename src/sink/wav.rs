@@ -0,0 +1,238 @@
+use crate::coremedia::audio_desc::{AudioStreamDescription, AUDIO_FORMAT_ID_LPCM};
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND};
+use crate::sink::SampleSink;
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+
+const WAV_HEADER_LEN: u64 = 44;
+// WAVE_FORMAT_PCM and WAVE_FORMAT_IEEE_FLOAT
+const WAV_FORMAT_PCM: u16 = 1;
+const WAV_FORMAT_FLOAT: u16 = 3;
+
+// EAT audio as a wav file. the header goes out with the first audio sample
+// that has a format, the riff and data sizes are patched in by finalize.
+// wav is little endian lpcm only, anything else is an error. sizes stop at
+// 4GiB like every plain wav writer
+pub struct WavSink<W: Write + Seek> {
+    writer: W,
+    audio_desc: Option<AudioStreamDescription>,
+    bytes_written: u64,
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(writer: W) -> WavSink<W> {
+        WavSink {
+            writer,
+            audio_desc: None,
+            bytes_written: 0,
+        }
+    }
+
+    // format of the file, None before the first audio sample
+    pub fn audio_desc(&self) -> Option<&AudioStreamDescription> {
+        self.audio_desc.as_ref()
+    }
+
+    // sample data bytes, without the header
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self, asbd: &AudioStreamDescription) -> Result<(), Error> {
+        if asbd.format_id() != AUDIO_FORMAT_ID_LPCM || asbd.is_big_endian() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "wav needs little endian lpcm",
+            ));
+        }
+
+        let format = match asbd.is_float() {
+            true => WAV_FORMAT_FLOAT,
+            false => WAV_FORMAT_PCM,
+        };
+        let sample_rate = asbd.sample_rate() as u32;
+        let block_align = asbd.bytes_per_frame();
+
+        let mut header: Vec<u8> = Vec::with_capacity(WAV_HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        // riff size, patched by finalize
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&format.to_le_bytes());
+        header.extend_from_slice(&(asbd.channels_per_frame() as u16).to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align).to_le_bytes());
+        header.extend_from_slice(&(block_align as u16).to_le_bytes());
+        header.extend_from_slice(&(asbd.bits_per_channel() as u16).to_le_bytes());
+        header.extend_from_slice(b"data");
+        // data size, patched by finalize
+        header.extend_from_slice(&0u32.to_le_bytes());
+
+        self.writer.write_all(&header)
+    }
+}
+
+impl<W: Write + Seek> SampleSink for WavSink<W> {
+    fn write(&mut self, sample_buffer: &SampleBuffer) -> Result<(), Error> {
+        if sample_buffer.media_type() != MEDIA_TYPE_SOUND {
+            return Ok(());
+        }
+
+        let asbd = match sample_buffer.format_description() {
+            Some(fd) => *fd.audio_stream_description(),
+            // nothing says how to play it yet
+            None if self.audio_desc.is_none() => return Ok(()),
+            None => self.audio_desc.unwrap(),
+        };
+
+        match self.audio_desc {
+            Some(desc) if desc != asbd => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "audio format changed mid stream",
+                ))
+            }
+            Some(_) => {}
+            None => {
+                match self.write_header(&asbd) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+                self.audio_desc = Some(asbd);
+            }
+        };
+
+        let data = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        match self.writer.write_all(data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.bytes_written += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        if self.audio_desc.is_none() {
+            return self.writer.flush();
+        }
+
+        let data_len = self.bytes_written.min((u32::MAX - 36) as u64) as u32;
+
+        match self.writer.seek(SeekFrom::Start(4)) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_all(&(data_len + 36).to_le_bytes()) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.seek(SeekFrom::Start(WAV_HEADER_LEN - 4)) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_all(&data_len.to_le_bytes()) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self
+            .writer
+            .seek(SeekFrom::Start(WAV_HEADER_LEN + self.bytes_written))
+        {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coremedia::audio_desc::{
+        AUDIO_FORMAT_FLAG_IS_BIG_ENDIAN, AUDIO_FORMAT_FLAG_IS_PACKED,
+        AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER,
+    };
+    use crate::coremedia::format_desc::FormatDescriptor;
+    use std::io::Cursor;
+
+    fn audio_sample(asbd: Option<AudioStreamDescription>, data: &[u8]) -> SampleBuffer {
+        let mut sample = SampleBuffer::new(MEDIA_TYPE_SOUND);
+        sample.set_format_description(asbd.map(FormatDescriptor::new_audio));
+        sample.set_sample_data(Some(Vec::from(data)));
+        sample
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_sizes_are_patched() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new()));
+
+        // before any format, nothing to write it with
+        sink.write(&audio_sample(None, &[9; 4])).unwrap();
+        sink.write(&audio_sample(
+            Some(AudioStreamDescription::default()),
+            &[1; 8],
+        ))
+        .unwrap();
+        sink.write(&audio_sample(None, &[2; 8])).unwrap();
+        sink.finalize().unwrap();
+
+        assert_eq!(sink.bytes_written(), 16);
+
+        let buf = sink.into_inner().into_inner();
+        assert_eq!(buf.len(), 44 + 16);
+        assert_eq!(&buf[..4], b"RIFF");
+        assert_eq!(u32_at(&buf, 4), 36 + 16);
+        assert_eq!(&buf[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&buf, 16), 16);
+        // pcm, 2 channels
+        assert_eq!(&buf[20..24], &[1, 0, 2, 0]);
+        assert_eq!(u32_at(&buf, 24), 48000);
+        assert_eq!(u32_at(&buf, 28), 48000 * 4);
+        // block align 4, 16 bits
+        assert_eq!(&buf[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&buf[36..40], b"data");
+        assert_eq!(u32_at(&buf, 40), 16);
+        assert_eq!(&buf[44..52], &[1; 8]);
+        assert_eq!(&buf[52..], &[2; 8]);
+    }
+
+    #[test]
+    fn big_endian_is_rejected() {
+        let asbd = AudioStreamDescription::new(
+            48000f64,
+            AUDIO_FORMAT_ID_LPCM,
+            AUDIO_FORMAT_FLAG_IS_BIG_ENDIAN
+                | AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER
+                | AUDIO_FORMAT_FLAG_IS_PACKED,
+            4,
+            1,
+            4,
+            2,
+            16,
+        );
+
+        let mut sink = WavSink::new(Cursor::new(Vec::new()));
+        let err = sink.write(&audio_sample(Some(asbd), &[0; 4])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
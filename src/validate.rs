@@ -0,0 +1,227 @@
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use crate::qt;
+use crate::qt_pkt;
+use crate::qt_pkt::QTPacket;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::io::{Error, Read};
+
+// errors beyond this are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+
+// what the parser made of a capture, see validate
+#[derive(Default)]
+pub struct Report {
+    pub bytes: u64,
+    pub packets: u64,
+    pub pings: u64,
+    // counts by sub packet fourcc
+    pub sync: BTreeMap<String, u64>,
+    pub asyn: BTreeMap<String, u64>,
+    pub unknown: u64,
+    pub video_frames: u64,
+    pub audio_samples: u64,
+    pub resyncs: u64,
+    pub error_count: u64,
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.error_count == 0 && self.resyncs == 0
+    }
+
+    fn error(&mut self, msg: String) {
+        self.error_count += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(msg);
+        }
+    }
+}
+
+impl Debug for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "bytes: {}\npackets: {}\npings: {}\nsync: {:?}\nasyn: {:?}\nunknown: {}\nvideo_frames: {}\naudio_samples: {}\nresyncs: {}\nerrors: {}\n",
+            self.bytes,
+            self.packets,
+            self.pings,
+            self.sync,
+            self.asyn,
+            self.unknown,
+            self.video_frames,
+            self.audio_samples,
+            self.resyncs,
+            self.error_count,
+        ))?;
+
+        for e in &self.errors {
+            f.write_fmt(format_args!("  {}\n", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn fourcc(magic: u32) -> String {
+    String::from_utf8_lossy(&magic.to_be_bytes()).into_owned()
+}
+
+// dry run over a capture of the raw bulk in stream (what the device sent,
+// back to back). packets are framed like QuickTime::run does and every
+// payload the loop understands is parsed, nothing is answered or written.
+// only reading the source can fail, parse problems end up in the report
+pub fn validate<R: Read>(mut source: R) -> Result<Report, Error> {
+    let mut data: Vec<u8> = Vec::new();
+
+    match source.read_to_end(&mut data) {
+        Err(e) => return Err(e),
+        _ => {}
+    };
+
+    let mut report = Report {
+        bytes: data.len() as u64,
+        ..Default::default()
+    };
+
+    let mut pos = 0;
+
+    while data.len() - pos >= 8 {
+        if !qt::is_packet_start(&data[pos..]) {
+            match (pos + 1..data.len() - 7).find(|i| qt::is_packet_start(&data[*i..])) {
+                Some(next) => {
+                    report.error(format!(
+                        "offset {}: lost framing, skipped {} bytes",
+                        pos,
+                        next - pos
+                    ));
+                    report.resyncs += 1;
+                    pos = next;
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        let pkt_len =
+            u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+
+        if data.len() - pos < pkt_len {
+            break;
+        }
+
+        let mut pkt = match QTPacket::from_bytes(&data[pos..pos + pkt_len]) {
+            Ok(e) => e,
+            Err(e) => {
+                report.error(format!("offset {}: {}", pos, e));
+                break;
+            }
+        };
+
+        report.packets += 1;
+
+        match parse_packet(&mut pkt, &mut report) {
+            Err(e) => report.error(format!("offset {}: {}", pos, e)),
+            _ => {}
+        };
+
+        pos += pkt_len;
+    }
+
+    if pos < data.len() {
+        report.error(format!(
+            "offset {}: {} trailing bytes",
+            pos,
+            data.len() - pos
+        ));
+    }
+
+    Ok(report)
+}
+
+fn parse_packet(pkt: &mut QTPacket, report: &mut Report) -> Result<(), Error> {
+    let magic = match pkt.read_u32() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    let sync = match magic {
+        qt_pkt::PACKET_MAGIC_PING => {
+            report.pings += 1;
+            return Ok(());
+        }
+        qt_pkt::PACKET_MAGIC_SYNC => true,
+        qt_pkt::PACKET_MAGIC_ASYN => false,
+        _ => {
+            report.unknown += 1;
+            return Ok(());
+        }
+    };
+
+    // clock ref
+    match pkt.read_u64() {
+        Err(e) => return Err(e),
+        _ => {}
+    };
+
+    let sub_magic = match pkt.read_u32() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    match sync {
+        true => {
+            *report.sync.entry(fourcc(sub_magic)).or_insert(0) += 1;
+
+            // correlation id
+            match pkt.read_u64() {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+
+            parse_sync(pkt, sub_magic)
+        }
+        false => {
+            *report.asyn.entry(fourcc(sub_magic)).or_insert(0) += 1;
+            parse_asyn(pkt, sub_magic, report)
+        }
+    }
+}
+
+fn parse_sync(pkt: &mut QTPacket, magic: u32) -> Result<(), Error> {
+    match magic {
+        qt_pkt::SYNC_PACKET_MAGIC_OG => qt_pkt::QTPacketOG::from_packet(pkt).map(|_| ()),
+        qt_pkt::SYNC_PACKET_MAGIC_CWPA => qt_pkt::QTPacketCWPA::from_packet(pkt).map(|_| ()),
+        qt_pkt::SYNC_PACKET_MAGIC_CVRP => qt_pkt::QTPacketCVRP::from_packet(pkt).map(|_| ()),
+        qt_pkt::SYNC_PACKET_MAGIC_AFMT => qt_pkt::QTPacketAFMT::from_packet(pkt).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+fn parse_asyn(pkt: &mut QTPacket, magic: u32, report: &mut Report) -> Result<(), Error> {
+    match magic {
+        qt_pkt::ASYN_PACKET_MAGIC_FEED => {
+            match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_VIDEO) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+            report.video_frames += 1;
+        }
+        qt_pkt::ASYN_PACKET_MAGIC_EAT => {
+            match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_SOUND) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+            report.audio_samples += 1;
+        }
+        qt_pkt::ASYN_PACKET_MAGIC_SPRP => {
+            match qt_pkt::QTPacketSPRP::from_packet(pkt) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+        _ => {}
+    };
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+// a short FEED stream as it comes off the bulk endpoint, run through the same
+// parsers the binary uses and turned into annex-b like `record h264`
+use qtstream::coremedia::sample::{
+    SampleBuffer, CODEC_AVC1, MAGIC_CODEC, MAGIC_EXTENSION, MAGIC_FORMAT_DESCRIPTOR,
+    MAGIC_MEDIA_TYPE, MAGIC_VIDEO_DIMENSION, MEDIA_TYPE_VIDEO,
+};
+use qtstream::coremedia::time::Time;
+use qtstream::h264;
+use qtstream::interop::AccessUnit;
+use qtstream::qt_pkt::{QTPacket, QTPacketASYN, ASYN_PACKET_MAGIC_FEED, PACKET_MAGIC_ASYN};
+use qtstream::qt_value::{QTKeyValuePair, QTValue};
+use std::convert::TryFrom;
+
+const SBUF: u32 = 0x73627566;
+const OPTS: u32 = 0x6F707473;
+const SDAT: u32 = 0x73646174;
+
+const VIDEO_CLOCK_REF: u64 = 0x7FA66CE20CB0;
+
+const SPS: [u8; 9] = [0x67, 0x64, 0x00, 0x1F, 0xAC, 0x2B, 0x40, 0x28, 0x02];
+const PPS: [u8; 4] = [0x68, 0xEE, 0x3C, 0xB0];
+
+fn put_box(parent: &mut QTPacket, magic: u32, payload: &[u8]) {
+    let mut child = QTPacket::new_with_magic(magic);
+    child.write(payload).unwrap();
+    parent.write(child.as_bytes().unwrap()).unwrap();
+}
+
+fn fdsc() -> Vec<u8> {
+    let mut avcc: Vec<u8> = vec![1, SPS[1], SPS[2], SPS[3], 0xFF, 0xE1];
+    avcc.extend_from_slice(&(SPS.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(&SPS);
+    avcc.push(1);
+    avcc.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(&PPS);
+
+    let atoms = QTValue::Object(vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+        QTValue::IdxKey(105),
+        QTValue::Data(avcc),
+    ))]);
+    let extension = QTValue::KeyValuePair(QTKeyValuePair::new(QTValue::IdxKey(49), atoms));
+
+    let mut extn = QTPacket::new_with_magic(MAGIC_EXTENSION);
+    extn.write(extension.as_qt_packet().unwrap().as_bytes().unwrap())
+        .unwrap();
+
+    let mut vdim: Vec<u8> = Vec::new();
+    vdim.extend_from_slice(&1170u32.to_le_bytes());
+    vdim.extend_from_slice(&2532u32.to_le_bytes());
+
+    let mut fdsc = QTPacket::new_with_magic(MAGIC_FORMAT_DESCRIPTOR);
+    put_box(&mut fdsc, MAGIC_MEDIA_TYPE, &MEDIA_TYPE_VIDEO.to_le_bytes());
+    put_box(&mut fdsc, MAGIC_VIDEO_DIMENSION, &vdim);
+    put_box(&mut fdsc, MAGIC_CODEC, &CODEC_AVC1.to_le_bytes());
+    fdsc.write(extn.as_bytes().unwrap()).unwrap();
+    fdsc.as_bytes().unwrap().to_vec()
+}
+
+// one FEED packet, the first of the stream carries the format inline
+fn feed(frame: u64, nalus: &[&[u8]], with_format: bool) -> Vec<u8> {
+    let mut sdat: Vec<u8> = Vec::new();
+    for nalu in nalus {
+        sdat.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+        sdat.extend_from_slice(nalu);
+    }
+
+    let opts = Time::new(frame * 20, 1200, 1, 0);
+
+    let mut sbuf = QTPacket::new_with_magic(SBUF);
+    put_box(&mut sbuf, OPTS, &opts.as_bytes().unwrap());
+    if with_format {
+        sbuf.write(&fdsc()).unwrap();
+    }
+    put_box(&mut sbuf, SDAT, &sdat);
+
+    let mut pkt = QTPacketASYN::new(None, ASYN_PACKET_MAGIC_FEED, VIDEO_CLOCK_REF)
+        .as_qt_packet()
+        .unwrap();
+    pkt.write(sbuf.as_bytes().unwrap()).unwrap();
+    pkt.as_bytes().unwrap().to_vec()
+}
+
+fn capture() -> Vec<u8> {
+    let mut stream: Vec<u8> = Vec::new();
+    // idr in two slices, then single slice p frames
+    stream.extend(feed(
+        0,
+        &[
+            &[0x06, 0x05, 0x01, 0x80],
+            &[0x65, 0x88, 0x84, 0x00],
+            &[0x65, 0x02, 0x92, 0x10],
+        ],
+        true,
+    ));
+    stream.extend(feed(1, &[&[0x41, 0x9A, 0x02, 0x04]], false));
+    stream.extend(feed(2, &[&[0x41, 0x9A, 0x04, 0x08]], false));
+    stream.extend(feed(3, &[&[0x41, 0x9A, 0x06, 0x0C]], false));
+    stream
+}
+
+#[test]
+fn feed_stream_to_annexb() {
+    let stream = capture();
+
+    let mut out: Vec<u8> = Vec::new();
+    let mut pts: Vec<f64> = Vec::new();
+    let mut cur = stream.as_slice();
+
+    while !cur.is_empty() {
+        let mut pkt = QTPacket::from_bytes(cur).unwrap();
+        let len = pkt.len().unwrap() as usize;
+        cur = &cur[len..];
+
+        assert_eq!(pkt.read_u32().unwrap(), PACKET_MAGIC_ASYN);
+        assert_eq!(pkt.read_u64().unwrap(), VIDEO_CLOCK_REF);
+        assert_eq!(pkt.read_u32().unwrap(), ASYN_PACKET_MAGIC_FEED);
+
+        let sample = SampleBuffer::from_qt_packet(&mut pkt, MEDIA_TYPE_VIDEO).unwrap();
+        let au = AccessUnit::try_from(&sample).unwrap();
+
+        assert_eq!(au.keyframe, pts.is_empty());
+        pts.push(au.pts);
+        out.extend_from_slice(&au.data);
+    }
+
+    let summary = h264::verify_annexb(&out).unwrap();
+    assert_eq!(summary.nalus, 8);
+    assert_eq!(summary.frames, 4);
+    assert_eq!(summary.idr_frames, 1);
+
+    let nalus = h264::split_annexb(&out).unwrap();
+    assert_eq!(nalus[0], SPS);
+    assert_eq!(nalus[1], PPS);
+    assert!(h264::is_sei(nalus[2]));
+    assert!(h264::is_idr(nalus[3]));
+    assert!(out.starts_with(&h264::NALU_START_CODE));
+
+    assert_eq!(pts.len(), 4);
+    assert!(pts.windows(2).all(|w| w[0] < w[1]));
+}